@@ -0,0 +1,54 @@
+//! Throughput of the FFmpeg backend
+//!
+//! Decodes `test.flac` end to end, one sample at a time through `FrameSource` as the old
+//! `SharedFFmpegDecoder` handed them out, and in blocks through `read_frames` as the decode
+//! thread reads them. Opening the file is setup, outside the measured time.
+//!
+//! Run with `cargo bench --bench ffmpeg_decode`.
+
+use std::{hint::black_box, path::Path};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rust_music_player::audio::{FFmpegDecoder, FrameReader, FrameSource};
+
+fn ffmpeg_decode(c: &mut Criterion) {
+    let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/resources/test.flac"));
+    let Ok(decoder) = FFmpegDecoder::load(path) else {
+        eprintln!("Skipping ffmpeg_decode: cannot open {}", path.display());
+        return;
+    };
+    let samples = FrameSource::new(decoder).count();
+    let load = || FFmpegDecoder::load(path).unwrap();
+
+    let mut group = c.benchmark_group("ffmpeg_decode");
+    group.throughput(Throughput::Elements(samples as u64));
+    group.sample_size(10);
+    group.bench_function("flac_per_sample", |b| {
+        b.iter_batched(
+            || FrameSource::new(load()),
+            |source| source.map(black_box).count(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("flac_blocks", |b| {
+        let mut block = vec![0.0f32; 4096];
+        b.iter_batched(
+            load,
+            |mut decoder| {
+                let mut frames = 0;
+                loop {
+                    let read = decoder.read_frames(&mut block);
+                    if read == 0 {
+                        break frames;
+                    }
+                    black_box(&block);
+                    frames += read;
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ffmpeg_decode);
+criterion_main!(benches);
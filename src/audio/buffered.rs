@@ -0,0 +1,352 @@
+//! Decoding on a background thread, so a slow read or demux stall does not starve the
+//! audio callback
+
+use std::{
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use anyhow::{anyhow, Result};
+use rodio::Source;
+
+use super::info::DecoderInfo;
+use super::position::FrameCounter;
+use super::registry::{AudioSource, BoxedSource};
+use super::ring::{ring_buffer, BufferStats, Consumer, Producer};
+
+/// How much decoded audio the ring holds
+const BUFFER_DURATION: Duration = Duration::from_millis(500);
+/// Frames decoded per write to the ring
+const CHUNK_FRAMES: usize = 1024;
+/// How long the decode thread waits for room in the ring before checking again
+const FILL_INTERVAL: Duration = Duration::from_millis(5);
+/// Longest wait for the first samples after opening or seeking
+const PREFILL_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Command {
+    /// Seeks the decoder, replying with the ring position where the new samples start
+    Seek(Duration, Sender<Result<usize>>),
+}
+
+/// A source decoded ahead on its own thread into a lock-free ring. The audio thread only
+/// drains the ring; if it ever runs dry, a frame of silence is played instead, which the
+/// frame counter given to `report_underruns` leaves out of the position.
+pub struct BufferedSource {
+    consumer: Consumer,
+    commands: Sender<Command>,
+    channels: u16,
+    sample_rate: u32,
+    total_duration: Option<Duration>,
+    info: DecoderInfo,
+    /// Samples left of a silent frame started on an underrun
+    pending_silence: usize,
+    frame_counter: Option<Arc<FrameCounter>>,
+}
+
+impl BufferedSource {
+    /// Starts decoding `source` on a new thread and waits for the first samples
+    pub fn new(source: BoxedSource) -> Result<Self> {
+        let channels = source.channels().max(1);
+        let sample_rate = source.sample_rate();
+        let total_duration = source.total_duration();
+        let info = source.info();
+
+        // Whole frames only, so an underrun always falls between frames
+        let frames = (sample_rate as u128 * BUFFER_DURATION.as_millis() / 1000) as usize;
+        let capacity = frames.max(2 * CHUNK_FRAMES) * channels as usize;
+        let (producer, consumer) = ring_buffer(capacity);
+        let (commands, receiver) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("decoder".to_string())
+            .spawn(move || decode(source, producer, receiver, channels as usize))?;
+
+        let buffered = Self {
+            consumer,
+            commands,
+            channels,
+            sample_rate,
+            total_duration,
+            info,
+            pending_silence: 0,
+            frame_counter: None,
+        };
+        buffered.wait_for_samples();
+        Ok(buffered)
+    }
+
+    /// Has `counter` skip the silent frames played on underruns
+    pub fn report_underruns(&mut self, counter: Arc<FrameCounter>) {
+        self.frame_counter = Some(counter);
+    }
+
+    /// Fill level and underruns of the ring, readable from any thread
+    pub fn stats(&self) -> BufferStats {
+        self.consumer.stats()
+    }
+
+    /// Asks the decode thread to seek, then drops the samples decoded before it did
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        let (reply, response) = mpsc::channel();
+        self.commands.send(Command::Seek(pos, reply))
+            .map_err(|_| anyhow!("Decode thread has stopped"))?;
+        let flush_point = response.recv()
+            .map_err(|_| anyhow!("Decode thread has stopped"))??;
+
+        self.consumer.skip_to(flush_point);
+        self.pending_silence = 0;
+        self.wait_for_samples();
+        Ok(())
+    }
+
+    /// Blocks the calling (non-audio) thread until the decoder has produced something, so
+    /// playback does not start with an underrun
+    fn wait_for_samples(&self) {
+        let deadline = Instant::now() + PREFILL_TIMEOUT;
+        while self.consumer.available() == 0 && !self.consumer.producer_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// The decode thread: keeps the ring full and serves seeks until the source is dropped
+fn decode(mut source: BoxedSource, mut producer: Producer, commands: Receiver<Command>, channels: usize) {
+    let chunk_len = CHUNK_FRAMES * channels;
+    let mut chunk = vec![0.0; chunk_len];
+
+    loop {
+        let idle = producer.is_finished() || producer.free() < chunk_len;
+        let command = if idle {
+            match commands.recv_timeout(FILL_INTERVAL) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        } else {
+            match commands.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        };
+
+        if let Some(Command::Seek(pos, reply)) = command {
+            let result = source.seek(pos).map(|()| {
+                producer.set_finished(false);
+                producer.written()
+            });
+            let _ = reply.send(result);
+            continue;
+        }
+        if idle {
+            continue;
+        }
+
+        // One call into the decoder per chunk; a short read is not the end, only an empty one
+        let frames = source.read_frames(&mut chunk);
+        producer.push(&chunk[..frames * channels]);
+        if frames == 0 {
+            producer.set_finished(true);
+        }
+    }
+}
+
+impl Iterator for BufferedSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending_silence > 0 {
+            self.pending_silence -= 1;
+            return Some(0.0);
+        }
+
+        // Checked before popping: once finished, every sample written is visible
+        let finished = self.consumer.producer_finished();
+        if let Some(sample) = self.consumer.pop() {
+            return Some(sample);
+        }
+        if finished {
+            return None;
+        }
+
+        self.consumer.record_underrun();
+        if let Some(counter) = &self.frame_counter {
+            counter.skip_silent_frame();
+        }
+        self.pending_silence = self.channels as usize - 1;
+        Some(0.0)
+    }
+}
+
+impl Source for BufferedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+impl AudioSource for BufferedSource {
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        BufferedSource::seek(self, pos)
+    }
+
+    fn info(&self) -> DecoderInfo {
+        self.info.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::position::CountingSource;
+
+    /// Counts up from the seek position, one sample per frame at 1 kHz
+    struct Counter {
+        position: u32,
+        end: u32,
+    }
+
+    impl Iterator for Counter {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            (self.position < self.end).then(|| {
+                self.position += 1;
+                (self.position - 1) as f32
+            })
+        }
+    }
+
+    impl Source for Counter {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            1000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    impl AudioSource for Counter {
+        fn seek(&mut self, pos: Duration) -> Result<()> {
+            self.position = pos.as_millis() as u32;
+            Ok(())
+        }
+    }
+
+    /// Yields 10 frames per read at 1 kHz, the second read only after the ring has run dry
+    struct Stalling {
+        reads: u32,
+    }
+
+    impl Iterator for Stalling {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            unreachable!("read in blocks")
+        }
+    }
+
+    impl Source for Stalling {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            1000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    impl AudioSource for Stalling {
+        fn seek(&mut self, _pos: Duration) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+            self.reads += 1;
+            match self.reads {
+                1 => {}
+                2 => thread::sleep(Duration::from_millis(100)),
+                _ => return 0,
+            }
+            buffer[..10].fill(1.0);
+            10
+        }
+    }
+
+    /// Skips the silence of underruns, which a busy test machine may well cause
+    fn next_sample(source: &mut BufferedSource) -> Option<f32> {
+        source.find(|&sample| sample != 0.0)
+    }
+
+    #[test]
+    fn test_plays_whole_stream_in_order() {
+        let mut source = BufferedSource::new(Box::new(Counter { position: 1, end: 5000 })).unwrap();
+        for expected in 1..5000 {
+            assert_eq!(next_sample(&mut source), Some(expected as f32));
+        }
+        assert_eq!(next_sample(&mut source), None);
+    }
+
+    #[test]
+    fn test_seek_flushes_buffer() {
+        let mut source = BufferedSource::new(Box::new(Counter { position: 1, end: 50_000 })).unwrap();
+        assert_eq!(next_sample(&mut source), Some(1.0));
+
+        source.seek(Duration::from_secs(30)).unwrap();
+        assert_eq!(next_sample(&mut source), Some(30_000.0));
+        assert_eq!(next_sample(&mut source), Some(30_001.0));
+
+        // Seeking back after the end restarts the decode thread's output
+        source.seek(Duration::from_millis(49_999)).unwrap();
+        assert_eq!(next_sample(&mut source), Some(49_999.0));
+        assert_eq!(next_sample(&mut source), None);
+        source.seek(Duration::from_millis(10)).unwrap();
+        assert_eq!(next_sample(&mut source), Some(10.0));
+    }
+
+    #[test]
+    fn test_underruns_are_not_counted() {
+        let mut source = BufferedSource::new(Box::new(Stalling { reads: 0 })).unwrap();
+        let counter = Arc::new(FrameCounter::new());
+        source.report_underruns(Arc::clone(&counter));
+        let stats = source.stats();
+        let mut counting = CountingSource::new(source, Arc::clone(&counter), Duration::ZERO);
+
+        // Both short reads are played, the silence between them is not counted
+        let played = counting.by_ref().filter(|&sample| sample != 0.0).count();
+        assert_eq!(played, 20);
+        assert!(stats.underruns() > 0);
+        assert_eq!(counter.frames(), 20);
+    }
+}
@@ -0,0 +1,264 @@
+//! Chapter marks of audiobooks and other long files, from whichever container stores them
+
+use std::{
+    io::{Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use super::decoders::mp4;
+use super::probe::id3v2_size;
+
+/// A chapter mark
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub start: Duration,
+    pub title: Option<String>,
+}
+
+impl Chapter {
+    /// The title, or "Chapter N" for an untitled chapter at `index` (counting from 0)
+    pub fn label(&self, index: usize) -> String {
+        match &self.title {
+            Some(title) => title.clone(),
+            None => format!("Chapter {}", index + 1),
+        }
+    }
+}
+
+/// Index of the chapter playing at `pos`, None before the first one
+pub fn chapter_at(chapters: &[Chapter], pos: Duration) -> Option<usize> {
+    chapters.iter().rposition(|chapter| chapter.start <= pos)
+}
+
+/// Orders chapters by start, keeping only the first of several at the same position
+pub(crate) fn sorted(mut chapters: Vec<Chapter>) -> Vec<Chapter> {
+    chapters.sort_by_key(|chapter| chapter.start);
+    chapters.dedup_by_key(|chapter| chapter.start);
+    chapters
+}
+
+/// Reads the chapters stored at the container level: ID3v2 `CHAP` frames in front of an
+/// MP3, or the chapters of an MP4 file. Anything else, or a damaged list, gives none.
+pub fn read_chapters<R: Read + Seek>(reader: &mut R) -> Vec<Chapter> {
+    let mut header = [0u8; 10];
+    if reader.seek(SeekFrom::Start(0)).is_err() || reader.read_exact(&mut header).is_err() {
+        return Vec::new();
+    }
+
+    if let Some(size) = id3v2_size(&header) {
+        // The size field is not to be trusted: a tag can't be longer than the file
+        let Ok(len) = reader.seek(SeekFrom::End(0)) else { return Vec::new() };
+        if size > len {
+            return Vec::new();
+        }
+        let mut tag = vec![0u8; size as usize];
+        if reader.seek(SeekFrom::Start(0)).is_err() || reader.read_exact(&mut tag).is_err() {
+            return Vec::new();
+        }
+        sorted(parse_id3_chapters(&tag))
+    } else if &header[4..8] == b"ftyp" {
+        mp4::read_chapters(reader).unwrap_or_default()
+    } else {
+        Vec::new()
+    }
+}
+
+/// The Ogg chapter extension: `CHAPTER001=00:01:02.500` with `CHAPTER001NAME=Title`
+pub fn from_vorbis_comments(comments: &[(String, String)]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    for (key, value) in comments {
+        let Some(number) = key.to_uppercase().strip_prefix("CHAPTER").map(str::to_string) else { continue };
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let Some(start) = parse_clock(value) else { continue };
+        let name_key = format!("CHAPTER{}NAME", number);
+        let title = comments.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(&name_key))
+            .map(|(_, title)| title.trim().to_string())
+            .filter(|title| !title.is_empty());
+        chapters.push(Chapter { start, title });
+    }
+    sorted(chapters)
+}
+
+/// Parses `HH:MM:SS.mmm`; the fraction may have any number of digits
+fn parse_clock(value: &str) -> Option<Duration> {
+    let (clock, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), ""));
+    let parts: Vec<u64> = clock.split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let &[hours, minutes, seconds] = parts.as_slice() else { return None };
+    if minutes >= 60 || seconds >= 60 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", fraction).get(..9)?.parse().ok()?;
+    Some(Duration::new(hours * 3600 + minutes * 60 + seconds, nanos))
+}
+
+/// Collects the `CHAP` frames of an ID3v2.3 or v2.4 tag, titled by their `TIT2` sub-frame
+fn parse_id3_chapters(tag: &[u8]) -> Vec<Chapter> {
+    if tag.len() < 10 {
+        return Vec::new();
+    }
+    let (major, flags) = (tag[3], tag[5]);
+    // Version 2.2 has no chapter frames, and an unsynchronised tag would need decoding first
+    if !(3..=4).contains(&major) || flags & 0x80 != 0 {
+        return Vec::new();
+    }
+
+    let mut frames = tag.get(10..).unwrap_or_default();
+    if flags & 0x40 != 0 {
+        // The extended header's size counts itself in v2.4, but not in v2.3
+        let Some(size) = frames.get(..4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap())) else {
+            return Vec::new();
+        };
+        let size = if major == 4 { syncsafe(size) as usize } else { size as usize + 4 };
+        frames = frames.get(size..).unwrap_or_default();
+    }
+
+    id3_frames(frames, major)
+        .filter(|(id, _)| id == b"CHAP")
+        .filter_map(|(_, body)| {
+            // Element ID, then start and end times in milliseconds and two byte offsets
+            let id_end = body.iter().position(|&b| b == 0)? + 1;
+            let start_ms = u32::from_be_bytes(body.get(id_end..id_end + 4)?.try_into().unwrap());
+            let title = id3_frames(body.get(id_end + 16..)?, major)
+                .find(|(id, _)| id == b"TIT2")
+                .map(|(_, text)| decode_id3_text(text))
+                .filter(|title| !title.is_empty());
+            Some(Chapter { start: Duration::from_millis(start_ms as u64), title })
+        })
+        .collect()
+}
+
+/// The (ID, body) frames of an ID3v2 tag body, up to the padding
+fn id3_frames(mut data: &[u8], major: u8) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let header = data.get(..10)?;
+        if header[0] == 0 {
+            return None;
+        }
+        let id: [u8; 4] = header[..4].try_into().unwrap();
+        let size = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let size = if major == 4 { syncsafe(size) } else { size } as usize;
+        let body = data.get(10..10 + size)?;
+        data = &data[10 + size..];
+        Some((id, body))
+    })
+}
+
+/// A 28-bit integer stored 7 bits per byte
+fn syncsafe(value: u32) -> u32 {
+    (value & 0x7F) | ((value >> 1) & 0x3F80) | ((value >> 2) & 0x1F_C000) | ((value >> 3) & 0xFE0_0000)
+}
+
+/// Decodes a text frame: an encoding byte (Latin-1, UTF-16 with BOM, UTF-16BE or UTF-8),
+/// then the text, possibly null-terminated
+fn decode_id3_text(data: &[u8]) -> String {
+    let Some((&encoding, text)) = data.split_first() else { return String::new() };
+    let decoded = match encoding {
+        0 => text.iter().map(|&byte| byte as char).collect(),
+        1 | 2 => {
+            let big_endian = encoding == 2 || text.starts_with(&[0xFE, 0xFF]);
+            let has_bom = text.starts_with(&[0xFE, 0xFF]) || text.starts_with(&[0xFF, 0xFE]);
+            let text = if has_bom { &text[2..] } else { text };
+            let units: Vec<u16> = text.chunks_exact(2)
+                .map(|pair| if big_endian {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    decoded.trim_end_matches('\0').trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn id3_frame(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn chap_frame(id: &str, start_ms: u32, title: Option<&[u8]>) -> Vec<u8> {
+        let mut body = id.as_bytes().to_vec();
+        body.push(0);
+        body.extend_from_slice(&start_ms.to_be_bytes());
+        body.extend_from_slice(&[0; 4]);
+        body.extend_from_slice(&[0xFF; 8]);
+        if let Some(title) = title {
+            body.extend(id3_frame(b"TIT2", title));
+        }
+        id3_frame(b"CHAP", &body)
+    }
+
+    #[test]
+    fn test_id3_chapters() {
+        // A v2.3 tag (plain frame sizes) with chapters out of order, then padding
+        let mut frames = id3_frame(b"TIT2", b"\x03The Book");
+        frames.extend(chap_frame("ch2", 61_500, Some(b"\x01\xFF\xFET\0w\0o\0")));
+        frames.extend(chap_frame("ch1", 0, Some(b"\x00One\0")));
+        frames.extend(chap_frame("ch3", 120_000, None));
+        frames.extend_from_slice(&[0; 32]);
+
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len() as u32;
+        tag.extend([(size >> 21) as u8 & 0x7F, (size >> 14) as u8 & 0x7F, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]);
+        tag.extend(frames);
+        tag.extend_from_slice(b"\xFF\xFBaudio");
+
+        assert_eq!(read_chapters(&mut Cursor::new(tag)), vec![
+            Chapter { start: Duration::ZERO, title: Some("One".to_string()) },
+            Chapter { start: Duration::from_millis(61_500), title: Some("Two".to_string()) },
+            Chapter { start: Duration::from_secs(120), title: None },
+        ]);
+    }
+
+    #[test]
+    fn test_oversized_id3_tag() {
+        // The largest size the header can claim, on a file of a few bytes
+        let mut tag = b"ID3\x03\x00\x00\x7F\x7F\x7F\x7F".to_vec();
+        tag.extend(chap_frame("ch1", 0, None));
+        assert!(read_chapters(&mut Cursor::new(tag)).is_empty());
+    }
+
+    #[test]
+    fn test_vorbis_comment_chapters() {
+        let comments: Vec<(String, String)> = [
+            ("TITLE", "The Book"),
+            ("CHAPTER002", "00:10:00.25"),
+            ("CHAPTER002NAME", "Second"),
+            ("CHAPTER001", "00:00:00.000"),
+            ("CHAPTER001NAME", "First"),
+            ("CHAPTER003", "01:00:00.000"),
+            ("CHAPTER004", "not a time"),
+        ].iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+
+        assert_eq!(from_vorbis_comments(&comments), vec![
+            Chapter { start: Duration::ZERO, title: Some("First".to_string()) },
+            Chapter { start: Duration::from_millis(600_250), title: Some("Second".to_string()) },
+            Chapter { start: Duration::from_secs(3600), title: None },
+        ]);
+    }
+
+    #[test]
+    fn test_chapter_at() {
+        let chapters: Vec<Chapter> = [5, 60, 120].iter()
+            .map(|&secs| Chapter { start: Duration::from_secs(secs), title: None })
+            .collect();
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(1)), None);
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(60)), Some(1));
+        assert_eq!(chapter_at(&chapters, Duration::from_secs(500)), Some(2));
+        assert_eq!(chapters[2].label(2), "Chapter 3");
+    }
+}
@@ -1,98 +1,15 @@
+//! Opens audio files through the decoder registry
+
 use anyhow::Result;
 use std::{path::Path, time::Duration};
-use crate::audio::ffmpeg::SharedFFmpegDecoder;
-use self::rodio::{Sample, Source};
-use super::decoders::*;
-
-pub enum AudioDecoder {
-    RodioDecoder(RodioDecoder),
-    Opus(DecoderOpus),
-    Vorbis(Box<VorbisDecoder>),
-    Alac(Box<AlacDecoder>),
-    FFmpeg(SharedFFmpegDecoder),
-}
-
-impl Iterator for AudioDecoder {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self {
-            AudioDecoder::RodioDecoder(d) => d.next(),
-            AudioDecoder::Opus(d) => d.next(),
-            AudioDecoder::Vorbis(d) => d.next(),
-            AudioDecoder::Alac(d) => d.next(),
-            AudioDecoder::FFmpeg(d) => d.next(),
-        }
-    }
-}
+use rodio::{Sample, Source};
 
-impl Source for AudioDecoder {
-    fn current_frame_len(&self) -> Option<usize> {
-        match self {
-            AudioDecoder::RodioDecoder(d) => d.current_frame_len(),
-            AudioDecoder::Opus(d) => d.current_frame_len(),
-            AudioDecoder::Vorbis(d) => d.current_frame_len(),
-            AudioDecoder::Alac(d) => d.current_frame_len(),
-            AudioDecoder::FFmpeg(d) => d.current_frame_len(),
-        }
-    }
-
-    fn channels(&self) -> u16 {
-        match self {
-            AudioDecoder::RodioDecoder(d) => d.channels(),
-            AudioDecoder::Opus(d) => d.channels(),
-            AudioDecoder::Vorbis(d) => d.channels(),
-            AudioDecoder::Alac(d) => d.channels(),
-            AudioDecoder::FFmpeg(d) => d.channels(),
-        }
-    }
+use super::registry::{global_registry, BoxedSource};
 
-    fn sample_rate(&self) -> u32 {
-        match self {
-            AudioDecoder::RodioDecoder(d) => d.sample_rate(),
-            AudioDecoder::Opus(d) => d.sample_rate(),
-            AudioDecoder::Vorbis(d) => d.sample_rate(),
-            AudioDecoder::Alac(d) => d.sample_rate(),
-            AudioDecoder::FFmpeg(d) => d.sample_rate(),
-        }
-    }
-
-    fn total_duration(&self) -> Option<Duration> {
-        match self {
-            AudioDecoder::RodioDecoder(d) => d.total_duration(),
-            AudioDecoder::Opus(d) => d.total_duration(),
-            AudioDecoder::Vorbis(d) => d.total_duration(),
-            AudioDecoder::Alac(d) => d.total_duration(),
-            AudioDecoder::FFmpeg(d) => d.total_duration(),
-        }
-    }
-}
-
-pub fn load_audio_file(path: &Path) -> Result<AudioDecoder> {
-    let extension = path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|s| s.to_lowercase());
-
-    match extension.as_deref() {
-        Some("opus") => Ok(AudioDecoder::Opus(DecoderOpus::load(path)?)),
-        Some("ogg") => Ok(AudioDecoder::Vorbis(Box::new(VorbisDecoder::load(path)?))),
-        Some("m4a") => match AlacDecoder::load(path) {
-            Ok(d) => Ok(AudioDecoder::Alac(Box::new(d))),
-            Err(_) => Ok(AudioDecoder::Opus(DecoderOpus::load(path)?)),
-        },
-        _ => match RodioDecoder::load(path) {
-            Ok(d) => Ok(AudioDecoder::RodioDecoder(d)),
-            Err(_) => Ok(AudioDecoder::FFmpeg(
-                FFmpegDecoder::load(path)?.into_shared()
-            )),
-        }
-    }
-}
-
-impl AudioDecoder {
-    pub fn skip_duration(self, duration: Duration) -> SkipDuration<Self> {
-        SkipDuration::new(self, duration)
-    }
+/// Opens `path` with the backend of the global registry that best matches its content,
+/// falling back to the others in order
+pub fn load_audio_file(path: &Path) -> Result<BoxedSource> {
+    global_registry().read().unwrap().open(path)
 }
 
 // SkipDuration implementation remains unchanged from original
@@ -106,7 +23,8 @@ where
     S: Source,
     S::Item: Sample,
 {
-    fn new(source: S, duration: Duration) -> Self {
+    /// Fallback for sources that cannot seek: decodes and discards up to `duration`
+    pub fn new(source: S, duration: Duration) -> Self {
         let samples_to_skip = (duration.as_secs_f32() * source.sample_rate() as f32) as usize
             * source.channels() as usize;
         Self {
@@ -1,117 +1,212 @@
-use std::{collections::VecDeque, fs::File, io::BufReader, path::Path};
-use alac::{Packets, StreamInfo};
-use anyhow::{Result, anyhow};
-use rodio::Source;
+use std::{collections::VecDeque, fs::File, io::BufReader, path::Path, time::Duration};
+use alac::{Decoder, StreamInfo};
+use anyhow::{Result, anyhow, bail};
+
+use crate::audio::info::DecoderInfo;
+use crate::audio::frames::{drain_samples, FrameReader};
+use super::mp4::{self, Mp4Track};
 
 const INITIAL_BUFFER_CAPACITY: usize = 4096;
-const I16_TO_F32_NORM_FACTOR: f32 = 32768.0;
-const I32_TO_F32_NORM_FACTOR: f32 = 2147483648.0;
+
+/// How decoded samples map to f32, fixed for a stream by its bit depth
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Normalization {
+    /// The decoder left-justifies samples in an i32; this brings them back to their own width
+    shift: u32,
+    /// 1 / 2^(bit depth - 1)
+    scale: f32,
+}
+
+impl Normalization {
+    fn for_bit_depth(bit_depth: u8) -> Result<Self> {
+        if !matches!(bit_depth, 16 | 20 | 24 | 32) {
+            bail!("Unsupported ALAC bit depth: {}", bit_depth);
+        }
+        Ok(Self {
+            shift: 32 - bit_depth as u32,
+            scale: 1.0 / (1u64 << (bit_depth - 1)) as f32,
+        })
+    }
+
+    fn apply(self, sample: i32) -> f32 {
+        (sample >> self.shift) as f32 * self.scale
+    }
+}
 
 pub struct AlacDecoder {
-    packets: Packets<BufReader<File>, i32>,
+    reader: BufReader<File>,
+    decoder: Decoder,
+    track: Mp4Track,
+    next_packet: usize,
+    output: Vec<i32>,
     buffer: VecDeque<f32>,
     config: StreamInfo,
-    current_shift: u32,
-    current_norm_factor: f32,
+    normalization: Normalization,
 }
 
 impl AlacDecoder {
     pub fn load(path: &Path) -> Result<Self> {
-        let file = BufReader::new(File::open(path)?);
-        let reader = alac::Reader::new(file)
+        let mut reader = BufReader::new(File::open(path)?);
+        let track = mp4::read_audio_track(&mut reader)?;
+        Self::from_mp4(reader, track)
+    }
+
+    /// Decodes an MP4 audio track that has already been demuxed
+    pub fn from_mp4(reader: BufReader<File>, track: Mp4Track) -> Result<Self> {
+        if &track.codec != b"alac" {
+            bail!("MP4 audio track is not ALAC");
+        }
+
+        // The 'alac' box body is a full box: skip version and flags to reach the ALACSpecificConfig
+        let cookie = track.codec_box(b"alac")
+            .and_then(|body| body.get(4..))
+            .ok_or_else(|| anyhow!("Missing ALAC magic cookie"))?;
+        let config = StreamInfo::from_cookie(cookie)
             .map_err(|e| anyhow!("Failed to create ALAC reader: {:?}", e))?;
+        let normalization = Normalization::for_bit_depth(config.bit_depth())?;
 
-        let stream_info = reader.stream_info().clone();
-        let packets = reader.into_packets();
+        let max_samples = config.max_samples_per_packet() as usize * config.channels() as usize;
 
         Ok(Self {
-            packets,
+            reader,
+            decoder: Decoder::new(config.clone()),
+            track,
+            next_packet: 0,
+            output: vec![0i32; max_samples],
             buffer: VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY),
-            config: stream_info,
-            current_shift: 0,
-            current_norm_factor: I32_TO_F32_NORM_FACTOR,
+            config,
+            normalization,
         })
     }
 
-    fn determine_normalization(&mut self, decoded: &[i32]) {
-        let max_abs = decoded.iter()
-            .map(|&s| s.abs())
-            .max()
-            .unwrap_or(0);
-
-        let (shift, norm_factor) = if max_abs > 0 {
-            let bits_needed = 32 - max_abs.leading_zeros();
+    /// Seeks by jumping straight to the packet that contains `pos`, then discarding
+    /// the leading frames of that packet
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        let target = (pos.as_secs_f64() * self.track.timescale as f64) as u64;
+        let index = self.track.packet_at(target);
+        let packet = self.track.packets.get(index)
+            .ok_or_else(|| anyhow!("Cannot seek beyond end of track"))?;
+        let skip_frames = target.saturating_sub(packet.timestamp) * self.config.sample_rate() as u64
+            / self.track.timescale as u64;
+
+        self.next_packet = index;
+        self.buffer.clear();
+        self.decode_next_packet()?;
+
+        let skip_samples = (skip_frames as usize * self.config.channels() as usize).min(self.buffer.len());
+        self.buffer.drain(..skip_samples);
+        Ok(())
+    }
 
-            match bits_needed {
-                0..=16 => (0, I16_TO_F32_NORM_FACTOR),
-                17..=24 => (8, 8388608.0),
-                _ => (0, I32_TO_F32_NORM_FACTOR)
-            }
-        } else {
-            (0, I32_TO_F32_NORM_FACTOR)
+    fn decode_next_packet(&mut self) -> Result<bool> {
+        let Some(packet) = self.track.read_packet(&mut self.reader, self.next_packet)? else {
+            return Ok(false);
         };
+        self.next_packet += 1;
+
+        let decoded = self.decoder.decode_packet(&packet, &mut self.output)
+            .map_err(|e| anyhow!("ALAC decoding error: {:?}", e))?;
 
-        self.current_shift = shift;
-        self.current_norm_factor = norm_factor;
+        let normalization = self.normalization;
+        self.buffer.extend(decoded.iter().map(|&sample| normalization.apply(sample)));
+        Ok(true)
     }
 }
 
-impl Iterator for AlacDecoder {
-    type Item = f32;
+impl FrameReader for AlacDecoder {
+    fn channels(&self) -> u16 {
+        self.config.channels() as u16
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(sample) = self.buffer.pop_front() {
-            return Some(sample);
-        }
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate()
+    }
 
-        let max_samples = self.config.max_samples_per_packet() as usize
-            * self.config.channels() as usize;
-        let mut output = vec![0i32; max_samples];
+    fn total_duration(&self) -> Option<Duration> {
+        self.track.duration()
+    }
 
-        // Fixed: Properly handle the returned slice
-        match self.packets.next_into(&mut output) {
-            Ok(Some(decoded)) => {
-                if self.buffer.is_empty() {
-                    self.determine_normalization(decoded);
-                }
+    fn bits_per_sample(&self) -> Option<u32> {
+        Some(self.config.bit_depth() as u32)
+    }
 
-                for &sample in decoded {
-                    let shifted = if self.current_shift > 0 {
-                        sample >> self.current_shift
-                    } else {
-                        sample
-                    };
+    fn info(&self) -> DecoderInfo {
+        DecoderInfo { chapters: self.track.chapters.clone(), ..DecoderInfo::from_reader(self) }
+    }
 
-                    let normalized = (shifted as f32) / self.current_norm_factor;
-                    let clamped = normalized.clamp(-1.0, 1.0);
-                    self.buffer.push_back(clamped);
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.config.channels() as usize;
+        let wanted = buffer.len() / channels * channels;
+        let mut written = 0;
+
+        while written < wanted {
+            if self.buffer.is_empty() {
+                match self.decode_next_packet() {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        break;
+                    }
                 }
-
-                self.buffer.pop_front()
-            }
-            Ok(None) => None,
-            Err(e) => {
-                eprintln!("ALAC decoding error: {:?}", e);
-                None
             }
+            written += drain_samples(&mut self.buffer, &mut buffer[written..wanted]);
         }
+
+        written / channels
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        AlacDecoder::seek(self, pos)
     }
 }
 
-impl Source for AlacDecoder {
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes a fixture and checks it sample for sample against its reference PCM: raw
+    /// little-endian integers of the stream's bit depth. The fixtures open with a packet
+    /// far below full scale and end on both extremes.
+    fn assert_matches_reference(name: &str, bit_depth: u32) {
+        let resources = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/resources"));
+        let mut decoder = AlacDecoder::load(&resources.join(format!("{}.m4a", name))).unwrap();
+        let reference = std::fs::read(resources.join(format!("{}.pcm", name))).unwrap();
+
+        let width = bit_depth as usize / 8;
+        let expected: Vec<f32> = reference.chunks_exact(width)
+            .map(|bytes| {
+                let mut le = [0u8; 4];
+                le[4 - width..].copy_from_slice(bytes);
+                (i32::from_le_bytes(le) >> (32 - bit_depth)) as f32 / (1u64 << (bit_depth - 1)) as f32
+            })
+            .collect();
+
+        let mut decoded = vec![0.0; expected.len() + 64];
+        let frames = decoder.read_frames(&mut decoded);
+        assert_eq!(frames * decoder.channels() as usize, expected.len());
+        assert_eq!(&decoded[..expected.len()], &expected[..]);
+        assert_eq!(expected.last(), Some(&-1.0));
     }
 
-    fn channels(&self) -> u16 {
-        self.config.channels() as u16
+    #[test]
+    fn test_normalization_by_bit_depth() {
+        for bit_depth in [16, 20, 24, 32] {
+            let normalization = Normalization::for_bit_depth(bit_depth).unwrap();
+            assert_eq!(normalization.apply(i32::MIN), -1.0);
+            assert_eq!(normalization.apply(1 << 30), 0.5);
+            assert_eq!(normalization.apply(1 << (32 - bit_depth)), 1.0 / (1u64 << (bit_depth - 1)) as f32);
+        }
+        assert!(Normalization::for_bit_depth(8).is_err());
     }
 
-    fn sample_rate(&self) -> u32 {
-        self.config.sample_rate()
+    #[test]
+    fn test_decodes_16_bit_reference() {
+        assert_matches_reference("alac_16bit", 16);
     }
 
-    fn total_duration(&self) -> Option<std::time::Duration> {
-        None
+    #[test]
+    fn test_decodes_24_bit_with_quiet_intro() {
+        assert_matches_reference("alac_24bit", 24);
     }
-}
\ No newline at end of file
+}
@@ -1,34 +1,130 @@
-use std::{
-    path::Path,
-    sync::{Arc, Mutex},
-    collections::VecDeque,
-};
-use std::time::Duration;
-use ffmpeg_next::{format, frame, codec, error, util::log::level};
+use std::{collections::VecDeque, path::Path, time::Duration};
+use ffmpeg_next::{format, frame, codec, error, ffi, software::resampling, util::log::level, ChannelLayout};
 use anyhow::{Result, anyhow};
-use rodio::Source;
+
+use crate::audio::chapters::Chapter;
+use crate::audio::info::DecoderInfo;
+use crate::audio::probe::{AudioFormat, ProbeData};
+use crate::audio::frames::{drain_samples, FrameReader, FrameSource};
+use crate::audio::registry::{BoxedSource, DecoderFactory, SCORE_CERTAIN, SCORE_FALLBACK};
 
 const INITIAL_BUFFER_CAPACITY: usize = 4096;
-const I16_TO_F32_NORM_FACTOR: f32 = 32768.0;
-const I32_TO_F32_NORM_FACTOR: f32 = 2147483648.0;
+/// Every frame is converted to interleaved f32
+const OUTPUT_FORMAT: format::Sample = format::Sample::F32(format::sample::Type::Packed);
+
+/// Converts decoded frames to interleaved f32 at a fixed channel layout and rate
+struct FrameConverter {
+    /// Created from the first decoded frame, and again whenever the input format changes
+    context: Option<resampling::Context>,
+    /// Mono stays mono; everything else is downmixed to stereo
+    layout: ChannelLayout,
+    /// The stream's initial rate, kept if the rate changes mid-stream
+    rate: u32,
+}
+
+impl FrameConverter {
+    fn new(channels: u16, rate: u32) -> Self {
+        let layout = if channels == 1 { ChannelLayout::MONO } else { ChannelLayout::STEREO };
+        Self { context: None, layout, rate }
+    }
+
+    fn channels(&self) -> usize {
+        self.layout.channels() as usize
+    }
+
+    /// Converts a frame into `buffer`. The resampler is rebuilt whenever the sample format,
+    /// channel layout or rate changes.
+    fn convert(&mut self, frame: &mut frame::Audio, buffer: &mut VecDeque<f32>) -> Result<()> {
+        // Frames with an unknown or inconsistent layout get the default one for their channel count
+        let layout = frame.channel_layout();
+        if layout.is_empty() || layout.channels() != frame.channels() as i32 {
+            frame.set_channel_layout(ChannelLayout::default(frame.channels() as i32));
+        }
+
+        let input_changed = self.context.as_ref().is_some_and(|context| {
+            let input = context.input();
+            (input.format, input.channel_layout, input.rate)
+                != (frame.format(), frame.channel_layout(), frame.rate())
+        });
+        if input_changed {
+            self.flush(buffer)?;
+        }
+
+        let context = match &mut self.context {
+            Some(context) => context,
+            slot @ None => slot.insert(
+                resampling::Context::get(
+                    frame.format(),
+                    frame.channel_layout(),
+                    frame.rate(),
+                    OUTPUT_FORMAT,
+                    self.layout,
+                    self.rate,
+                )
+                .map_err(|e| anyhow!("Resampler error: {}", e))?,
+            ),
+        };
+
+        // Room for this frame plus whatever the resampler buffered before
+        let capacity = unsafe { ffi::swr_get_out_samples(context.as_mut_ptr(), frame.samples() as i32) };
+        let mut output = frame::Audio::new(OUTPUT_FORMAT, capacity.max(1) as usize, self.layout);
+        context.run(frame, &mut output)
+            .map_err(|e| anyhow!("Resampling error: {}", e))?;
+        self.push_output(&output, buffer);
+        Ok(())
+    }
+
+    /// Moves the samples the resampler still holds into `buffer` and drops it
+    fn flush(&mut self, buffer: &mut VecDeque<f32>) -> Result<()> {
+        let Some(mut context) = self.context.take() else {
+            return Ok(());
+        };
+        let capacity = unsafe { ffi::swr_get_out_samples(context.as_mut_ptr(), 0) };
+        if capacity <= 0 {
+            return Ok(());
+        }
+        let mut output = frame::Audio::new(OUTPUT_FORMAT, capacity as usize, self.layout);
+        context.flush(&mut output)
+            .map_err(|e| anyhow!("Resampling error: {}", e))?;
+        self.push_output(&output, buffer);
+        Ok(())
+    }
 
-#[derive(Clone)]
-pub struct SharedFFmpegDecoder(Arc<Mutex<FFmpegDecoder>>);
+    /// Drops the resampler along with the samples it still holds
+    fn reset(&mut self) {
+        self.context = None;
+    }
 
-impl SharedFFmpegDecoder {
-    pub fn new(decoder: FFmpegDecoder) -> Self {
-        Self(Arc::new(Mutex::new(decoder)))
+    fn push_output(&self, output: &frame::Audio, buffer: &mut VecDeque<f32>) {
+        let samples = output.samples() * self.channels();
+        buffer.extend(
+            output.data(0)
+                .chunks_exact(4)
+                .take(samples)
+                .map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        );
     }
 }
+
+/// Decodes anything FFmpeg can demux. The decoder owns all of its FFmpeg state and is driven
+/// from a single thread through `&mut self`, so no locking is needed.
 pub struct FFmpegDecoder {
-    decoder: Mutex<codec::decoder::Audio>,
-    context: Arc<Mutex<format::context::Input>>,
-    frame: Mutex<frame::Audio>,
-    sample_buffer: Mutex<VecDeque<f32>>,
+    input: format::context::Input,
+    decoder: codec::decoder::Audio,
+    frame: frame::Audio,
+    converter: FrameConverter,
+    sample_buffer: VecDeque<f32>,
+    stream_index: usize,
+    time_base: f64,
+    duration: Option<Duration>,
+    chapters: Vec<Chapter>,
+    seek_target: Option<f64>,
 }
 
+// SAFETY: the FFmpeg contexts are not tied to the thread that created them, only to not being
+// used from two threads at once. They are owned exclusively by this decoder and only reached
+// through `&mut self`, so moving the decoder to the audio thread is sound. It is not `Sync`.
 unsafe impl Send for FFmpegDecoder {}
-unsafe impl Sync for FFmpegDecoder {}
 
 impl FFmpegDecoder {
     pub fn load(path: &Path) -> Result<Self> {
@@ -41,6 +137,27 @@ impl FFmpegDecoder {
         let stream = input.streams()
             .best(ffmpeg_next::media::Type::Audio)
             .ok_or_else(|| anyhow!("No audio stream found"))?;
+        let stream_index = stream.index();
+        let time_base = f64::from(stream.time_base());
+        // Not every container stores a stream duration; the format duration is in AV_TIME_BASE units
+        let seconds = if stream.duration() > 0 {
+            stream.duration() as f64 * time_base
+        } else {
+            input.duration() as f64 / f64::from(ffi::AV_TIME_BASE)
+        };
+        let duration = (seconds > 0.0).then(|| Duration::from_secs_f64(seconds));
+
+        // The chapters of Matroska and the other containers FFmpeg demuxes
+        let chapters = input.chapters()
+            .map(|chapter| {
+                let metadata = chapter.metadata();
+                let start = chapter.start() as f64 * f64::from(chapter.time_base());
+                Chapter {
+                    start: Duration::from_secs_f64(start.max(0.0)),
+                    title: metadata.get("title").map(str::to_string).filter(|title| !title.is_empty()),
+                }
+            })
+            .collect();
 
         let mut decoder = codec::Context::from_parameters(stream.parameters())
             .map_err(|e| anyhow!("Codec context error: {}", e))?
@@ -51,107 +168,78 @@ impl FFmpegDecoder {
         decoder.set_parameters(stream.parameters())
             .map_err(|e| anyhow!("Parameter error: {}", e))?;
 
+        let converter = FrameConverter::new(decoder.channels(), decoder.rate());
+
         Ok(Self {
-            decoder: Mutex::new(decoder),
-            context: Arc::new(Mutex::new(input)),
-            frame: Mutex::new(frame::Audio::empty()),
-            sample_buffer: Mutex::new(VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY)),
+            input,
+            decoder,
+            frame: frame::Audio::empty(),
+            converter,
+            sample_buffer: VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY),
+            stream_index,
+            time_base,
+            duration,
+            chapters,
+            seek_target: None,
         })
     }
 
-    pub fn into_shared(self) -> SharedFFmpegDecoder {
-        SharedFFmpegDecoder::new(self)
+    /// Seeks the demuxer to the keyframe before `pos`; frames decoded before `pos`
+    /// are dropped once their timestamps are known
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        let timestamp = pos.as_micros() as i64;
+        self.input.seek(timestamp, ..timestamp)
+            .map_err(|e| anyhow!("FFmpeg seek error: {}", e))?;
+
+        self.decoder.flush();
+        // Samples still delayed in the resampler belong before the seek
+        self.converter.reset();
+        self.sample_buffer.clear();
+        self.seek_target = Some(pos.as_secs_f64());
+        Ok(())
     }
 
-    fn decode_frame(&self) -> Result<()> {
-        let mut decoder = self.decoder.lock().unwrap();
-        let mut frame = self.frame.lock().unwrap();
-        let mut buffer = self.sample_buffer.lock().unwrap();
-
+    /// Decodes the next frame into the sample buffer. Returns false at the end of the stream.
+    fn decode_frame(&mut self) -> Result<bool> {
         loop {
-            match decoder.receive_frame(&mut frame) {
+            match self.decoder.receive_frame(&mut self.frame) {
                 Ok(_) => {
-                    let samples = frame.samples();
-                    let channels = frame.channels() as usize;
-
-                    match frame.format() {
-                        format::Sample::F32(layout) => self.process_f32_frame(&frame, samples, channels, layout, &mut buffer),
-                        format::Sample::I16(layout) => self.process_i16_frame(&frame, samples, channels, layout, &mut buffer),
-                        format::Sample::I32(layout) => self.process_i32_frame(&frame, samples, channels, layout, &mut buffer),
-                        other => return Err(anyhow!("Unsupported sample format: {:?}", other)),
+                    self.converter.convert(&mut self.frame, &mut self.sample_buffer)?;
+                    if let Some(target) = self.seek_target.take() {
+                        self.discard_before(target);
                     }
-                    break Ok(());
+                    return Ok(true);
                 }
                 Err(error::Error::Other { errno: error::EAGAIN }) => {
-                    self.feed_packets()?;
+                    self.feed_packet()?;
                 }
-                Err(e) => return Err(anyhow!("Frame error: {}", e)),
-            }
-        }
-    }
-
-    fn process_f32_frame(&self, frame: &frame::Audio, samples: usize, channels: usize, layout: format::sample::Type, buffer: &mut VecDeque<f32>) {
-        if layout == format::sample::Type::Planar {
-            for i in 0..samples {
-                for c in 0..channels {
-                    buffer.push_back(frame.plane::<f32>(c)[i]);
-                }
-            }
-        } else {
-            buffer.extend(frame.plane::<f32>(0).iter().take(samples * channels).copied());
-        }
-    }
-
-    fn process_i16_frame(&self, frame: &frame::Audio, samples: usize, channels: usize, layout: format::sample::Type, buffer: &mut VecDeque<f32>) {
-        if layout == format::sample::Type::Planar {
-            for i in 0..samples {
-                for c in 0..channels {
-                    buffer.push_back(frame.plane::<i16>(c)[i] as f32 / I16_TO_F32_NORM_FACTOR);
+                Err(error::Error::Eof) => {
+                    // Drain what the resampler still holds
+                    self.converter.flush(&mut self.sample_buffer)?;
+                    return Ok(!self.sample_buffer.is_empty());
                 }
+                Err(e) => return Err(anyhow!("Frame error: {}", e)),
             }
-        } else {
-            buffer.extend(
-                frame.plane::<i16>(0)
-                    .iter()
-                    .take(samples * channels)
-                    .map(|&s| s as f32 / I16_TO_F32_NORM_FACTOR)
-            );
         }
     }
 
-    fn process_i32_frame(&self, frame: &frame::Audio, samples: usize, channels: usize, layout: format::sample::Type, buffer: &mut VecDeque<f32>) {
-        if layout == format::sample::Type::Planar {
-            for i in 0..samples {
-                for c in 0..channels {
-                    buffer.push_back(frame.plane::<i32>(c)[i] as f32 / I32_TO_F32_NORM_FACTOR);
-                }
-            }
-        } else {
-            buffer.extend(
-                frame.plane::<i32>(0)
-                    .iter()
-                    .take(samples * channels)
-                    .map(|&s| s as f32 / I32_TO_F32_NORM_FACTOR)
-            );
-        }
+    /// Drops the samples of a freshly decoded frame that precede the seek target
+    fn discard_before(&mut self, target: f64) {
+        let Some(pts) = self.frame.pts() else { return };
+        let frame_start = pts as f64 * self.time_base;
+        let skip_frames = ((target - frame_start) * self.converter.rate as f64).max(0.0) as usize;
+        let skip_samples = (skip_frames * self.converter.channels()).min(self.sample_buffer.len());
+        self.sample_buffer.drain(..skip_samples);
     }
 
-    fn feed_packets(&self) -> Result<()> {
-        let mut context = self.context.lock().unwrap();
-        let mut decoder = self.decoder.lock().unwrap();
-
-        let stream_index = context.streams()
-            .best(ffmpeg_next::media::Type::Audio)
-            .map(|s| s.index())
-            .unwrap_or(0);
-
-        if let Some((stream, packet)) = context.packets().next() {
-            if stream.index() == stream_index {
-                decoder.send_packet(&packet)
+    fn feed_packet(&mut self) -> Result<()> {
+        if let Some((stream, packet)) = self.input.packets().next() {
+            if stream.index() == self.stream_index {
+                self.decoder.send_packet(&packet)
                     .map_err(|e| anyhow!("Packet error: {}", e))?;
             }
         } else {
-            decoder.send_eof()
+            self.decoder.send_eof()
                 .map_err(|e| anyhow!("EOF error: {}", e))?;
         }
 
@@ -159,63 +247,75 @@ impl FFmpegDecoder {
     }
 }
 
-impl Iterator for FFmpegDecoder {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut buffer = self.sample_buffer.lock().unwrap();
-
-        if buffer.is_empty() {
-            if let Err(e) = self.decode_frame() {
-                eprintln!("Decoding error: {}", e);
-                return None;
-            }
-        }
+impl FrameReader for FFmpegDecoder {
+    fn channels(&self) -> u16 {
+        self.converter.channels() as u16
+    }
 
-        buffer.pop_front()
+    fn sample_rate(&self) -> u32 {
+        self.converter.rate
     }
-}
 
-impl Source for FFmpegDecoder {
-    fn current_frame_len(&self) -> Option<usize> {
-        None // Variable frame size
+    fn total_duration(&self) -> Option<Duration> {
+        self.duration
     }
 
-    fn channels(&self) -> u16 {
-        self.decoder.lock().unwrap().channels()
+    fn info(&self) -> DecoderInfo {
+        DecoderInfo { chapters: self.chapters.clone(), ..DecoderInfo::from_reader(self) }
     }
 
-    fn sample_rate(&self) -> u32 {
-        self.decoder.lock().unwrap().rate()
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.converter.channels();
+        let wanted = buffer.len() / channels * channels;
+        let mut written = 0;
+
+        while written < wanted {
+            if self.sample_buffer.is_empty() {
+                match self.decode_frame() {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => {
+                        eprintln!("Decoding error: {}", e);
+                        break;
+                    }
+                }
+            }
+            written += drain_samples(&mut self.sample_buffer, &mut buffer[written..wanted]);
+        }
+
+        written / channels
     }
 
-    fn total_duration(&self) -> Option<Duration> {
-        None // Would require container duration calculation
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        FFmpegDecoder::seek(self, pos)
     }
 }
 
-impl Iterator for SharedFFmpegDecoder {
-    type Item = f32;
+/// The last resort for any file, and the only backend for WMA and Matroska
+pub struct FFmpegFactory;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.lock().unwrap().next()
+impl DecoderFactory for FFmpegFactory {
+    fn name(&self) -> &str {
+        "ffmpeg"
     }
-}
 
-impl Source for SharedFFmpegDecoder {
-    fn current_frame_len(&self) -> Option<usize> {
-        self.0.lock().unwrap().current_frame_len()
+    fn extensions(&self) -> &[&str] {
+        // Not `mkv`: a directory scan would pick up films
+        &["wma", "mka", "webm"]
     }
 
-    fn channels(&self) -> u16 {
-        self.0.lock().unwrap().channels()
+    fn mime_types(&self) -> &[&str] {
+        &["audio/x-ms-wma", "audio/x-matroska", "audio/webm"]
     }
 
-    fn sample_rate(&self) -> u32 {
-        self.0.lock().unwrap().sample_rate()
+    fn probe(&self, probe: &ProbeData) -> u8 {
+        match probe.format {
+            Some(AudioFormat::Asf | AudioFormat::Matroska) => SCORE_CERTAIN,
+            _ => SCORE_FALLBACK,
+        }
     }
 
-    fn total_duration(&self) -> Option<Duration> {
-        self.0.lock().unwrap().total_duration()
+    fn open(&self, path: &Path) -> Result<BoxedSource> {
+        Ok(Box::new(FrameSource::new(FFmpegDecoder::load(path)?)))
     }
-}
\ No newline at end of file
+}
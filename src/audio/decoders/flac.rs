@@ -0,0 +1,577 @@
+//! Native FLAC decoding: claxon decodes the frames and checks their CRCs, while the
+//! metadata blocks and seeking are handled here
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    mem,
+    path::Path,
+    time::Duration,
+};
+use anyhow::{anyhow, bail, Result};
+use claxon::{frame::FrameReader as ClaxonFrames, input::BufferedReader};
+
+use crate::audio::frames::{drain_samples, FrameReader, FrameSource};
+use crate::audio::info::{parse_vorbis_comments, DecoderInfo, Picture};
+use crate::audio::probe::{id3v2_size, AudioFormat, ProbeData};
+use crate::audio::registry::{BoxedSource, DecoderFactory, SCORE_CERTAIN};
+
+const INITIAL_BUFFER_CAPACITY: usize = 4096;
+/// Once a binary search has narrowed the start of the target frame down to this many
+/// bytes, the rest is decoded
+const SEEK_WINDOW: u64 = 16 * 1024;
+/// Bytes scanned for a frame header at each step of the binary search
+const SCAN_SIZE: usize = 32 * 1024;
+/// Longest possible frame header: sync, 7-byte sample number, block size, rate and CRC
+const MAX_HEADER_SIZE: usize = 16;
+/// Consecutive frames that may fail to decode before the stream is given up on
+const MAX_DECODE_RETRIES: usize = 3;
+
+const BLOCK_STREAMINFO: u8 = 0;
+const BLOCK_SEEKTABLE: u8 = 3;
+const BLOCK_VORBIS_COMMENT: u8 = 4;
+const BLOCK_PICTURE: u8 = 6;
+
+/// The STREAMINFO block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_sample: u32,
+    /// Samples per channel, if the encoder knew it
+    pub total_samples: Option<u64>,
+}
+
+impl StreamInfo {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let data = data.get(..18).ok_or_else(|| anyhow!("Truncated FLAC STREAMINFO"))?;
+        // 20 bits of sample rate, 3 of channels, 5 of bits per sample and 36 of length
+        let packed = u64::from_be_bytes(data[10..18].try_into().unwrap());
+        let info = Self {
+            min_block_size: u16::from_be_bytes([data[0], data[1]]),
+            max_block_size: u16::from_be_bytes([data[2], data[3]]),
+            sample_rate: (packed >> 44) as u32,
+            channels: ((packed >> 41) & 0x07) as u32 + 1,
+            bits_per_sample: ((packed >> 36) & 0x1F) as u32 + 1,
+            total_samples: Some(packed & 0xF_FFFF_FFFF).filter(|&samples| samples > 0),
+        };
+        if info.sample_rate == 0 || info.bits_per_sample < 4 {
+            bail!("Invalid FLAC STREAMINFO");
+        }
+        Ok(info)
+    }
+}
+
+/// A SEEKTABLE entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeekPoint {
+    /// First sample of the target frame
+    pub sample: u64,
+    /// Offset of the target frame from the first frame
+    pub offset: u64,
+}
+
+/// Everything before the first frame
+struct Metadata {
+    stream: StreamInfo,
+    seek_points: Vec<SeekPoint>,
+    comments: Vec<(String, String)>,
+    pictures: Vec<Picture>,
+    /// File offset of the first frame
+    audio_start: u64,
+}
+
+/// Reads the `fLaC` marker and the metadata blocks, skipping a leading ID3v2 tag
+fn read_metadata<R: Read>(reader: &mut R) -> Result<Metadata> {
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header[..4])?;
+    let mut position = 4;
+    if header.starts_with(b"ID3") {
+        reader.read_exact(&mut header[4..])?;
+        let tag_size = id3v2_size(&header).ok_or_else(|| anyhow!("Invalid ID3v2 tag"))?;
+        std::io::copy(&mut reader.take(tag_size - 10), &mut std::io::sink())?;
+        reader.read_exact(&mut header[..4])?;
+        position = tag_size + 4;
+    }
+    if &header[..4] != b"fLaC" {
+        bail!("Not a FLAC stream");
+    }
+
+    let mut stream = None;
+    let mut seek_points = Vec::new();
+    let mut comments = Vec::new();
+    let mut pictures = Vec::new();
+    loop {
+        let mut block_header = [0u8; 4];
+        reader.read_exact(&mut block_header)?;
+        let is_last = block_header[0] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, block_header[1], block_header[2], block_header[3]]);
+        let mut block = Vec::new();
+        reader.take(length as u64).read_to_end(&mut block)?;
+        if block.len() != length as usize {
+            bail!("Truncated FLAC metadata block");
+        }
+        position += 4 + length as u64;
+
+        match block_header[0] & 0x7F {
+            BLOCK_STREAMINFO => stream = Some(StreamInfo::parse(&block)?),
+            BLOCK_SEEKTABLE => {
+                // Placeholder points have a sample number of all ones
+                seek_points = block.chunks_exact(18)
+                    .map(|point| SeekPoint {
+                        sample: u64::from_be_bytes(point[..8].try_into().unwrap()),
+                        offset: u64::from_be_bytes(point[8..16].try_into().unwrap()),
+                    })
+                    .filter(|point| point.sample != u64::MAX)
+                    .collect();
+            }
+            BLOCK_VORBIS_COMMENT => comments = parse_vorbis_comments(&block)?,
+            BLOCK_PICTURE => pictures.extend(Picture::from_flac_block(&block)),
+            _ => {}
+        }
+        if is_last {
+            break;
+        }
+    }
+
+    Ok(Metadata {
+        stream: stream.ok_or_else(|| anyhow!("Missing FLAC STREAMINFO"))?,
+        seek_points,
+        comments,
+        pictures,
+        audio_start: position,
+    })
+}
+
+/// CRC-8 of frame headers (polynomial x^8 + x^2 + x + 1)
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+/// Reads the UTF-8-like coded frame or sample number at `at`, returning it and the
+/// offset after it
+fn read_coded_number(data: &[u8], at: usize) -> Option<(u64, usize)> {
+    let first = *data.get(at)?;
+    let len = first.leading_ones() as usize;
+    match len {
+        0 => return Some((first as u64, at + 1)),
+        1 | 8 => return None,
+        _ => {}
+    }
+
+    let continuation = data.get(at + 1..at + len)?;
+    let value = continuation.iter().try_fold((first & (0x7F >> len)) as u64, |value, &byte| {
+        (byte & 0xC0 == 0x80).then_some((value << 6) | (byte & 0x3F) as u64)
+    })?;
+    Some((value, at + len))
+}
+
+/// Parses the frame header at the start of `data`, returning the number of the frame's
+/// first sample if the header is valid for `stream`
+fn frame_start_sample(data: &[u8], stream: &StreamInfo) -> Option<u64> {
+    let header = data.get(..4)?;
+    if header[0] != 0xFF || header[1] & 0xFE != 0xF8 {
+        return None;
+    }
+    let variable_block_size = header[1] & 0x01 != 0;
+    let block_size_code = header[2] >> 4;
+    let rate_code = header[2] & 0x0F;
+    let channel_code = header[3] >> 4;
+    let size_code = (header[3] >> 1) & 0x07;
+    if block_size_code == 0 || rate_code == 15 || channel_code > 10 || size_code == 3 || header[3] & 0x01 != 0 {
+        return None;
+    }
+    let channels = if channel_code < 8 { channel_code as u32 + 1 } else { 2 };
+    if channels != stream.channels {
+        return None;
+    }
+
+    let (number, mut end) = read_coded_number(data, 4)?;
+    end += match block_size_code {
+        6 => 1,
+        7 => 2,
+        _ => 0,
+    };
+    end += match rate_code {
+        12 => 1,
+        13 | 14 => 2,
+        _ => 0,
+    };
+    if crc8(data.get(..end)?) != *data.get(end)? {
+        return None;
+    }
+
+    // Fixed block size streams number their frames rather than their samples
+    Some(if variable_block_size { number } else { number * stream.max_block_size as u64 })
+}
+
+pub struct FlacDecoder {
+    /// Shares its position with the frame reader's file; used to find frames when seeking
+    file: File,
+    frames: ClaxonFrames<BufferedReader<File>>,
+    stream: StreamInfo,
+    seek_points: Vec<SeekPoint>,
+    comments: Vec<(String, String)>,
+    pictures: Vec<Picture>,
+    audio_start: u64,
+    file_len: u64,
+    /// Decoded block, handed back to claxon for the next frame
+    block: Vec<i32>,
+    buffer: VecDeque<f32>,
+    /// First sample of the frame to decode next
+    next_sample: u64,
+    /// 1 / 2^(bits per sample - 1)
+    scale: f32,
+}
+
+impl FlacDecoder {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let metadata = read_metadata(&mut BufReader::new(&mut file))?;
+        let stream = metadata.stream;
+        let file_len = file.metadata()?.len();
+
+        file.seek(SeekFrom::Start(metadata.audio_start))?;
+        let frames = ClaxonFrames::new(BufferedReader::new(file.try_clone()?));
+
+        Ok(Self {
+            file,
+            frames,
+            stream,
+            seek_points: metadata.seek_points,
+            comments: metadata.comments,
+            pictures: metadata.pictures,
+            audio_start: metadata.audio_start,
+            file_len,
+            block: Vec::with_capacity(stream.max_block_size as usize * stream.channels as usize),
+            buffer: VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY),
+            next_sample: 0,
+            scale: 1.0 / (1u64 << (stream.bits_per_sample - 1)) as f32,
+        })
+    }
+
+    pub fn stream_info(&self) -> &StreamInfo {
+        &self.stream
+    }
+
+    /// Starts decoding at the frame found from the seek table, or by a binary search over
+    /// frame headers, then discards the samples of that frame before `pos`
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        let target = (pos.as_secs_f64() * self.stream.sample_rate as f64) as u64;
+        if self.stream.total_samples.is_some_and(|total| target >= total) {
+            bail!("Cannot seek beyond end of track");
+        }
+
+        let (offset, first_sample) = match self.seek_point_before(target)? {
+            Some(start) => start,
+            None => self.search_frame_before(target)?,
+        };
+        self.decode_from(offset, first_sample)?;
+
+        // Decode whole frames up to the one that holds the target
+        let mut position = first_sample;
+        loop {
+            let frames = self.decode_next_frame()? as u64;
+            if frames == 0 || position + frames > target {
+                break;
+            }
+            position += frames;
+            self.buffer.clear();
+        }
+
+        let skip = ((target.saturating_sub(position)) * self.stream.channels as u64) as usize;
+        self.buffer.drain(..skip.min(self.buffer.len()));
+        Ok(())
+    }
+
+    /// Stream format plus the Vorbis comments and pictures
+    pub fn info(&self) -> DecoderInfo {
+        let mut info = DecoderInfo::from_reader(self).with_comments(self.comments.clone());
+        info.pictures.extend(self.pictures.iter().cloned());
+        info
+    }
+
+    /// The last usable seek point at or before `target`, as (file offset, first sample)
+    fn seek_point_before(&mut self, target: u64) -> Result<Option<(u64, u64)>> {
+        let Some(point) = self.seek_points.iter().rev().find(|point| point.sample <= target).copied() else {
+            return Ok(None);
+        };
+
+        // A stale seek table (the file was edited after encoding) must not send us
+        // into the middle of a frame
+        let offset = self.audio_start + point.offset;
+        let valid = self.frame_at(offset)?.is_some_and(|sample| sample == point.sample);
+        Ok(valid.then_some((offset, point.sample)))
+    }
+
+    /// Binary search for a frame starting at or before `target`, as (file offset, first
+    /// sample). It ends within `SEEK_WINDOW` bytes of the target frame.
+    fn search_frame_before(&mut self, target: u64) -> Result<(u64, u64)> {
+        let (mut low, mut low_sample) = (self.audio_start, 0);
+        let mut high = self.file_len;
+
+        while high - low > SEEK_WINDOW {
+            let middle = low + (high - low) / 2;
+            match self.next_frame_from(middle)? {
+                Some((offset, sample)) if offset < high && sample <= target => {
+                    low = offset;
+                    low_sample = sample;
+                }
+                _ => high = middle,
+            }
+        }
+        Ok((low, low_sample))
+    }
+
+    /// The first sample of the frame starting at `offset`, if one does
+    fn frame_at(&mut self, offset: u64) -> Result<Option<u64>> {
+        let mut header = Vec::with_capacity(MAX_HEADER_SIZE);
+        self.file.seek(SeekFrom::Start(offset))?;
+        (&mut self.file).take(MAX_HEADER_SIZE as u64).read_to_end(&mut header)?;
+        Ok(frame_start_sample(&header, &self.stream))
+    }
+
+    /// Scans forward from `from` for the next frame header, as (file offset, first sample)
+    fn next_frame_from(&mut self, from: u64) -> Result<Option<(u64, u64)>> {
+        let mut data = Vec::with_capacity(SCAN_SIZE + MAX_HEADER_SIZE);
+        self.file.seek(SeekFrom::Start(from))?;
+        (&mut self.file).take((SCAN_SIZE + MAX_HEADER_SIZE) as u64).read_to_end(&mut data)?;
+
+        let found = (0..data.len().min(SCAN_SIZE))
+            .find_map(|at| frame_start_sample(&data[at..], &self.stream).map(|sample| (from + at as u64, sample)));
+        Ok(found)
+    }
+
+    /// Restarts the frame reader at the frame at `offset`, which begins with `sample`
+    fn decode_from(&mut self, offset: u64, sample: u64) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.frames = ClaxonFrames::new(BufferedReader::new(self.file.try_clone()?));
+        self.buffer.clear();
+        self.next_sample = sample;
+        Ok(())
+    }
+
+    /// Moves past a frame that failed to decode to the first frame after it. False when
+    /// there is none left.
+    fn resync(&mut self) -> Result<bool> {
+        let damaged = self.next_sample;
+        let (mut from, _) = self.search_frame_before(damaged)?;
+        while from < self.file_len {
+            match self.next_frame_from(from + 1)? {
+                Some((offset, sample)) if sample > damaged => {
+                    self.decode_from(offset, sample)?;
+                    return Ok(true);
+                }
+                Some((offset, _)) => from = offset,
+                None => from += SCAN_SIZE as u64,
+            }
+        }
+        Ok(false)
+    }
+
+    /// Decodes one frame into the buffer, returning its length in samples per channel
+    fn decode_next_frame(&mut self) -> Result<usize> {
+        let block = self.frames.read_next_or_eof(mem::take(&mut self.block))
+            .map_err(|e| anyhow!("FLAC decoding error: {}", e))?;
+        let Some(block) = block else {
+            return Ok(0);
+        };
+
+        let frames = block.duration() as usize;
+        self.next_sample = block.time() + frames as u64;
+        for i in 0..block.duration() {
+            for channel in 0..block.channels() {
+                self.buffer.push_back(block.sample(channel, i) as f32 * self.scale);
+            }
+        }
+        self.block = block.into_buffer();
+        Ok(frames)
+    }
+}
+
+impl FrameReader for FlacDecoder {
+    fn channels(&self) -> u16 {
+        self.stream.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.stream.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.stream.total_samples
+            .map(|samples| Duration::from_secs_f64(samples as f64 / self.stream.sample_rate as f64))
+    }
+
+    fn bits_per_sample(&self) -> Option<u32> {
+        Some(self.stream.bits_per_sample)
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.stream.channels as usize;
+        let wanted = buffer.len() / channels * channels;
+        let mut written = 0;
+        let mut errors = 0;
+
+        while written < wanted {
+            if self.buffer.is_empty() {
+                match self.decode_next_frame() {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        errors = 0;
+                        continue;
+                    }
+                    // A damaged frame is skipped, but not a run of them
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        errors += 1;
+                        if errors > MAX_DECODE_RETRIES || !self.resync().unwrap_or(false) {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+            }
+            written += drain_samples(&mut self.buffer, &mut buffer[written..wanted]);
+        }
+
+        written / channels
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        FlacDecoder::seek(self, pos)
+    }
+
+    fn info(&self) -> DecoderInfo {
+        FlacDecoder::info(self)
+    }
+}
+
+/// Native FLAC files
+pub struct FlacFactory;
+
+impl DecoderFactory for FlacFactory {
+    fn name(&self) -> &str {
+        "flac"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["flac"]
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/flac", "audio/x-flac"]
+    }
+
+    fn probe(&self, probe: &ProbeData) -> u8 {
+        probe.score(&[AudioFormat::Flac], self.extensions(), SCORE_CERTAIN)
+    }
+
+    fn open(&self, path: &Path) -> Result<BoxedSource> {
+        Ok(Box::new(FrameSource::new(FlacDecoder::load(path)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::test_utils::{read_all, resource, wav_integers};
+
+    /// The samples of `test.wav`, which `test.flac` was encoded from
+    fn reference_samples() -> Vec<f32> {
+        wav_integers("test.wav", 2).iter().map(|&sample| sample as f32 / 32768.0).collect()
+    }
+
+    #[test]
+    fn test_coded_numbers() {
+        assert_eq!(read_coded_number(&[0x00, 0x7F], 1), Some((0x7F, 2)));
+        assert_eq!(read_coded_number(&[0xC2, 0xA9], 0), Some((0xA9, 2)));
+        assert_eq!(read_coded_number(&[0xE2, 0x82, 0xAC], 0), Some((0x20AC, 3)));
+        assert_eq!(read_coded_number(&[0xFE, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF], 0), Some((0xF_FFFF_FFFF, 7)));
+        assert_eq!(read_coded_number(&[0x80], 0), None);
+        assert_eq!(read_coded_number(&[0xC2, 0x29], 0), None);
+    }
+
+    #[test]
+    fn test_frame_header() {
+        let stream = StreamInfo {
+            min_block_size: 4608,
+            max_block_size: 4608,
+            sample_rate: 44100,
+            channels: 2,
+            bits_per_sample: 16,
+            total_samples: None,
+        };
+        // Frame 3 of a fixed block size stream: 4608 samples, 44.1 kHz, mid/side, 16 bits
+        let mut header = vec![0xFF, 0xF8, 0x59, 0xA8, 0x03];
+        header.push(crc8(&header));
+        assert_eq!(frame_start_sample(&header, &stream), Some(3 * 4608));
+
+        let mut corrupt = header.clone();
+        corrupt[4] = 0x04;
+        assert_eq!(frame_start_sample(&corrupt, &stream), None);
+        let mono = StreamInfo { channels: 1, ..stream };
+        assert_eq!(frame_start_sample(&header, &mono), None);
+    }
+
+    #[test]
+    fn test_decodes_to_reference() {
+        let mut decoder = FlacDecoder::load(&resource("test.flac")).unwrap();
+        assert_eq!(decoder.stream_info().bits_per_sample, 16);
+        assert_eq!(decoder.total_duration(), Some(Duration::from_secs(5)));
+        assert!(read_all(&mut decoder) == reference_samples());
+    }
+
+    #[test]
+    fn test_seeks_exactly() {
+        let reference = reference_samples();
+        let mut decoder = FlacDecoder::load(&resource("test.flac")).unwrap();
+        assert!(decoder.seek_points.is_empty());
+
+        // Binary search over the frame headers
+        for millis in [0, 1, 2500, 4990, 1000] {
+            decoder.seek(Duration::from_millis(millis)).unwrap();
+            let start = millis as usize * 441 / 10 * 2;
+            assert!(read_all(&mut decoder) == reference[start..], "seek to {} ms", millis);
+        }
+        assert!(decoder.seek(Duration::from_secs(5)).is_err());
+
+        // A seek table pointing at the frame found for 3 s
+        let (offset, sample) = decoder.search_frame_before(3 * 44100).unwrap();
+        decoder.seek_points = vec![SeekPoint { sample, offset: offset - decoder.audio_start }];
+        decoder.seek(Duration::from_millis(3200)).unwrap();
+        assert!(read_all(&mut decoder) == reference[3200 * 441 / 10 * 2..]);
+
+        // A stale one is ignored
+        decoder.seek_points[0].offset += 1;
+        decoder.seek(Duration::from_millis(3200)).unwrap();
+        assert!(read_all(&mut decoder) == reference[3200 * 441 / 10 * 2..]);
+    }
+
+    #[test]
+    fn test_skips_damaged_frame() {
+        let reference = reference_samples();
+        let mut data = std::fs::read(resource("test.flac")).unwrap();
+        // A byte in the middle of a frame: its CRC no longer matches
+        let middle = data.len() / 2;
+        data[middle] ^= 0xFF;
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &data).unwrap();
+
+        let mut decoder = FlacDecoder::load(file.path()).unwrap();
+        let block = decoder.stream_info().max_block_size as usize * 2;
+        let samples = read_all(&mut decoder);
+
+        // Only the damaged frame is missing: playback carries on to the end
+        let missing = reference.len() - samples.len();
+        assert!(missing > 0 && missing <= block, "{} samples missing", missing);
+        let gap = samples.iter().zip(&reference).position(|(a, b)| a != b).unwrap();
+        assert!(samples[..gap] == reference[..gap]);
+        assert!(samples[gap..] == reference[gap + missing..]);
+    }
+}
@@ -2,11 +2,16 @@ pub mod opus;
 pub mod vorbis;
 pub mod alac;
 pub mod ffmpeg;
-pub mod rodio;
+pub mod symphonia;
+pub mod mp4;
+pub mod flac;
+mod ogg_pages;
 
-pub use opus::DecoderOpus;
-pub use vorbis::VorbisDecoder;
+pub use opus::{DecoderOpus, OpusFactory};
+pub use vorbis::{VorbisDecoder, VorbisFactory};
 pub use alac::AlacDecoder;
-pub use ffmpeg::FFmpegDecoder;
-pub use rodio::RodioDecoder;
+pub use ffmpeg::{FFmpegDecoder, FFmpegFactory};
+pub use symphonia::{SymphoniaDecoder, SymphoniaFactory};
+pub use mp4::Mp4Factory;
+pub use flac::{FlacDecoder, FlacFactory};
 
@@ -0,0 +1,604 @@
+//! Minimal MP4/M4A demuxer: locates the first audio track and builds its packet index
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::audio::chapters::{self, Chapter};
+use crate::audio::frames::FrameSource;
+use crate::audio::probe::{AudioFormat, ProbeData};
+use crate::audio::registry::{BoxedSource, DecoderFactory, SCORE_CERTAIN};
+use super::{AlacDecoder, DecoderOpus};
+
+/// Location and timing of a single packet (MP4 "sample") in the file
+#[derive(Debug, Clone, Copy)]
+pub struct Mp4Packet {
+    pub offset: u64,
+    pub size: u32,
+    /// Start timestamp in track timescale units
+    pub timestamp: u64,
+    pub duration: u32,
+}
+
+/// Audio track description taken from the `moov` box
+#[derive(Debug, Clone)]
+pub struct Mp4Track {
+    /// Sample entry type, e.g. `alac`, `Opus` or `mp4a`
+    pub codec: [u8; 4],
+    pub timescale: u32,
+    pub duration: u64,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Child boxes of the sample entry (`alac`, `dOps`, `esds`, ...) as (type, body)
+    pub codec_boxes: Vec<([u8; 4], Vec<u8>)>,
+    pub packets: Vec<Mp4Packet>,
+    /// Chapters of the file, which M4B audiobooks keep outside the audio track
+    pub chapters: Vec<Chapter>,
+}
+
+impl Mp4Track {
+    /// Returns the body of the sample entry child box with the given type
+    pub fn codec_box(&self, kind: &[u8; 4]) -> Option<&[u8]> {
+        self.codec_boxes.iter()
+            .find(|(k, _)| k == kind)
+            .map(|(_, body)| body.as_slice())
+    }
+
+    /// Returns the index of the packet containing `timestamp` (in timescale units)
+    pub fn packet_at(&self, timestamp: u64) -> usize {
+        match self.packets.binary_search_by(|p| p.timestamp.cmp(&timestamp)) {
+            Ok(index) => index,
+            Err(index) => index.saturating_sub(1),
+        }
+    }
+
+    /// Length of the track according to the sample table, which unlike the `mdhd`
+    /// duration is exact
+    pub fn duration(&self) -> Option<Duration> {
+        let end = self.packets.last().map(|p| p.timestamp + p.duration as u64)?;
+        (self.timescale > 0).then(|| Duration::from_secs_f64(end as f64 / self.timescale as f64))
+    }
+
+    /// Reads the raw bytes of a packet
+    pub fn read_packet<R: Read + Seek>(&self, reader: &mut R, index: usize) -> Result<Option<Vec<u8>>> {
+        let Some(packet) = self.packets.get(index) else {
+            return Ok(None);
+        };
+
+        reader.seek(SeekFrom::Start(packet.offset))?;
+        let mut data = vec![0u8; packet.size as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+struct BoxHeader {
+    kind: [u8; 4],
+    /// Size of the box body (without the header)
+    body_size: u64,
+}
+
+fn read_box_header<R: Read>(reader: &mut R) -> Result<Option<BoxHeader>> {
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let kind = [header[4], header[5], header[6], header[7]];
+
+    let body_size = match size {
+        0 => u64::MAX, // Box extends to the end of the file
+        1 => {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            u64::from_be_bytes(large)
+                .checked_sub(16)
+                .ok_or_else(|| anyhow!("Invalid MP4 box size"))?
+        }
+        _ => size.checked_sub(8).ok_or_else(|| anyhow!("Invalid MP4 box size"))?,
+    };
+
+    Ok(Some(BoxHeader { kind, body_size }))
+}
+
+/// Iterates over the boxes contained in `data`, returning (type, body) pairs
+fn child_boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let kind = [data[4], data[5], data[6], data[7]];
+        let (header_len, size) = match size {
+            0 => (8, data.len()),
+            1 if data.len() >= 16 => {
+                let large = u64::from_be_bytes(data[8..16].try_into().unwrap());
+                (16, large.min(data.len() as u64) as usize)
+            }
+            _ => (8, size),
+        };
+        if size < header_len || size > data.len() {
+            break;
+        }
+        boxes.push((kind, &data[header_len..size]));
+        data = &data[size..];
+    }
+    boxes
+}
+
+fn find_child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    child_boxes(data).into_iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, body)| body)
+}
+
+fn be_u16(data: &[u8], at: usize) -> Result<u16> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("Truncated MP4 box"))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Result<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow!("Truncated MP4 box"))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Result<u64> {
+    data.get(at..at + 8)
+        .map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("Truncated MP4 box"))
+}
+
+/// Reads the `moov` box body from the file, wherever it is located
+pub fn read_moov<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut first = true;
+    while let Some(header) = read_box_header(reader)? {
+        if first && &header.kind != b"ftyp" {
+            bail!("Not an MP4 file");
+        }
+        first = false;
+
+        if &header.kind == b"moov" {
+            let mut body = Vec::new();
+            reader.take(header.body_size).read_to_end(&mut body)?;
+            return Ok(body);
+        }
+        if header.body_size == u64::MAX {
+            break;
+        }
+        reader.seek(SeekFrom::Current(header.body_size as i64))?;
+    }
+
+    bail!("MP4 file has no moov box")
+}
+
+/// Finds the first audio track in the file and builds its packet index
+pub fn read_audio_track<R: Read + Seek>(reader: &mut R) -> Result<Mp4Track> {
+    let moov = read_moov(reader)?;
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut track = audio_track(&moov, file_len)?;
+    // A damaged chapter list does not keep the audio from playing
+    track.chapters = moov_chapters(reader, &moov).unwrap_or_default();
+    Ok(track)
+}
+
+/// Finds the first audio track in a `moov` box body; `file_len` bounds its sample count
+pub fn audio_track(moov: &[u8], file_len: u64) -> Result<Mp4Track> {
+    for (kind, trak) in child_boxes(moov) {
+        if &kind != b"trak" {
+            continue;
+        }
+        let Some(mdia) = find_child(trak, b"mdia") else { continue };
+        let is_audio = find_child(mdia, b"hdlr")
+            .and_then(|hdlr| hdlr.get(8..12))
+            .is_some_and(|handler| handler == b"soun");
+        if is_audio {
+            return parse_audio_track(mdia, file_len);
+        }
+    }
+
+    bail!("MP4 file has no audio track")
+}
+
+/// Reads an iTunes freeform (`----`) tag such as `iTunSMPB` from `moov/udta/meta/ilst`
+pub fn read_freeform_tag(moov: &[u8], name: &str) -> Option<String> {
+    let meta = find_child(moov, b"udta").and_then(|udta| find_child(udta, b"meta"))?;
+    // meta is a full box: skip version and flags
+    let ilst = find_child(meta.get(4..)?, b"ilst")?;
+
+    child_boxes(ilst).into_iter()
+        .filter(|(kind, _)| kind == b"----")
+        .find_map(|(_, item)| {
+            let item_name = find_child(item, b"name")?.get(4..)?;
+            if item_name != name.as_bytes() {
+                return None;
+            }
+            // data box: 4 bytes of type indicator and 4 bytes of locale precede the value
+            let data = find_child(item, b"data")?.get(8..)?;
+            Some(String::from_utf8_lossy(data).into_owned())
+        })
+}
+
+/// Reads the chapters of an MP4 file: the QuickTime chapter track that audiobooks use, or
+/// else the Nero `chpl` list
+pub fn read_chapters<R: Read + Seek>(reader: &mut R) -> Result<Vec<Chapter>> {
+    let moov = read_moov(reader)?;
+    moov_chapters(reader, &moov)
+}
+
+fn moov_chapters<R: Read + Seek>(reader: &mut R, moov: &[u8]) -> Result<Vec<Chapter>> {
+    let mut chapters = read_chapter_track(reader, moov)?;
+    if chapters.is_empty() {
+        chapters = find_child(moov, b"udta")
+            .and_then(|udta| find_child(udta, b"chpl"))
+            .map(parse_chpl)
+            .unwrap_or_default();
+    }
+    Ok(chapters::sorted(chapters))
+}
+
+/// Reads the text track named by a `tref/chap` box, whose samples are the chapter titles
+fn read_chapter_track<R: Read + Seek>(reader: &mut R, moov: &[u8]) -> Result<Vec<Chapter>> {
+    let traks: Vec<&[u8]> = child_boxes(moov).into_iter()
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, trak)| trak)
+        .collect();
+    let chapter_ids: Vec<u32> = traks.iter()
+        .filter_map(|trak| find_child(trak, b"tref").and_then(|tref| find_child(tref, b"chap")))
+        .flat_map(|chap| chap.chunks_exact(4).map(|id| u32::from_be_bytes(id.try_into().unwrap())))
+        .collect();
+    let Some(mdia) = traks.iter()
+        .find(|trak| track_id(trak).is_some_and(|id| chapter_ids.contains(&id)))
+        .and_then(|trak| find_child(trak, b"mdia"))
+    else {
+        return Ok(Vec::new());
+    };
+
+    let (timescale, _) = media_header(mdia)?;
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let packets = build_packet_index(sample_table(mdia)?, file_len)?;
+    let mut chapters = Vec::with_capacity(packets.len());
+    for packet in packets {
+        reader.seek(SeekFrom::Start(packet.offset))?;
+        // Whatever the sample size claims, its 16-bit length limits the title
+        let mut sample = Vec::new();
+        reader.by_ref().take((packet.size as u64).min(2 + u16::MAX as u64)).read_to_end(&mut sample)?;
+        chapters.push(Chapter {
+            start: Duration::from_secs_f64(packet.timestamp as f64 / timescale.max(1) as f64),
+            title: decode_text_sample(&sample).filter(|title| !title.is_empty()),
+        });
+    }
+    Ok(chapters)
+}
+
+fn track_id(trak: &[u8]) -> Option<u32> {
+    let tkhd = find_child(trak, b"tkhd")?;
+    let at = if tkhd.first() == Some(&1) { 20 } else { 12 };
+    be_u32(tkhd, at).ok()
+}
+
+/// A QuickTime text sample: a 16-bit length, then UTF-8 text, or UTF-16 after a BOM
+fn decode_text_sample(sample: &[u8]) -> Option<String> {
+    let len = be_u16(sample, 0).ok()? as usize;
+    let text = sample.get(2..2 + len)?;
+    let text = match text.strip_prefix(&[0xFE, 0xFF]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        None => String::from_utf8_lossy(text).into_owned(),
+    };
+    Some(text.trim().to_string())
+}
+
+/// Nero chapters: version and flags, a count, then per chapter its start in 100 ns units
+/// and a length-prefixed title
+fn parse_chpl(chpl: &[u8]) -> Vec<Chapter> {
+    // Version 1 has four more bytes before the count
+    let count_at = if chpl.first() == Some(&1) { 8 } else { 4 };
+    let Some(&count) = chpl.get(count_at) else { return Vec::new() };
+
+    let mut rest = &chpl[count_at + 1..];
+    let mut chapters = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let Ok(start) = be_u64(rest, 0) else { break };
+        let Some(&len) = rest.get(8) else { break };
+        let Some(title) = rest.get(9..9 + len as usize) else { break };
+        let title = String::from_utf8_lossy(title).trim().to_string();
+        chapters.push(Chapter {
+            start: Duration::from_nanos(start.saturating_mul(100)),
+            title: Some(title).filter(|title| !title.is_empty()),
+        });
+        rest = &rest[9 + len as usize..];
+    }
+    chapters
+}
+
+/// Timescale and duration from a media's `mdhd` box
+fn media_header(mdia: &[u8]) -> Result<(u32, u64)> {
+    let mdhd = find_child(mdia, b"mdhd").ok_or_else(|| anyhow!("Missing mdhd box"))?;
+    if mdhd.first() == Some(&1) {
+        Ok((be_u32(mdhd, 20)?, be_u64(mdhd, 24)?))
+    } else {
+        Ok((be_u32(mdhd, 12)?, be_u32(mdhd, 16)? as u64))
+    }
+}
+
+fn sample_table(mdia: &[u8]) -> Result<&[u8]> {
+    find_child(mdia, b"minf")
+        .and_then(|minf| find_child(minf, b"stbl"))
+        .ok_or_else(|| anyhow!("Missing stbl box"))
+}
+
+fn parse_audio_track(mdia: &[u8], file_len: u64) -> Result<Mp4Track> {
+    let (timescale, duration) = media_header(mdia)?;
+    let stbl = sample_table(mdia)?;
+
+    let stsd = find_child(stbl, b"stsd").ok_or_else(|| anyhow!("Missing stsd box"))?;
+    let (codec, entry) = child_boxes(stsd.get(8..).unwrap_or_default())
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Empty stsd box"))?;
+
+    // Audio sample entry: 8 bytes of SampleEntry, then the (QuickTime versioned) sound description
+    let version = be_u16(entry, 8)?;
+    let channels = be_u16(entry, 16)?;
+    let sample_rate = be_u32(entry, 24)? >> 16;
+    let children_start = match version {
+        1 => 28 + 16,
+        2 => 28 + 36,
+        _ => 28,
+    };
+    let codec_boxes = child_boxes(entry.get(children_start..).unwrap_or_default())
+        .into_iter()
+        .map(|(kind, body)| (kind, body.to_vec()))
+        .collect();
+
+    let packets = build_packet_index(stbl, file_len)?;
+
+    Ok(Mp4Track {
+        codec,
+        timescale,
+        duration,
+        channels,
+        sample_rate,
+        codec_boxes,
+        packets,
+        chapters: Vec::new(),
+    })
+}
+
+/// The entry count of a full-box table, checked against the entries the box really holds
+fn table_count(table: &[u8], entry_size: usize) -> Result<usize> {
+    let count = be_u32(table, 4)? as usize;
+    if count > table.len().saturating_sub(8) / entry_size {
+        bail!("MP4 table claims more entries than its box holds");
+    }
+    Ok(count)
+}
+
+/// An empty vector for `capacity` entries, or an error when that much memory is not available
+fn try_vec<T>(capacity: usize) -> Result<Vec<T>> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(capacity)
+        .map_err(|_| anyhow!("MP4 sample table too large ({} samples)", capacity))?;
+    Ok(vec)
+}
+
+/// Builds the packet index from a sample table. Every count in the tables is bounded, by
+/// its box or by `file_len`, so a corrupt file cannot make us allocate without limit.
+fn build_packet_index(stbl: &[u8], file_len: u64) -> Result<Vec<Mp4Packet>> {
+    // Sample sizes: one fixed size, or a table after the count
+    let stsz = find_child(stbl, b"stsz").ok_or_else(|| anyhow!("Missing stsz box"))?;
+    let fixed_size = be_u32(stsz, 4)?;
+    let sample_count = if fixed_size == 0 {
+        // The size table starts 4 bytes later than the entries `table_count` expects
+        table_count(stsz.get(4..).unwrap_or_default(), 4)?
+    } else {
+        // Fixed-size samples all have to fit in the file
+        (be_u32(stsz, 8)? as u64).min(file_len / fixed_size as u64) as usize
+    };
+    let size_at = |index: usize| match fixed_size {
+        0 => be_u32(stsz, 12 + index * 4).ok(),
+        size => (index < sample_count).then_some(size),
+    };
+
+    // Chunk offsets
+    let chunk_offsets = if let Some(stco) = find_child(stbl, b"stco") {
+        let count = table_count(stco, 4)?;
+        (0..count).map(|i| be_u32(stco, 8 + i * 4).map(u64::from)).collect::<Result<Vec<u64>>>()?
+    } else if let Some(co64) = find_child(stbl, b"co64") {
+        let count = table_count(co64, 8)?;
+        (0..count).map(|i| be_u64(co64, 8 + i * 8)).collect::<Result<Vec<u64>>>()?
+    } else {
+        bail!("Missing stco box");
+    };
+
+    // Sample-to-chunk runs: (first_chunk, samples_per_chunk)
+    let stsc = find_child(stbl, b"stsc").ok_or_else(|| anyhow!("Missing stsc box"))?;
+    let run_count = table_count(stsc, 12)?;
+    let runs = (0..run_count)
+        .map(|i| Ok((be_u32(stsc, 8 + i * 12)? as usize, be_u32(stsc, 12 + i * 12)? as usize)))
+        .collect::<Result<Vec<(usize, usize)>>>()?;
+
+    // Time-to-sample runs: (count, delta), expanded no further than the samples there are
+    let stts = find_child(stbl, b"stts").ok_or_else(|| anyhow!("Missing stts box"))?;
+    let entry_count = table_count(stts, 8)?;
+    let mut durations = try_vec(sample_count)?;
+    for i in 0..entry_count {
+        let count = be_u32(stts, 8 + i * 8)? as usize;
+        let delta = be_u32(stts, 12 + i * 8)?;
+        let count = count.min(sample_count - durations.len());
+        durations.extend(std::iter::repeat(delta).take(count));
+    }
+
+    let mut packets = try_vec(sample_count)?;
+    let mut timestamp = 0u64;
+    for (run_index, &(first_chunk, samples_per_chunk)) in runs.iter().enumerate() {
+        let last_chunk = runs.get(run_index + 1)
+            .map(|&(next_first, _)| next_first)
+            .unwrap_or(chunk_offsets.len() + 1);
+
+        for chunk in first_chunk..last_chunk {
+            let Some(&chunk_offset) = chunk_offsets.get(chunk.wrapping_sub(1)) else { break };
+            let mut offset = chunk_offset;
+            for _ in 0..samples_per_chunk {
+                let index = packets.len();
+                let Some(size) = size_at(index) else { break };
+                let duration = durations.get(index).copied().unwrap_or(0);
+                packets.push(Mp4Packet { offset, size, timestamp, duration });
+                offset += size as u64;
+                timestamp += duration as u64;
+            }
+        }
+    }
+
+    Ok(packets)
+}
+
+/// MP4/M4A files: demuxes far enough to see the codec of the audio track and opens the
+/// native ALAC and Opus decoders. Other codecs (AAC, FLAC) are left to the general backends.
+pub struct Mp4Factory;
+
+impl DecoderFactory for Mp4Factory {
+    fn name(&self) -> &str {
+        "mp4"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["m4a", "m4b", "mp4"]
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/mp4", "audio/x-m4a", "audio/x-m4b"]
+    }
+
+    fn probe(&self, probe: &ProbeData) -> u8 {
+        probe.score(&[AudioFormat::Mp4], self.extensions(), SCORE_CERTAIN)
+    }
+
+    fn open(&self, path: &Path) -> Result<BoxedSource> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let track = read_audio_track(&mut reader)
+            .with_context(|| format!("Failed to read MP4 file: {}", path.display()))?;
+
+        match &track.codec {
+            b"alac" => Ok(Box::new(FrameSource::new(AlacDecoder::from_mp4(reader, track)?))),
+            b"Opus" => Ok(Box::new(FrameSource::new(DecoderOpus::from_mp4(reader, track)?))),
+            codec => bail!("Unsupported codec '{}' in MP4 file", String::from_utf8_lossy(codec).trim_end()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_duration() {
+        let packet = |timestamp, duration| Mp4Packet { offset: 0, size: 0, timestamp, duration };
+        let mut track = Mp4Track {
+            codec: *b"alac",
+            timescale: 44100,
+            // mdhd durations are often rounded; the sample table wins
+            duration: 88200,
+            channels: 2,
+            sample_rate: 44100,
+            codec_boxes: Vec::new(),
+            chapters: Vec::new(),
+            packets: vec![packet(0, 4096), packet(4096, 4096), packet(8192, 1234)],
+        };
+        assert_eq!(track.duration(), Some(Duration::from_secs_f64(9426.0 / 44100.0)));
+
+        track.timescale = 0;
+        assert_eq!(track.duration(), None);
+        track.packets.clear();
+        assert_eq!(track.duration(), None);
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn test_oversized_sample_counts() {
+        let full_box = |kind: &[u8; 4], fields: &[u32]| {
+            let body: Vec<u8> = [0u32].iter().chain(fields).flat_map(|field| field.to_be_bytes()).collect();
+            mp4_box(kind, &body)
+        };
+        let stco = full_box(b"stco", &[1, 0]);
+        let stsc = full_box(b"stsc", &[1, 1, u32::MAX, 1]);
+        let stts = full_box(b"stts", &[1, u32::MAX, 1024]);
+
+        // A fixed sample size: only as many samples as fit in the file
+        let stbl = [full_box(b"stsz", &[1000, u32::MAX]), stco.clone(), stsc.clone(), stts.clone()].concat();
+        let packets = build_packet_index(&stbl, 10_000).unwrap();
+        assert_eq!(packets.len(), 10);
+        assert_eq!(packets[9].timestamp, 9 * 1024);
+
+        // A size table shorter than its count
+        let stbl = [full_box(b"stsz", &[0, u32::MAX, 100, 100]), stco, stsc.clone(), stts.clone()].concat();
+        assert!(build_packet_index(&stbl, 10_000).is_err());
+
+        // A chunk offset table shorter than its count
+        let stbl = [full_box(b"stsz", &[1000, 10]), full_box(b"stco", &[u32::MAX, 0]), stsc, stts].concat();
+        assert!(build_packet_index(&stbl, 10_000).is_err());
+    }
+
+    #[test]
+    fn test_nero_chapters() {
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 3];
+        chpl.extend(0u64.to_be_bytes());
+        chpl.extend(b"\x05Intro");
+        chpl.extend(90_000_000_000u64.to_be_bytes());
+        chpl.push(0);
+        chpl.extend(u64::MAX.to_be_bytes());
+        chpl.push(0);
+        assert_eq!(parse_chpl(&chpl), vec![
+            Chapter { start: Duration::ZERO, title: Some("Intro".to_string()) },
+            Chapter { start: Duration::from_secs(9000), title: None },
+            // A start too large for nanoseconds saturates instead of overflowing
+            Chapter { start: Duration::from_nanos(u64::MAX), title: None },
+        ]);
+    }
+
+    #[test]
+    fn test_chapter_track() {
+        // Two text samples, 5 s apart, in a track the audio track points to with tref/chap;
+        // the second claims a 4 GiB size, which must not be allocated
+        let samples = [&b"\x00\x05Start"[..], &b"\x00\x08\xFE\xFF\x00E\x00n\x00d"[..]];
+        let ftyp = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        let mdat = mp4_box(b"mdat", &samples.concat());
+        let sample_offset = (ftyp.len() + 8) as u32;
+
+        let tkhd = |id: u32| mp4_box(b"tkhd", &[&[0; 12][..], &id.to_be_bytes(), &[0; 68]].concat());
+        let audio = mp4_box(b"trak", &[tkhd(1), mp4_box(b"tref", &mp4_box(b"chap", &2u32.to_be_bytes()))].concat());
+        let stbl = [
+            mp4_box(b"stsz", &[[0; 4], 0u32.to_be_bytes(), 2u32.to_be_bytes(), 7u32.to_be_bytes(), u32::MAX.to_be_bytes()].concat()),
+            mp4_box(b"stco", &[[0; 4], 1u32.to_be_bytes(), sample_offset.to_be_bytes()].concat()),
+            mp4_box(b"stsc", &[[0; 4], 1u32.to_be_bytes(), 1u32.to_be_bytes(), 2u32.to_be_bytes(), 1u32.to_be_bytes()].concat()),
+            mp4_box(b"stts", &[[0; 4], 1u32.to_be_bytes(), 2u32.to_be_bytes(), 5000u32.to_be_bytes()].concat()),
+        ].concat();
+        let mdhd = mp4_box(b"mdhd", &[&[0; 12][..], &1000u32.to_be_bytes(), &10_000u32.to_be_bytes(), &[0; 4]].concat());
+        let mdia = mp4_box(b"mdia", &[mdhd, mp4_box(b"minf", &mp4_box(b"stbl", &stbl))].concat());
+        let text = mp4_box(b"trak", &[tkhd(2), mdia].concat());
+        let moov = mp4_box(b"moov", &[audio, text].concat());
+
+        let file = [ftyp, mdat, moov].concat();
+        assert_eq!(read_chapters(&mut std::io::Cursor::new(file)).unwrap(), vec![
+            Chapter { start: Duration::ZERO, title: Some("Start".to_string()) },
+            Chapter { start: Duration::from_secs(5), title: Some("End".to_string()) },
+        ]);
+    }
+}
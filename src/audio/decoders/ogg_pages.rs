@@ -0,0 +1,49 @@
+//! Page-level helpers shared by the Ogg decoders (Opus and Vorbis)
+
+use std::io::{Read, Seek, SeekFrom};
+
+// An Ogg page is at most 27 + 255 header bytes and 255 * 255 data bytes
+const OGG_MAX_PAGE_SIZE: u64 = 65307;
+
+/// Finds the granule position of the last page of stream `serial` by scanning the end
+/// of the file, so the duration is known without decoding the whole stream
+pub fn last_granule_position<R: Read + Seek>(reader: &mut R, serial: u32) -> Option<u64> {
+    let len = reader.seek(SeekFrom::End(0)).ok()?;
+    let start = len.saturating_sub(2 * OGG_MAX_PAGE_SIZE);
+    reader.seek(SeekFrom::Start(start)).ok()?;
+    let mut tail = Vec::with_capacity((len - start) as usize);
+    reader.read_to_end(&mut tail).ok()?;
+
+    // Pages where no packet ends have a granule position of -1
+    (0..tail.len().saturating_sub(26)).rev()
+        .filter(|&i| &tail[i..i + 4] == b"OggS")
+        .map(|i| {
+            let granule = u64::from_le_bytes(tail[i + 6..i + 14].try_into().unwrap());
+            let page_serial = u32::from_le_bytes(tail[i + 14..i + 18].try_into().unwrap());
+            (granule, page_serial)
+        })
+        .find(|&(granule, page_serial)| page_serial == serial && granule != u64::MAX)
+        .map(|(granule, _)| granule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_granule_position() {
+        fn page(granule: u64, serial: u32) -> Vec<u8> {
+            let mut page = b"OggS\0\0".to_vec();
+            page.extend(granule.to_le_bytes());
+            page.extend(serial.to_le_bytes());
+            page.extend([0; 9]);
+            page.extend([0xAB; 40]);
+            page
+        }
+        let data = [page(960, 7), page(48312, 7), page(u64::MAX, 7), page(99999, 8)].concat();
+
+        let granule = last_granule_position(&mut std::io::Cursor::new(data), 7);
+        assert_eq!(granule, Some(48312));
+        assert_eq!(last_granule_position(&mut std::io::Cursor::new(Vec::new()), 7), None);
+    }
+}
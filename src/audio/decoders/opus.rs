@@ -1,15 +1,321 @@
-use std::{collections::VecDeque, fs::File, io::BufReader, path::Path, time::Duration};
-use anyhow::{Result, anyhow};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufReader,
+    os::raw::c_int,
+    path::Path,
+    ptr::NonNull,
+    time::Duration,
+};
+use anyhow::{Result, anyhow, bail};
+use audiopus_sys as ffi;
 use ogg::reading::PacketReader;
 use opus::Decoder as OpusDecoder;
 
+use crate::audio::info::{parse_vorbis_comments, DecoderInfo};
+use crate::audio::probe::{AudioFormat, ProbeData};
+use crate::audio::frames::{drain_samples, FrameReader, FrameSource};
+use crate::audio::registry::{BoxedSource, DecoderFactory, SCORE_CERTAIN};
+use super::mp4::Mp4Track;
+use super::ogg_pages::last_granule_position;
+
 const INITIAL_BUFFER_CAPACITY: usize = 4096;
-const OPUS_BUFFER_SIZE: usize = 2880;
+// Frames in the longest Opus packet (120 ms at 48 kHz)
+const OPUS_MAX_FRAME_SIZE: usize = 5760;
+const OPUS_SAMPLE_RATE: u32 = 48000;
+// RFC 7845 recommends decoding at least 80 ms before the seek target so the decoder converges
+const SEEK_PRE_ROLL: u64 = 3840;
 
-pub struct DecoderOpus {
-    decoder: OpusDecoder,
-    packet_reader: PacketReader<BufReader<File>>,
+/// Where each output channel (in WAVE order) is found in the Vorbis channel order used by
+/// mapping family 1, for 1 to 8 channels
+const VORBIS_TO_WAVE_ORDER: [&[usize]; 8] = [
+    &[0],
+    &[0, 1],
+    &[0, 2, 1],
+    &[0, 1, 2, 3],
+    &[0, 2, 1, 3, 4],
+    &[0, 2, 1, 5, 3, 4],
+    &[0, 2, 1, 6, 5, 3, 4],
+    &[0, 2, 1, 7, 5, 6, 3, 4],
+];
+
+/// The identification header of an Ogg Opus stream (RFC 7845, section 5.1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpusHead {
+    pub channels: u8,
+    /// Samples the encoder prepended as priming: they must not be played
+    pub pre_skip: u16,
+    /// Sample rate of the original input, informational only
+    pub input_sample_rate: u32,
+    /// Gain to apply to the decoded output, in Q7.8 dB
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    /// Decoded channel for each output channel; 255 means silence
+    pub mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 19 || &data[..8] != b"OpusHead" {
+            bail!("Invalid Opus header");
+        }
+        // Only the major version (upper 4 bits) breaks compatibility
+        if data[8] >> 4 != 0 {
+            bail!("Unsupported Opus version {}", data[8]);
+        }
+        let channels = data[9];
+        if channels == 0 {
+            bail!("Invalid Opus header: no channels");
+        }
+
+        let mut head = Self {
+            channels,
+            pre_skip: u16::from_le_bytes([data[10], data[11]]),
+            input_sample_rate: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            output_gain: i16::from_le_bytes([data[16], data[17]]),
+            mapping_family: data[18],
+            stream_count: 1,
+            coupled_count: channels - 1,
+            mapping: (0..channels).collect(),
+        };
+
+        match head.mapping_family {
+            0 if channels > 2 => bail!("Invalid Opus header: {} channels in mapping family 0", channels),
+            0 => return Ok(head),
+            1 if channels > 8 => bail!("Invalid Opus header: {} channels in mapping family 1", channels),
+            1 | 255 => {}
+            family => bail!("Unsupported Opus channel mapping family {}", family),
+        }
+
+        let table = data.get(21..21 + channels as usize)
+            .ok_or_else(|| anyhow!("Invalid Opus header: truncated channel mapping"))?;
+        head.stream_count = data[19];
+        head.coupled_count = data[20];
+        head.mapping = table.to_vec();
+
+        let decoded_channels = head.stream_count as usize + head.coupled_count as usize;
+        if head.stream_count == 0 || head.coupled_count > head.stream_count || decoded_channels > 255 {
+            bail!("Invalid Opus header: {} streams, {} coupled", head.stream_count, head.coupled_count);
+        }
+        if head.mapping.iter().any(|&index| index != 255 && index as usize >= decoded_channels) {
+            bail!("Invalid Opus header: channel mapping out of range");
+        }
+        Ok(head)
+    }
+
+    /// Parses the `dOps` box of Opus in MP4, which holds the same fields as OpusHead but
+    /// big-endian and without the magic signature
+    pub fn from_dops(data: &[u8]) -> Result<Self> {
+        if data.len() < 11 {
+            bail!("Invalid Opus dOps box");
+        }
+        if data[0] != 0 {
+            bail!("Unsupported Opus dOps version {}", data[0]);
+        }
+
+        let mut head = b"OpusHead".to_vec();
+        head.extend([1, data[1]]);
+        head.extend(u16::from_be_bytes([data[2], data[3]]).to_le_bytes());
+        head.extend(u32::from_be_bytes([data[4], data[5], data[6], data[7]]).to_le_bytes());
+        head.extend(i16::from_be_bytes([data[8], data[9]]).to_le_bytes());
+        // Mapping family, then the stream counts and mapping table, if any
+        head.extend(&data[10..]);
+        Self::parse(&head)
+    }
+
+    /// Linear factor for the output gain
+    pub fn gain_factor(&self) -> f32 {
+        10f32.powf(self.output_gain as f32 / (20.0 * 256.0))
+    }
+
+    /// The mapping table with the output reordered from Vorbis to WAVE channel order, which
+    /// is what the audio output expects for surround sound
+    fn output_mapping(&self) -> Vec<u8> {
+        match self.mapping_family {
+            1 => VORBIS_TO_WAVE_ORDER[self.channels as usize - 1].iter()
+                .map(|&vorbis| self.mapping[vorbis])
+                .collect(),
+            _ => self.mapping.clone(),
+        }
+    }
+}
+
+/// Parses the OpusTags packet (RFC 7845, section 5.2) into (upper-case key, value) comments
+pub fn parse_opus_tags(data: &[u8]) -> Result<Vec<(String, String)>> {
+    let comments = data.strip_prefix(b"OpusTags")
+        .ok_or_else(|| anyhow!("Invalid Opus comments"))?;
+    parse_vorbis_comments(comments)
+}
+
+/// Safe wrapper around libopus' multistream decoder, which the `opus` crate does not expose
+struct MultistreamDecoder {
+    state: NonNull<ffi::OpusMSDecoder>,
+    channels: usize,
+}
+
+// The decoder state is only ever used through `&mut self`
+unsafe impl Send for MultistreamDecoder {}
+
+impl MultistreamDecoder {
+    fn new(head: &OpusHead) -> Result<Self> {
+        let mapping = head.output_mapping();
+        let mut error: c_int = 0;
+        let state = unsafe {
+            ffi::opus_multistream_decoder_create(
+                OPUS_SAMPLE_RATE as ffi::opus_int32,
+                head.channels as c_int,
+                head.stream_count as c_int,
+                head.coupled_count as c_int,
+                mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        match NonNull::new(state) {
+            Some(state) if error == ffi::OPUS_OK => Ok(Self { state, channels: head.channels as usize }),
+            _ => bail!("Failed to create Opus multistream decoder (error {})", error),
+        }
+    }
+
+    fn decode_float(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize> {
+        let frames = unsafe {
+            ffi::opus_multistream_decode_float(
+                self.state.as_ptr(),
+                input.as_ptr(),
+                input.len() as ffi::opus_int32,
+                output.as_mut_ptr(),
+                (output.len() / self.channels) as c_int,
+                0,
+            )
+        };
+        if frames < 0 {
+            bail!("Opus multistream decoding error {}", frames);
+        }
+        Ok(frames as usize)
+    }
+
+    fn reset_state(&mut self) -> Result<()> {
+        let result = unsafe { ffi::opus_multistream_decoder_ctl(self.state.as_ptr(), ffi::OPUS_RESET_STATE) };
+        if result != ffi::OPUS_OK {
+            bail!("Failed to reset Opus multistream decoder (error {})", result);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MultistreamDecoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_multistream_decoder_destroy(self.state.as_ptr()) }
+    }
+}
+
+/// Mapping family 0 (mono or stereo) uses a plain decoder, families 1 and 255 the
+/// multistream one
+enum Decoder {
+    Single(OpusDecoder),
+    Multistream(MultistreamDecoder),
+}
+
+impl Decoder {
+    fn new(head: &OpusHead) -> Result<Self> {
+        Ok(match head.mapping_family {
+            0 => {
+                let channels = if head.channels == 1 { opus::Channels::Mono } else { opus::Channels::Stereo };
+                Decoder::Single(OpusDecoder::new(OPUS_SAMPLE_RATE, channels)?)
+            }
+            _ => Decoder::Multistream(MultistreamDecoder::new(head)?),
+        })
+    }
+
+    fn decode_float(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize> {
+        match self {
+            Decoder::Single(decoder) => Ok(decoder.decode_float(input, output, false)?),
+            Decoder::Multistream(decoder) => decoder.decode_float(input, output),
+        }
+    }
+
+    fn reset_state(&mut self) -> Result<()> {
+        match self {
+            Decoder::Single(decoder) => Ok(decoder.reset_state()?),
+            Decoder::Multistream(decoder) => decoder.reset_state(),
+        }
+    }
+}
+
+/// Decodes packets into interleaved samples, applying the pre-skip and output gain
+struct PacketDecoder {
+    decoder: Decoder,
     sample_buffer: VecDeque<f32>,
+    output_buffer: Vec<f32>,
+    channels: usize,
+    gain: f32,
+    samples_to_skip: usize,
+    /// Frames decoded so far, pre-skip included, when known
+    granule_position: u64,
+}
+
+impl PacketDecoder {
+    fn new(head: &OpusHead) -> Result<Self> {
+        let channels = head.channels as usize;
+        Ok(Self {
+            decoder: Decoder::new(head)?,
+            sample_buffer: VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY),
+            output_buffer: vec![0.0; OPUS_MAX_FRAME_SIZE * channels],
+            channels,
+            gain: head.gain_factor(),
+            samples_to_skip: head.pre_skip as usize * channels,
+            granule_position: 0,
+        })
+    }
+
+    /// Clears the decoder state and buffered samples before decoding from a new position
+    fn reset(&mut self) -> Result<()> {
+        self.decoder.reset_state()?;
+        self.sample_buffer.clear();
+        self.samples_to_skip = 0;
+        Ok(())
+    }
+
+    /// Decodes a packet into the sample buffer, keeping at most `max_frames` of it (the
+    /// container's way to trim the padding at the end of the stream)
+    fn decode(&mut self, data: &[u8], max_frames: Option<usize>) {
+        let Ok(mut decoded_frames) = self.decoder.decode_float(data, &mut self.output_buffer) else {
+            return;
+        };
+        if let Some(max_frames) = max_frames {
+            decoded_frames = decoded_frames.min(max_frames);
+        }
+        self.granule_position += decoded_frames as u64;
+
+        let decoded = &self.output_buffer[..decoded_frames * self.channels];
+        let skip = self.samples_to_skip.min(decoded.len());
+        self.samples_to_skip -= skip;
+        if self.gain == 1.0 {
+            self.sample_buffer.extend(&decoded[skip..]);
+        } else {
+            self.sample_buffer.extend(decoded[skip..].iter().map(|sample| sample * self.gain));
+        }
+    }
+}
+
+/// Where the Opus packets come from
+enum PacketSource {
+    Ogg(PacketReader<BufReader<File>>),
+    /// Opus in MP4: packets come from the demuxer's index, in track timescale units
+    Mp4 {
+        reader: BufReader<File>,
+        track: Mp4Track,
+        next_packet: usize,
+    },
+}
+
+pub struct DecoderOpus {
+    packets: PacketDecoder,
+    source: PacketSource,
+    pre_skip: u64,
+    duration: Option<Duration>,
+    comments: Vec<(String, String)>,
 }
 
 impl DecoderOpus {
@@ -17,59 +323,301 @@ impl DecoderOpus {
         let file = BufReader::new(File::open(path)?);
         let mut packet_reader = PacketReader::new(file);
 
-        let _header = packet_reader.read_packet()?
+        let header = packet_reader.read_packet()?
             .ok_or_else(|| anyhow!("Missing Opus header"))?;
+        let head = OpusHead::parse(&header.data)?;
 
-        let _comments = packet_reader.read_packet()?
+        let comments = packet_reader.read_packet()?
             .ok_or_else(|| anyhow!("Missing Opus comments"))?;
+        // Broken tags should not keep the audio from playing
+        let comments = parse_opus_tags(&comments.data).unwrap_or_default();
+
+        // The last granule position counts the pre-skip too
+        let duration = last_granule_position(&mut File::open(path)?, header.stream_serial())
+            .map(|granule| granule.saturating_sub(head.pre_skip as u64))
+            .map(|samples| Duration::from_secs_f64(samples as f64 / OPUS_SAMPLE_RATE as f64));
 
         Ok(Self {
-            decoder: OpusDecoder::new(48000, opus::Channels::Stereo)?,
-            packet_reader,
-            sample_buffer: VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY),
+            packets: PacketDecoder::new(&head)?,
+            source: PacketSource::Ogg(packet_reader),
+            pre_skip: head.pre_skip as u64,
+            duration,
+            comments,
         })
-    }   
-}
+    }
 
-impl Iterator for DecoderOpus {
-    type Item = f32;
+    /// Decodes the Opus track of an MP4 file, whose `dOps` box stands in for OpusHead
+    pub fn from_mp4(reader: BufReader<File>, track: Mp4Track) -> Result<Self> {
+        if track.timescale == 0 {
+            bail!("Invalid MP4 track timescale");
+        }
+        let dops = track.codec_box(b"dOps")
+            .ok_or_else(|| anyhow!("MP4 Opus track has no dOps box"))?;
+        let head = OpusHead::from_dops(dops)?;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(sample) = self.sample_buffer.pop_front() {
-            return Some(sample);
+        // The track duration counts the pre-skip too
+        let samples = to_opus_rate(track.duration, track.timescale).saturating_sub(head.pre_skip as u64);
+
+        Ok(Self {
+            packets: PacketDecoder::new(&head)?,
+            pre_skip: head.pre_skip as u64,
+            duration: Some(Duration::from_secs_f64(samples as f64 / OPUS_SAMPLE_RATE as f64)),
+            comments: Vec::new(),
+            source: PacketSource::Mp4 { reader, track, next_packet: 0 },
+        })
+    }
+
+    /// The duration from the container, plus the OpusTags comments, or the MP4 chapters
+    pub fn info(&self) -> DecoderInfo {
+        let mut info = DecoderInfo::from_reader(self).with_comments(self.comments.clone());
+        if let PacketSource::Mp4 { track, .. } = &self.source {
+            info.chapters = track.chapters.clone();
         }
-        
-        // Read and decode the next packet
-        while self.sample_buffer.is_empty() {
-            match self.packet_reader.read_packet() {
-                Ok(Some(packet)) => {
-                    let mut output_buffer = vec![0.0f32; OPUS_BUFFER_SIZE]; // Max frame size for 120ms
-                    if let Ok(decoded_samples) = self.decoder.decode_float(&packet.data, &mut output_buffer, false) {
-                        self.sample_buffer.extend(output_buffer.into_iter().take(decoded_samples * 2));
-                    } 
+        info
+    }
+
+    /// Decodes from a little before `pos`, so the decoder converges, and trims the decoded
+    /// samples up to the exact target
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        // Timestamps count the pre-skip samples too
+        let target = (pos.as_secs_f64() * OPUS_SAMPLE_RATE as f64) as u64 + self.pre_skip;
+        let start = target.saturating_sub(SEEK_PRE_ROLL);
+
+        match &mut self.source {
+            PacketSource::Ogg(packet_reader) => {
+                // Bisects the Ogg pages on granule position
+                if !packet_reader.seek_absgp(None, start)? {
+                    bail!("Cannot seek beyond end of track");
+                }
+                self.packets.reset()?;
+                // Unknown until the first page boundary; this also disables end trimming meanwhile
+                self.packets.granule_position = 0;
+
+                // The page granule position marks the end of its last packet, which tells us
+                // where the samples decoded so far start
+                while let Some(packet) = packet_reader.read_packet()? {
+                    self.packets.decode(&packet.data, None);
+                    if packet.last_in_page() {
+                        let channels = self.packets.channels;
+                        let buffer = &mut self.packets.sample_buffer;
+                        let start = packet.absgp_page().saturating_sub((buffer.len() / channels) as u64);
+                        let skip = target.saturating_sub(start) as usize * channels;
+                        let drained = skip.min(buffer.len());
+                        buffer.drain(..drained);
+                        // A page that ends before the target leaves the rest to later packets
+                        self.packets.samples_to_skip = skip - drained;
+                        self.packets.granule_position = packet.absgp_page();
+                        break;
+                    }
                 }
-                _ => return None, // End of stream error
+            }
+            PacketSource::Mp4 { track, next_packet, .. } => {
+                let index = track.packet_at(start * track.timescale as u64 / OPUS_SAMPLE_RATE as u64);
+                let packet = track.packets.get(index)
+                    .filter(|_| target < to_opus_rate(track.duration, track.timescale))
+                    .ok_or_else(|| anyhow!("Cannot seek beyond end of track"))?;
+                let packet_start = to_opus_rate(packet.timestamp, track.timescale);
+
+                self.packets.reset()?;
+                self.packets.granule_position = packet_start;
+                // Dropped as the packets are decoded
+                self.packets.samples_to_skip = target.saturating_sub(packet_start) as usize * self.packets.channels;
+                *next_packet = index;
             }
         }
-    
-        self.sample_buffer.pop_front()
+
+        Ok(())
     }
-}
 
-impl rodio::Source for DecoderOpus {
-    fn current_frame_len(&self) -> Option<usize> {
-        Some(960)
+    /// Reads and decodes the next packet; false at the end of the stream
+    fn decode_next_packet(&mut self) -> Result<bool> {
+        match &mut self.source {
+            PacketSource::Ogg(packet_reader) => {
+                let Some(packet) = packet_reader.read_packet()? else { return Ok(false) };
+                // The final granule position cuts off the padding of the last packet
+                let granule_position = self.packets.granule_position;
+                let max_frames = (packet.last_in_stream() && granule_position > 0)
+                    .then(|| packet.absgp_page().saturating_sub(granule_position) as usize);
+                self.packets.decode(&packet.data, max_frames);
+            }
+            PacketSource::Mp4 { reader, track, next_packet } => {
+                let Some(data) = track.read_packet(reader, *next_packet)? else { return Ok(false) };
+                // Each sample's duration says how much of the packet is played
+                let duration = track.packets[*next_packet].duration as u64;
+                *next_packet += 1;
+                self.packets.decode(&data, Some(to_opus_rate(duration, track.timescale) as usize));
+            }
+        }
+        Ok(true)
     }
+}
+
+/// Converts a duration in track timescale units to 48 kHz samples
+fn to_opus_rate(value: u64, timescale: u32) -> u64 {
+    value * OPUS_SAMPLE_RATE as u64 / timescale as u64
+}
 
+impl FrameReader for DecoderOpus {
     fn channels(&self) -> u16 {
-        2
+        self.packets.channels as u16
     }
 
     fn sample_rate(&self) -> u32 {
-        48000
+        OPUS_SAMPLE_RATE
     }
 
     fn total_duration(&self) -> Option<Duration> {
-        None
+        self.duration
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.packets.channels;
+        let wanted = buffer.len() / channels * channels;
+        let mut written = 0;
+
+        while written < wanted {
+            if self.packets.sample_buffer.is_empty() {
+                // Read and decode the next packet
+                match self.decode_next_packet() {
+                    Ok(true) => continue,
+                    _ => break, // End of stream error
+                }
+            }
+            written += drain_samples(&mut self.packets.sample_buffer, &mut buffer[written..wanted]);
+        }
+
+        written / channels
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        DecoderOpus::seek(self, pos)
     }
-}
\ No newline at end of file
+
+    fn info(&self) -> DecoderInfo {
+        DecoderOpus::info(self)
+    }
+}
+
+/// Ogg Opus files; Opus in MP4 is opened by `Mp4Factory`
+pub struct OpusFactory;
+
+impl DecoderFactory for OpusFactory {
+    fn name(&self) -> &str {
+        "opus"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["opus"]
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/opus", "audio/ogg; codecs=opus"]
+    }
+
+    fn probe(&self, probe: &ProbeData) -> u8 {
+        probe.score(&[AudioFormat::OggOpus], self.extensions(), SCORE_CERTAIN)
+    }
+
+    fn open(&self, path: &Path) -> Result<BoxedSource> {
+        Ok(Box::new(FrameSource::new(DecoderOpus::load(path)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::test_utils::{read_all, resource};
+
+    fn header(channels: u8, family: u8, table: &[u8]) -> Vec<u8> {
+        let mut data = b"OpusHead".to_vec();
+        data.extend([1, channels]);
+        data.extend(312u16.to_le_bytes());
+        data.extend(44100u32.to_le_bytes());
+        data.extend((-256i16).to_le_bytes());
+        data.push(family);
+        data.extend(table);
+        data
+    }
+
+    #[test]
+    fn test_parse_mono_header() {
+        let head = OpusHead::parse(&header(1, 0, &[])).unwrap();
+        assert_eq!(head.channels, 1);
+        assert_eq!(head.pre_skip, 312);
+        assert_eq!(head.input_sample_rate, 44100);
+        assert_eq!((head.stream_count, head.coupled_count), (1, 0));
+        assert!((head.gain_factor() - 10f32.powf(-1.0 / 20.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_parse_surround_header() {
+        // 5.1: 4 streams, 2 of them coupled, in Vorbis order FL C FR RL RR LFE
+        let head = OpusHead::parse(&header(6, 1, &[4, 2, 0, 4, 1, 2, 3, 5])).unwrap();
+        assert_eq!((head.stream_count, head.coupled_count), (4, 2));
+        assert_eq!(head.mapping, vec![0, 4, 1, 2, 3, 5]);
+        // WAVE order FL FR C LFE RL RR
+        assert_eq!(head.output_mapping(), vec![0, 1, 4, 5, 2, 3]);
+    }
+
+    #[test]
+    fn test_parse_dops() {
+        let dops = [0, 2, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0, 0, 0];
+        let head = OpusHead::parse(&header(2, 0, &[])).unwrap();
+        let from_dops = OpusHead::from_dops(&dops).unwrap();
+        assert_eq!(from_dops.channels, 2);
+        assert_eq!(from_dops.pre_skip, 312);
+        assert_eq!(from_dops.input_sample_rate, 48000);
+        assert_eq!(from_dops.mapping, head.mapping);
+        assert!(OpusHead::from_dops(&dops[..8]).is_err());
+    }
+
+    #[test]
+    fn test_parse_opus_tags() {
+        let mut data = b"OpusTags".to_vec();
+        data.extend(6u32.to_le_bytes());
+        data.extend(b"vendor");
+        data.extend(3u32.to_le_bytes());
+        for comment in ["title=Song", "R128_TRACK_GAIN=-512", "no separator"] {
+            data.extend((comment.len() as u32).to_le_bytes());
+            data.extend(comment.as_bytes());
+        }
+
+        let comments = parse_opus_tags(&data).unwrap();
+        assert_eq!(comments, vec![
+            ("TITLE".to_string(), "Song".to_string()),
+            ("R128_TRACK_GAIN".to_string(), "-512".to_string()),
+        ]);
+        assert!(parse_opus_tags(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_headers() {
+        assert!(OpusHead::parse(&header(3, 0, &[])).is_err());
+        assert!(OpusHead::parse(&header(0, 0, &[])).is_err());
+        assert!(OpusHead::parse(&header(6, 1, &[4, 2, 0, 4])).is_err());
+        assert!(OpusHead::parse(&header(2, 1, &[1, 0, 0, 1])).is_err());
+        assert!(OpusHead::parse(&header(2, 2, &[1, 1, 0, 1])).is_err());
+    }
+
+    #[test]
+    fn test_seek_is_exact() {
+        let path = resource("test.opus");
+        let reference = read_all(&mut DecoderOpus::load(&path).unwrap());
+        let channels = DecoderOpus::load(&path).unwrap().channels() as usize;
+
+        // 1.05 s starts its pre-roll in the first page, which ends before the target
+        for millis in [500, 1050, 2500] {
+            let mut decoder = DecoderOpus::load(&path).unwrap();
+            decoder.seek(Duration::from_millis(millis)).unwrap();
+            let decoded = read_all(&mut decoder);
+
+            // Decoding restarts at the seek, so it matches the reference closely, not exactly
+            let offset = millis as usize * 48 * channels;
+            assert_eq!(decoded.len(), reference.len() - offset, "seek to {} ms", millis);
+            let error = decoded.iter().zip(&reference[offset..])
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            assert!(error < 0.01, "seek to {} ms is off by up to {}", millis, error);
+        }
+    }
+}
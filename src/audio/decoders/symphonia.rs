@@ -0,0 +1,321 @@
+use std::{fs::File, io::{BufReader, Seek}, path::Path, time::Duration};
+use anyhow::{Result, anyhow};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error,
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::Time,
+};
+
+use crate::audio::chapters::{read_chapters, Chapter};
+use crate::audio::gapless::{EncoderDelay, GaplessTrim};
+use crate::audio::info::DecoderInfo;
+use crate::audio::probe::{AudioFormat, ProbeData};
+use crate::audio::frames::{FrameReader, FrameSource};
+use crate::audio::registry::{BoxedSource, DecoderFactory, SCORE_SUPPORTED};
+
+/// Consecutive packets that may fail to decode before the stream is given up on
+const MAX_DECODE_RETRIES: usize = 3;
+
+/// Symphonia's format reader and decoder for one track, yielding interleaved f32 samples
+struct SymphoniaStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    /// The last decoded packet, converted from the decoder's native sample format
+    samples: Option<SampleBuffer<f32>>,
+    position: usize,
+    /// Channels of the last decoded packet
+    channels: usize,
+}
+
+impl SymphoniaStream {
+    /// Decodes the next packet of the track into `samples`, returning false at the end
+    fn decode_next_packet(&mut self) -> bool {
+        let mut errors = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(_)) => return false,
+                Err(e) => {
+                    eprintln!("Symphonia error: {}", e);
+                    return false;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    self.channels = decoded.spec().channels.count();
+                    // Reallocated only when a packet outgrows the buffer
+                    let required = decoded.capacity() * self.channels;
+                    if self.samples.as_ref().is_some_and(|samples| samples.capacity() < required) {
+                        self.samples = None;
+                    }
+                    let spec = *decoded.spec();
+                    let capacity = decoded.capacity() as u64;
+                    self.samples
+                        .get_or_insert_with(|| SampleBuffer::new(capacity, spec))
+                        .copy_interleaved_ref(decoded);
+                    self.position = 0;
+                    return true;
+                }
+                // A corrupt packet is skipped, but not a run of them
+                Err(Error::DecodeError(e)) if errors < MAX_DECODE_RETRIES => {
+                    eprintln!("Symphonia decoding error: {}", e);
+                    errors += 1;
+                }
+                Err(e) => {
+                    eprintln!("Symphonia decoding error: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for SymphoniaStream {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(&sample) = self.samples.as_ref().and_then(|samples| samples.samples().get(self.position)) {
+                self.position += 1;
+                return Some(sample);
+            }
+            if !self.decode_next_packet() {
+                return None;
+            }
+        }
+    }
+}
+
+/// The general-purpose backend: Symphonia, which rodio bundles, driven directly so
+/// samples reach playback as f32 in the decoder's full precision
+pub struct SymphoniaDecoder {
+    stream: SymphoniaStream,
+    channels: u16,
+    sample_rate: u32,
+    total_duration: Option<Duration>,
+    bits_per_sample: Option<u32>,
+    trim: Option<GaplessTrim>,
+    /// ID3 `CHAP` frames or MP4 chapters, which Symphonia does not read
+    chapters: Vec<Chapter>,
+}
+
+impl SymphoniaDecoder {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        // Symphonia does not apply the iTunes gapless tag of MP4/AAC files, so we do
+        let encoder_delay = EncoderDelay::read_mp4(&mut file);
+        let chapters = read_chapters(&mut file);
+        file.rewind()?;
+
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(extension);
+        }
+        let source = MediaSourceStream::new(Box::new(file.into_inner()), Default::default());
+        let format_options = FormatOptions { enable_gapless: true, ..Default::default() };
+        let probed = symphonia::default::get_probe()
+            .format(&hint, source, &format_options, &MetadataOptions::default())
+            .map_err(|e| anyhow!("Symphonia error: {}", e))?;
+
+        let track = probed.format.tracks().iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| anyhow!("No track with a supported codec"))?;
+        let track_id = track.id;
+        let params = track.codec_params.clone();
+        let decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .map_err(|e| anyhow!("Symphonia error: {}", e))?;
+
+        let mut stream = SymphoniaStream {
+            format: probed.format,
+            decoder,
+            track_id,
+            samples: None,
+            position: 0,
+            channels: 0,
+        };
+
+        // Some containers only reveal the channel layout in the first packet
+        let channels = match params.channels {
+            Some(channels) => channels.count() as u16,
+            None => {
+                stream.decode_next_packet();
+                stream.channels as u16
+            }
+        };
+        let sample_rate = params.sample_rate
+            .ok_or_else(|| anyhow!("Unknown sample rate"))?;
+        let total_duration = params.time_base.zip(params.n_frames)
+            .map(|(time_base, frames)| {
+                let Time { seconds, frac } = time_base.calc_time(frames);
+                Duration::from_secs(seconds) + Duration::from_secs_f64(frac)
+            });
+        let trim = encoder_delay.map(|info| GaplessTrim::new(info, channels));
+
+        Ok(Self {
+            stream,
+            channels,
+            sample_rate,
+            total_duration,
+            bits_per_sample: params.bits_per_sample,
+            trim,
+            chapters,
+        })
+    }
+
+    /// Seeks through Symphonia's format reader, then decodes up to the exact frame
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        let frame = (pos.as_secs_f64() * self.sample_rate as f64) as u64;
+        let raw_frame = self.trim.as_ref().map_or(frame, |trim| trim.raw_frame(frame));
+        let time = Time::from(raw_frame as f64 / self.sample_rate as f64);
+
+        let seeked = self.stream.format
+            .seek(SeekMode::Accurate, SeekTo::Time { time, track_id: Some(self.stream.track_id) })
+            .map_err(|e| anyhow!("Symphonia seek error: {}", e))?;
+        self.stream.decoder.reset();
+        if let Some(samples) = self.stream.samples.as_mut() {
+            samples.clear();
+        }
+
+        let skip = seeked.required_ts.saturating_sub(seeked.actual_ts) * self.channels as u64;
+        for _ in 0..skip {
+            if self.stream.next().is_none() {
+                break;
+            }
+        }
+        if let Some(trim) = self.trim.as_mut() {
+            trim.reposition(frame);
+        }
+        Ok(())
+    }
+}
+
+impl FrameReader for SymphoniaDecoder {
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+
+    fn bits_per_sample(&self) -> Option<u32> {
+        self.bits_per_sample
+    }
+
+    fn info(&self) -> DecoderInfo {
+        DecoderInfo { chapters: self.chapters.clone(), ..DecoderInfo::from_reader(self) }
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.channels.max(1) as usize;
+        let mut written = 0;
+        let wanted = buffer.len() / channels * channels;
+        for slot in &mut buffer[..wanted] {
+            let sample = match self.trim.as_mut() {
+                Some(trim) => trim.next_sample(&mut self.stream),
+                None => self.stream.next(),
+            };
+            let Some(sample) = sample else { break };
+            *slot = sample;
+            written += 1;
+        }
+        written / channels
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        SymphoniaDecoder::seek(self, pos)
+    }
+}
+
+/// Everything Symphonia decodes: MP3, FLAC, WAV, AAC (including M4B audiobooks) and Vorbis
+pub struct SymphoniaFactory;
+
+impl DecoderFactory for SymphoniaFactory {
+    fn name(&self) -> &str {
+        "symphonia"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mp3", "wav", "flac", "aac", "m4a", "m4b", "ogg"]
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/mpeg", "audio/flac", "audio/wav", "audio/x-wav", "audio/aac"]
+    }
+
+    fn probe(&self, probe: &ProbeData) -> u8 {
+        const FORMATS: &[AudioFormat] = &[
+            AudioFormat::Mp3,
+            AudioFormat::Flac,
+            AudioFormat::Wav,
+            AudioFormat::Aac,
+            AudioFormat::Mp4,
+            AudioFormat::OggVorbis,
+            AudioFormat::OggFlac,
+            AudioFormat::Ogg,
+        ];
+        probe.score(FORMATS, self.extensions(), SCORE_SUPPORTED)
+    }
+
+    fn open(&self, path: &Path) -> Result<BoxedSource> {
+        Ok(Box::new(FrameSource::new(SymphoniaDecoder::load(path)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::test_utils::{read_all, resource, wav_integers};
+
+    #[test]
+    fn test_keeps_24_bit_precision() {
+        let mut decoder = SymphoniaDecoder::load(&resource("test_24bit.wav")).unwrap();
+        assert_eq!(decoder.bits_per_sample(), Some(24));
+        assert_eq!(decoder.sample_rate(), 96000);
+        assert_eq!(decoder.info().resolution(), "24-bit/96kHz");
+
+        // Every low bit survives: scaled back up, each sample is the exact integer
+        let decoded: Vec<i32> = read_all(&mut decoder).iter()
+            .map(|&sample| (sample * 8_388_608.0) as i32)
+            .collect();
+        assert_eq!(decoded, wav_integers("test_24bit.wav", 3));
+    }
+
+    #[test]
+    fn test_decodes_16_bit() {
+        let mut decoder = SymphoniaDecoder::load(&resource("test.wav")).unwrap();
+        assert_eq!(decoder.bits_per_sample(), Some(16));
+
+        let expected: Vec<f32> = wav_integers("test.wav", 2).iter()
+            .map(|&sample| sample as f32 / 32768.0)
+            .collect();
+        assert_eq!(read_all(&mut decoder), expected);
+    }
+
+    #[test]
+    fn test_seek_is_exact() {
+        let mut decoder = SymphoniaDecoder::load(&resource("test_24bit.wav")).unwrap();
+        decoder.seek(Duration::from_millis(50)).unwrap();
+
+        let expected = wav_integers("test_24bit.wav", 3);
+        let mut block = [0.0; 4];
+        assert_eq!(decoder.read_frames(&mut block), 2);
+        let decoded: Vec<i32> = block.iter().map(|&sample| (sample * 8_388_608.0) as i32).collect();
+        assert_eq!(decoded, expected[9600..9604]);
+    }
+}
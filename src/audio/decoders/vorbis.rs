@@ -2,12 +2,19 @@ use std::{collections::VecDeque, fs::File, io::BufReader, path::Path, time::Dura
 use lewton::inside_ogg::OggStreamReader;
 use anyhow::{anyhow, Result};
 
+use crate::audio::info::DecoderInfo;
+use crate::audio::probe::{AudioFormat, ProbeData};
+use crate::audio::frames::{drain_samples, FrameReader, FrameSource};
+use crate::audio::registry::{BoxedSource, DecoderFactory, SCORE_CERTAIN};
+use super::ogg_pages::last_granule_position;
+
 const INITIAL_BUFFER_CAPACITY: usize = 4096;
 const I16_TO_F32_NORM_FACTOR: f32 = i16::MAX as f32;
 
 pub struct VorbisDecoder {
     decoder: OggStreamReader<BufReader<File>>,
     sample_buffer: VecDeque<f32>,
+    duration: Option<Duration>,
 }
 
 impl VorbisDecoder {
@@ -16,46 +23,56 @@ impl VorbisDecoder {
         let decoder = OggStreamReader::new(file)
             .map_err(|e| anyhow!("Vorbis decoding error: {:?}", e))?;
 
+        // The last granule position is the number of samples per channel
+        let sample_rate = decoder.ident_hdr.audio_sample_rate;
+        let duration = last_granule_position(&mut File::open(path)?, decoder.stream_serial())
+            .filter(|_| sample_rate > 0)
+            .map(|samples| Duration::from_secs_f64(samples as f64 / sample_rate as f64));
+
         Ok(Self {
             decoder,
             sample_buffer: VecDeque::with_capacity(INITIAL_BUFFER_CAPACITY),
+            duration,
         })
     }
-}
 
-impl Iterator for VorbisDecoder {
-    type Item = f32;
+    /// Seeks to the page holding `pos`, then decodes up to the next page boundary
+    /// to learn the exact granule position and trims the samples before `pos`
+    pub fn seek(&mut self, pos: Duration) -> Result<()> {
+        let channels = self.decoder.ident_hdr.audio_channels as u64;
+        let target = (pos.as_secs_f64() * self.decoder.ident_hdr.audio_sample_rate as f64) as u64;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(sample) = self.sample_buffer.pop_front() {
-            return Some(sample);
-        }
+        self.decoder.seek_absgp_pg(target)
+            .map_err(|e| anyhow!("Vorbis seek error: {:?}", e))?;
+        self.sample_buffer.clear();
 
-        while self.sample_buffer.is_empty() {
+        while self.decoder.get_last_absgp().is_none() {
             match self.decoder.read_dec_packet_itl() {
-                Ok(Some(pck_samples)) => {
-                    for sample in pck_samples {
-                        self.sample_buffer.push_back(sample as f32 / I16_TO_F32_NORM_FACTOR);
-                    }
-                }
-                Ok(None) => return None, // End of stream
-                Err(e) => {
-                    eprintln!("Vorbis decoding error: {:?}", e);
-                    return None;
-                }
+                Ok(Some(pck_samples)) => self.sample_buffer.extend(
+                    pck_samples.into_iter().map(|s| s as f32 / I16_TO_F32_NORM_FACTOR)
+                ),
+                Ok(None) => break,
+                Err(e) => return Err(anyhow!("Vorbis decoding error: {:?}", e)),
             }
         }
 
-        self.sample_buffer.pop_front()
-    }
-}
+        if let Some(absgp) = self.decoder.get_last_absgp() {
+            let buffered_frames = self.sample_buffer.len() as u64 / channels;
+            let start = absgp.saturating_sub(buffered_frames);
+            let skip = (target.saturating_sub(start) * channels) as usize;
+            self.sample_buffer.drain(..skip.min(self.sample_buffer.len()));
+        }
 
-impl rodio::Source for VorbisDecoder {
+        Ok(())
+    }
 
-    fn current_frame_len(&self) -> Option<usize> {
-        None
+    /// Stream format plus the Vorbis comments
+    pub fn info(&self) -> DecoderInfo {
+        DecoderInfo::from_reader(self).with_comments(self.decoder.comment_hdr.comment_list.clone())
     }
+}
 
+impl FrameReader for VorbisDecoder {
     fn channels(&self) -> u16 {
         self.decoder.ident_hdr.audio_channels as u16
     }
@@ -65,7 +82,66 @@ impl rodio::Source for VorbisDecoder {
     }
 
     fn total_duration(&self) -> Option<Duration> {
-        None
+        self.duration
     }
 
-}
\ No newline at end of file
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.decoder.ident_hdr.audio_channels as usize;
+        let wanted = buffer.len() / channels * channels;
+        let mut written = 0;
+
+        while written < wanted {
+            if self.sample_buffer.is_empty() {
+                match self.decoder.read_dec_packet_itl() {
+                    Ok(Some(pck_samples)) => {
+                        self.sample_buffer.extend(
+                            pck_samples.into_iter().map(|s| s as f32 / I16_TO_F32_NORM_FACTOR)
+                        );
+                        continue;
+                    }
+                    Ok(None) => break, // End of stream
+                    Err(e) => {
+                        eprintln!("Vorbis decoding error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            written += drain_samples(&mut self.sample_buffer, &mut buffer[written..wanted]);
+        }
+
+        written / channels
+    }
+
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        VorbisDecoder::seek(self, pos)
+    }
+
+    fn info(&self) -> DecoderInfo {
+        VorbisDecoder::info(self)
+    }
+}
+
+/// Ogg Vorbis files, decoded with lewton
+pub struct VorbisFactory;
+
+impl DecoderFactory for VorbisFactory {
+    fn name(&self) -> &str {
+        "vorbis"
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ogg", "oga"]
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/ogg", "audio/vorbis"]
+    }
+
+    fn probe(&self, probe: &ProbeData) -> u8 {
+        probe.score(&[AudioFormat::OggVorbis], self.extensions(), SCORE_CERTAIN)
+    }
+
+    fn open(&self, path: &Path) -> Result<BoxedSource> {
+        Ok(Box::new(FrameSource::new(VorbisDecoder::load(path)?)))
+    }
+}
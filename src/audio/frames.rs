@@ -0,0 +1,206 @@
+//! Block-based decoding: decoders fill whole buffers of frames, and `FrameSource` turns
+//! that into the per-sample `Source` rodio wants
+
+use std::{collections::VecDeque, time::Duration};
+use anyhow::Result;
+use rodio::Source;
+
+use super::info::DecoderInfo;
+use super::registry::AudioSource;
+
+/// Frames per block read by `FrameSource`
+const BLOCK_FRAMES: usize = 1024;
+
+/// A decoder that produces interleaved f32 samples a block at a time
+pub trait FrameReader: Send {
+    fn channels(&self) -> u16;
+
+    fn sample_rate(&self) -> u32;
+
+    fn total_duration(&self) -> Option<Duration>;
+
+    /// Bit depth of the source, where the format has one (lossless and PCM formats)
+    fn bits_per_sample(&self) -> Option<u32> {
+        None
+    }
+
+    /// Fills `buffer` with whole interleaved frames and returns how many were written.
+    /// Fewer than fit are returned only at the end of the stream, and 0 after it.
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize;
+
+    /// Seeks using the backend's native seeking, leaving the decoder positioned at `pos`
+    fn seek(&mut self, pos: Duration) -> Result<()>;
+
+    /// Duration, format and tags as reported by the decoder
+    fn info(&self) -> DecoderInfo {
+        DecoderInfo::from_reader(self)
+    }
+}
+
+/// Moves samples from the front of `queue` into `buffer`, returning how many were moved
+pub fn drain_samples(queue: &mut VecDeque<f32>, buffer: &mut [f32]) -> usize {
+    let count = queue.len().min(buffer.len());
+    let (front, back) = queue.as_slices();
+    let from_front = count.min(front.len());
+    buffer[..from_front].copy_from_slice(&front[..from_front]);
+    buffer[from_front..count].copy_from_slice(&back[..count - from_front]);
+    queue.drain(..count);
+    count
+}
+
+/// Adapts a `FrameReader` to rodio's `Source`, reading one block per call into the decoder
+pub struct FrameSource<R> {
+    reader: R,
+    block: Vec<f32>,
+    position: usize,
+    len: usize,
+}
+
+impl<R: FrameReader> FrameSource<R> {
+    pub fn new(reader: R) -> Self {
+        let block = vec![0.0; BLOCK_FRAMES * reader.channels().max(1) as usize];
+        Self { reader, block, position: 0, len: 0 }
+    }
+
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+}
+
+impl<R: FrameReader> Iterator for FrameSource<R> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position == self.len {
+            let frames = self.reader.read_frames(&mut self.block);
+            self.len = frames * self.reader.channels().max(1) as usize;
+            self.position = 0;
+        }
+
+        let sample = self.block[..self.len].get(self.position).copied()?;
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl<R: FrameReader> Source for FrameSource<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.reader.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.reader.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.reader.total_duration()
+    }
+}
+
+impl<R: FrameReader> AudioSource for FrameSource<R> {
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        self.reader.seek(pos)?;
+        self.position = 0;
+        self.len = 0;
+        Ok(())
+    }
+
+    fn info(&self) -> DecoderInfo {
+        self.reader.info()
+    }
+
+    /// Hands out the whole frames left of the current block, then reads straight into
+    /// `buffer`. The rest of a frame that `next` stopped partway through is dropped, as
+    /// copying it would shift every following frame across the channels.
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.reader.channels().max(1) as usize;
+        self.position = self.position.next_multiple_of(channels).min(self.len);
+        let buffered = (self.len - self.position).min(buffer.len() / channels * channels);
+        buffer[..buffered].copy_from_slice(&self.block[self.position..self.position + buffered]);
+        self.position += buffered;
+
+        buffered / channels + self.reader.read_frames(&mut buffer[buffered..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo frames (n, -n) for n in 1..=frames
+    struct Ramp {
+        next: usize,
+        frames: usize,
+    }
+
+    impl FrameReader for Ramp {
+        fn channels(&self) -> u16 {
+            2
+        }
+
+        fn sample_rate(&self) -> u32 {
+            1000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            None
+        }
+
+        fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+            let mut written = 0;
+            for frame in buffer.chunks_exact_mut(2) {
+                if self.next == self.frames {
+                    break;
+                }
+                self.next += 1;
+                frame.copy_from_slice(&[self.next as f32, -(self.next as f32)]);
+                written += 1;
+            }
+            written
+        }
+
+        fn seek(&mut self, pos: Duration) -> Result<()> {
+            self.next = pos.as_millis() as usize;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_drain_samples_across_wrap() {
+        let mut queue: VecDeque<f32> = VecDeque::with_capacity(4);
+        queue.extend([0.0, 0.0, 1.0, 2.0]);
+        queue.drain(..2);
+        queue.extend([3.0, 4.0]);
+
+        let mut buffer = [0.0; 3];
+        assert_eq!(drain_samples(&mut queue, &mut buffer), 3);
+        assert_eq!(buffer, [1.0, 2.0, 3.0]);
+        assert_eq!(queue, [4.0]);
+    }
+
+    #[test]
+    fn test_source_matches_blocks() {
+        let samples: Vec<f32> = FrameSource::new(Ramp { next: 0, frames: 3000 }).collect();
+        assert_eq!(samples.len(), 6000);
+        assert_eq!(&samples[2046..2050], &[1024.0, -1024.0, 1025.0, -1025.0]);
+
+        let mut source = FrameSource::new(Ramp { next: 0, frames: 3000 });
+        source.next();
+        source.next();
+        let mut buffer = [0.0; 6];
+        assert_eq!(source.read_frames(&mut buffer), 3);
+        assert_eq!(buffer, [2.0, -2.0, 3.0, -3.0, 4.0, -4.0]);
+
+        // Stopped halfway through a frame: the next whole one follows, channels in place
+        source.next();
+        assert_eq!(source.read_frames(&mut buffer), 3);
+        assert_eq!(buffer, [6.0, -6.0, 7.0, -7.0, 8.0, -8.0]);
+
+        source.seek(Duration::from_millis(2999)).unwrap();
+        assert_eq!(source.collect::<Vec<_>>(), vec![3000.0, -3000.0]);
+    }
+}
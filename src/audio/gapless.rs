@@ -0,0 +1,143 @@
+//! Encoder delay and padding trimming for gapless playback
+//!
+//! MP3 LAME/Xing headers are already honoured by Symphonia (rodio opens it in gapless
+//! mode) and Opus pre-skip is applied by `DecoderOpus`, so this module covers the
+//! iTunes `iTunSMPB` tag written by AAC encoders into MP4 files.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use super::decoders::mp4;
+
+/// Number of priming and padding frames the encoder added around the real audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderDelay {
+    pub delay: u64,
+    pub padding: u64,
+    /// Length of the audio without delay and padding, when the encoder recorded it
+    pub valid_frames: Option<u64>,
+}
+
+impl EncoderDelay {
+    /// Parses an `iTunSMPB` value such as
+    /// `" 00000000 00000840 000001CA 00000000003F1BF6 ..."`
+    pub fn from_itunsmpb(value: &str) -> Option<Self> {
+        let fields: Vec<u64> = value.split_whitespace()
+            .take(4)
+            .map(|field| u64::from_str_radix(field, 16).ok())
+            .collect::<Option<_>>()?;
+        if fields.len() < 3 {
+            return None;
+        }
+
+        Some(Self {
+            delay: fields[1],
+            padding: fields[2],
+            valid_frames: fields.get(3).copied().filter(|&frames| frames > 0),
+        })
+    }
+
+    /// Reads the `iTunSMPB` tag from an MP4 file's `moov/udta/meta/ilst` box
+    pub fn read_mp4<R: Read + Seek>(reader: &mut R) -> Option<Self> {
+        let moov = mp4::read_moov(reader).ok()?;
+        let value = mp4::read_freeform_tag(&moov, "iTunSMPB")?;
+        let mut info = Self::from_itunsmpb(&value)?;
+
+        // Older encoders leave the length empty: derive it from the track duration
+        if info.valid_frames.is_none() {
+            let file_len = reader.seek(SeekFrom::End(0)).ok()?;
+            let track = mp4::audio_track(&moov, file_len).ok()?;
+            let total_frames = track.duration * track.sample_rate as u64 / track.timescale.max(1) as u64;
+            info.valid_frames = total_frames.checked_sub(info.delay + info.padding);
+        }
+
+        Some(info)
+    }
+}
+
+/// Drops the encoder delay at the start of a stream and the padding at its end
+#[derive(Debug, Clone)]
+pub struct GaplessTrim {
+    info: EncoderDelay,
+    channels: u64,
+    samples_to_skip: u64,
+    samples_remaining: Option<u64>,
+}
+
+impl GaplessTrim {
+    pub fn new(info: EncoderDelay, channels: u16) -> Self {
+        let channels = channels.max(1) as u64;
+        Self {
+            info,
+            channels,
+            samples_to_skip: info.delay * channels,
+            samples_remaining: info.valid_frames.map(|valid| valid * channels),
+        }
+    }
+
+    /// Number of untrimmed frames to seek past to reach `frame` of the trimmed stream
+    pub fn raw_frame(&self, frame: u64) -> u64 {
+        frame + self.info.delay
+    }
+
+    /// Updates the trim window after the underlying source was moved to `raw_frame(frame)`
+    pub fn reposition(&mut self, frame: u64) {
+        self.samples_to_skip = 0;
+        self.samples_remaining = self.info.valid_frames
+            .map(|valid| valid.saturating_sub(frame) * self.channels);
+    }
+
+    /// Pulls the next sample from `source`, skipping the delay and stopping before the padding
+    pub fn next_sample<I: Iterator>(&mut self, source: &mut I) -> Option<I::Item> {
+        while self.samples_to_skip > 0 {
+            source.next()?;
+            self.samples_to_skip -= 1;
+        }
+
+        if let Some(remaining) = self.samples_remaining.as_mut() {
+            if *remaining == 0 {
+                return None;
+            }
+            *remaining -= 1;
+        }
+
+        source.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_itunsmpb_parsing() {
+        let value = " 00000000 00000840 000001CA 00000000003F1BF6 00000000 00000000 00000000 00000000";
+        let info = EncoderDelay::from_itunsmpb(value).unwrap();
+        assert_eq!(info.delay, 2112);
+        assert_eq!(info.padding, 458);
+        assert_eq!(info.valid_frames, Some(0x3F1BF6));
+
+        assert_eq!(EncoderDelay::from_itunsmpb("garbage"), None);
+    }
+
+    #[test]
+    fn test_trim_skips_delay_and_padding() {
+        let info = EncoderDelay { delay: 2, padding: 1, valid_frames: Some(3) };
+        let mut trim = GaplessTrim::new(info, 2);
+        let mut source = 0..12;
+
+        let samples: Vec<_> = std::iter::from_fn(|| trim.next_sample(&mut source)).collect();
+        assert_eq!(samples, vec![4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_trim_after_seek() {
+        let info = EncoderDelay { delay: 2, padding: 1, valid_frames: Some(3) };
+        let mut trim = GaplessTrim::new(info, 1);
+        assert_eq!(trim.raw_frame(1), 3);
+
+        trim.reposition(1);
+        let mut source = 3..10;
+        let samples: Vec<_> = std::iter::from_fn(|| trim.next_sample(&mut source)).collect();
+        assert_eq!(samples, vec![3, 4]);
+    }
+}
@@ -0,0 +1,245 @@
+//! Stream information reported by the decoders themselves, independent of tag readers
+
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use rodio::Source;
+
+use super::chapters::{self, Chapter};
+use super::frames::FrameReader;
+
+/// An embedded picture, such as the cover art
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    /// Picture type as defined by ID3v2 APIC (3 is the front cover)
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    /// Decodes a `METADATA_BLOCK_PICTURE` comment: a base64-encoded FLAC picture block
+    pub fn from_metadata_block_picture(value: &str) -> Option<Self> {
+        Self::from_flac_block(&base64_decode(value)?)
+    }
+
+    /// Parses the body of a FLAC `PICTURE` metadata block
+    pub fn from_flac_block(block: &[u8]) -> Option<Self> {
+        let mut reader = BlockReader(block);
+
+        let picture_type = reader.u32()?;
+        let mime_len = reader.u32()? as usize;
+        let mime_type = String::from_utf8_lossy(reader.bytes(mime_len)?).into_owned();
+        let description_len = reader.u32()? as usize;
+        let description = String::from_utf8_lossy(reader.bytes(description_len)?).into_owned();
+        // Width, height, colour depth and palette size
+        reader.bytes(16)?;
+        let data_len = reader.u32()? as usize;
+        let data = reader.bytes(data_len)?.to_vec();
+
+        Some(Self { picture_type, mime_type, description, data })
+    }
+}
+
+/// What a decoder knows about its stream
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecoderInfo {
+    pub duration: Option<Duration>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Bit depth of the source, if the format has one
+    pub bits_per_sample: Option<u32>,
+    /// Comments as (upper-case key, value), in the order they appear in the file
+    pub tags: Vec<(String, String)>,
+    /// Track gain in dB relative to the EBU R128 reference level, from `R128_TRACK_GAIN`
+    pub r128_track_gain: Option<f32>,
+    pub pictures: Vec<Picture>,
+    /// Chapter marks, ordered by start
+    pub chapters: Vec<Chapter>,
+}
+
+impl DecoderInfo {
+    /// The basic information every `Source` provides
+    pub fn from_source<S: Source + ?Sized>(source: &S) -> Self
+    where
+        S::Item: rodio::Sample,
+    {
+        Self {
+            duration: source.total_duration(),
+            sample_rate: source.sample_rate(),
+            channels: source.channels(),
+            ..Self::default()
+        }
+    }
+
+    /// The basic information every `FrameReader` provides
+    pub fn from_reader<R: FrameReader + ?Sized>(reader: &R) -> Self {
+        Self {
+            duration: reader.total_duration(),
+            sample_rate: reader.sample_rate(),
+            channels: reader.channels(),
+            bits_per_sample: reader.bits_per_sample(),
+            ..Self::default()
+        }
+    }
+
+    /// Bit depth and sample rate for display, e.g. "24-bit/96kHz", or just "48kHz" for
+    /// formats without a bit depth
+    pub fn resolution(&self) -> String {
+        let rate = format!("{}kHz", self.sample_rate as f64 / 1000.0);
+        match self.bits_per_sample {
+            Some(bits) => format!("{}-bit/{}", bits, rate),
+            None => rate,
+        }
+    }
+
+    /// Adds Vorbis-style comments, picking out the gain, pictures and chapters
+    pub fn with_comments(mut self, comments: Vec<(String, String)>) -> Self {
+        self.chapters = chapters::from_vorbis_comments(&comments);
+        for (key, value) in comments {
+            let key = key.to_uppercase();
+            match key.as_str() {
+                // Q7.8 fixed point dB
+                "R128_TRACK_GAIN" => {
+                    self.r128_track_gain = value.trim().parse::<i16>().ok()
+                        .map(|gain| gain as f32 / 256.0);
+                }
+                "METADATA_BLOCK_PICTURE" => {
+                    self.pictures.extend(Picture::from_metadata_block_picture(&value));
+                    continue;
+                }
+                _ => {}
+            }
+            self.tags.push((key, value));
+        }
+        self
+    }
+
+    /// The first value of a tag, looked up case-insensitively
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parses a Vorbis comment block (vendor string, then `KEY=value` comments, all
+/// length-prefixed) into (upper-case key, value) comments
+pub fn parse_vorbis_comments(mut data: &[u8]) -> Result<Vec<(String, String)>> {
+    fn read_u32(rest: &mut &[u8]) -> Result<usize> {
+        let bytes = rest.get(..4).ok_or_else(|| anyhow!("Truncated Vorbis comments"))?;
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        *rest = &rest[4..];
+        Ok(value)
+    }
+
+    let vendor_len = read_u32(&mut data)?;
+    data = data.get(vendor_len..).ok_or_else(|| anyhow!("Truncated Vorbis comments"))?;
+    let count = read_u32(&mut data)?;
+
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = read_u32(&mut data)?;
+        let comment = data.get(..len).ok_or_else(|| anyhow!("Truncated Vorbis comments"))?;
+        data = &data[len..];
+        if let Some((key, value)) = String::from_utf8_lossy(comment).split_once('=') {
+            comments.push((key.to_uppercase(), value.to_string()));
+        }
+    }
+    Ok(comments)
+}
+
+/// Reads the big-endian fields of a FLAC metadata block
+struct BlockReader<'a>(&'a [u8]);
+
+impl<'a> BlockReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.0.len() {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// Decodes standard base64, ignoring whitespace; None on invalid input
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(input.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+
+    for c in input.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_decode() {
+        assert_eq!(base64_decode("aGVsbG8=").as_deref(), Some(&b"hello"[..]));
+        assert_eq!(base64_decode("aGVs\nbG8h").as_deref(), Some(&b"hello!"[..]));
+        assert_eq!(base64_decode("a$b"), None);
+    }
+
+    #[test]
+    fn test_resolution() {
+        let mut info = DecoderInfo { sample_rate: 96000, bits_per_sample: Some(24), ..DecoderInfo::default() };
+        assert_eq!(info.resolution(), "24-bit/96kHz");
+        info.sample_rate = 44100;
+        info.bits_per_sample = Some(16);
+        assert_eq!(info.resolution(), "16-bit/44.1kHz");
+        info.bits_per_sample = None;
+        assert_eq!(info.resolution(), "44.1kHz");
+    }
+
+    #[test]
+    fn test_comments() {
+        // A front cover with an empty description and the 3-byte image "abc"
+        let mut block = Vec::new();
+        block.extend(3u32.to_be_bytes());
+        block.extend(9u32.to_be_bytes());
+        block.extend(b"image/png");
+        block.extend(0u32.to_be_bytes());
+        block.extend([0; 16]);
+        block.extend(3u32.to_be_bytes());
+        block.extend(b"abc");
+        let encoded = "AAAAAwAAAAlpbWFnZS9wbmcAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAANhYmM=";
+        assert_eq!(base64_decode(encoded), Some(block));
+
+        let info = DecoderInfo::default().with_comments(vec![
+            ("title".to_string(), "Song".to_string()),
+            ("R128_TRACK_GAIN".to_string(), "-512".to_string()),
+            ("METADATA_BLOCK_PICTURE".to_string(), encoded.to_string()),
+        ]);
+        assert_eq!(info.tag("TITLE"), Some("Song"));
+        assert_eq!(info.r128_track_gain, Some(-2.0));
+        assert_eq!(info.pictures.len(), 1);
+        assert_eq!(info.pictures[0].mime_type, "image/png");
+        assert_eq!(info.pictures[0].data, b"abc");
+        assert_eq!(info.tag("METADATA_BLOCK_PICTURE"), None);
+    }
+}
@@ -0,0 +1,410 @@
+//! Mixes the playing track with the next one, for gapless joins and crossfades
+//!
+//! The mixer is appended to the sink once and never ends; the player hands it tracks
+//! through a `MixerHandle`. Its output format follows the playing track, and may only
+//! change on block boundaries so rodio picks the change up.
+
+use std::{
+    f32::consts::FRAC_PI_2,
+    mem,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    time::Duration,
+};
+use rodio::{source::UniformSourceIterator, Source};
+
+/// A decoded track as handed to the mixer
+pub type TrackSource = Box<dyn Source<Item = f32> + Send>;
+
+// Number of frames between points where the output format may change
+const BLOCK_FRAMES: usize = 2048;
+
+struct Track {
+    source: TrackSource,
+    length: Option<Duration>,
+    /// Frames left before the end, in the mixer's output format, when the length is known
+    remaining_frames: Option<u64>,
+}
+
+impl Track {
+    fn new(source: TrackSource, length: Option<Duration>) -> Self {
+        let length = length.or_else(|| source.total_duration());
+        Self {
+            source,
+            length,
+            remaining_frames: None,
+        }
+    }
+}
+
+struct Outgoing {
+    track: Track,
+    fade_position: u64,
+    fade_length: u64,
+}
+
+enum Command {
+    Play(Track),
+    Replace(Track),
+    Queue(Track, Duration),
+    Crossfade(Duration),
+    Dequeue,
+    Clear,
+}
+
+struct Shared {
+    commands: Mutex<Vec<Command>>,
+    has_commands: AtomicBool,
+    active: AtomicBool,
+}
+
+/// Controls a `TrackMixer` from the player thread
+#[derive(Clone)]
+pub struct MixerHandle {
+    shared: Arc<Shared>,
+}
+
+impl MixerHandle {
+    /// Stops whatever is playing, including the queued track, and starts `source`
+    pub fn play(&self, source: TrackSource, length: Option<Duration>) {
+        self.send(Command::Play(Track::new(source, length)));
+    }
+
+    /// Swaps the playing track for `source` (e.g. after a seek), keeping the queued track.
+    /// `remaining` is the time left from where `source` starts.
+    pub fn replace(&self, source: TrackSource, remaining: Option<Duration>) {
+        self.send(Command::Replace(Track { source, length: remaining, remaining_frames: None }));
+    }
+
+    /// Queues `source` after the playing track, crossfading over `crossfade` (zero for gapless)
+    pub fn queue(&self, source: TrackSource, length: Option<Duration>, crossfade: Duration) {
+        self.send(Command::Queue(Track::new(source, length), crossfade));
+    }
+
+    /// Drops the queued track, so playback stops after the current one
+    pub fn dequeue(&self) {
+        self.send(Command::Dequeue);
+    }
+
+    /// Changes the crossfade into the queued track, if one is queued
+    pub fn set_queued_crossfade(&self, crossfade: Duration) {
+        self.send(Command::Crossfade(crossfade));
+    }
+
+    /// Stops playback and drops every track
+    pub fn clear(&self) {
+        self.send(Command::Clear);
+    }
+
+    /// Returns true while a track is playing or about to start
+    pub fn is_active(&self) -> bool {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    fn send(&self, command: Command) {
+        let mut commands = self.shared.commands.lock().unwrap();
+        let active = match &command {
+            Command::Play(_) | Command::Replace(_) => true,
+            Command::Clear => false,
+            Command::Queue(..) | Command::Crossfade(_) | Command::Dequeue => self.shared.active.load(Ordering::SeqCst),
+        };
+        commands.push(command);
+        self.shared.has_commands.store(true, Ordering::SeqCst);
+        self.shared.active.store(active, Ordering::SeqCst);
+    }
+}
+
+/// Source that plays the current track and joins or crossfades it into the queued one
+pub struct TrackMixer {
+    shared: Arc<Shared>,
+    channels: u16,
+    sample_rate: u32,
+    block_remaining: usize,
+    channel_index: u16,
+    current: Option<Track>,
+    /// Track waiting for the next block boundary to switch the output to its format
+    pending_start: Option<Track>,
+    queued: Option<(Track, Duration)>,
+    outgoing: Option<Outgoing>,
+}
+
+impl TrackMixer {
+    pub fn new() -> (Self, MixerHandle) {
+        let shared = Arc::new(Shared {
+            commands: Mutex::new(Vec::new()),
+            has_commands: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+        });
+
+        let mixer = Self {
+            shared: Arc::clone(&shared),
+            channels: 2,
+            sample_rate: 44100,
+            block_remaining: BLOCK_FRAMES * 2,
+            channel_index: 0,
+            current: None,
+            pending_start: None,
+            queued: None,
+            outgoing: None,
+        };
+
+        (mixer, MixerHandle { shared })
+    }
+
+    fn matches_format(&self, source: &TrackSource) -> bool {
+        source.channels() == self.channels && source.sample_rate() == self.sample_rate
+    }
+
+    fn frames_for(&self, length: Option<Duration>) -> Option<u64> {
+        length.map(|length| (length.as_secs_f64() * self.sample_rate as f64) as u64)
+    }
+
+    /// Makes `track` the playing track, now if it matches the output format,
+    /// otherwise at the next block boundary
+    fn start(&mut self, mut track: Track) {
+        self.current = None;
+        if self.matches_format(&track.source) {
+            track.remaining_frames = self.frames_for(track.length);
+            self.current = Some(track);
+        } else {
+            self.pending_start = Some(track);
+        }
+    }
+
+    fn apply_commands(&mut self) {
+        let commands = {
+            let mut commands = self.shared.commands.lock().unwrap();
+            self.shared.has_commands.store(false, Ordering::SeqCst);
+            mem::take(&mut *commands)
+        };
+
+        for command in commands {
+            match command {
+                Command::Play(track) => {
+                    self.outgoing = None;
+                    self.queued = None;
+                    self.pending_start = None;
+                    self.start(track);
+                }
+                Command::Replace(track) => {
+                    self.outgoing = None;
+                    self.pending_start = None;
+                    self.start(track);
+                }
+                Command::Queue(track, crossfade) => self.queued = Some((track, crossfade)),
+                Command::Dequeue => self.queued = None,
+                Command::Crossfade(crossfade) => {
+                    if let Some((_, queued_crossfade)) = self.queued.as_mut() {
+                        *queued_crossfade = crossfade;
+                    }
+                }
+                Command::Clear => {
+                    self.current = None;
+                    self.outgoing = None;
+                    self.queued = None;
+                    self.pending_start = None;
+                }
+            }
+        }
+    }
+
+    fn start_block(&mut self) {
+        if let Some(mut track) = self.pending_start.take() {
+            self.channels = track.source.channels().max(1);
+            self.sample_rate = track.source.sample_rate();
+            track.remaining_frames = self.frames_for(track.length);
+            self.current = Some(track);
+        }
+        self.block_remaining = BLOCK_FRAMES * self.channels as usize;
+    }
+
+    /// Moves the current track out and fades the queued one in over it
+    fn begin_crossfade(&mut self, fade_frames: u64) {
+        let (Some(outgoing), Some((incoming, _))) = (self.current.take(), self.queued.take()) else {
+            return;
+        };
+
+        let source = if self.matches_format(&incoming.source) {
+            incoming.source
+        } else {
+            Box::new(UniformSourceIterator::new(incoming.source, self.channels, self.sample_rate))
+        };
+
+        self.outgoing = Some(Outgoing { track: outgoing, fade_position: 0, fade_length: fade_frames.max(1) });
+        self.current = Some(Track {
+            source,
+            length: incoming.length,
+            remaining_frames: self.frames_for(incoming.length),
+        });
+    }
+
+    /// Called when the current track ran out: joins the queued track with no gap
+    fn end_current(&mut self) {
+        self.current = None;
+        if let Some((track, _)) = self.queued.take() {
+            self.start(track);
+            return;
+        }
+
+        if self.outgoing.is_none() && self.pending_start.is_none() {
+            // Commands sent meanwhile decide the state themselves
+            let commands = self.shared.commands.lock().unwrap();
+            if commands.is_empty() {
+                self.shared.active.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn on_frame_start(&mut self) {
+        if let Some(outgoing) = self.outgoing.as_mut() {
+            outgoing.fade_position += 1;
+            if outgoing.fade_position >= outgoing.fade_length {
+                self.outgoing = None;
+            }
+        }
+
+        let Some(current) = self.current.as_mut() else { return };
+        if let Some(remaining) = current.remaining_frames.as_mut() {
+            *remaining = remaining.saturating_sub(1);
+        }
+
+        let crossfade = match &self.queued {
+            Some((_, crossfade)) if !crossfade.is_zero() && self.outgoing.is_none() => *crossfade,
+            _ => return,
+        };
+        let fade_frames = (crossfade.as_secs_f64() * self.sample_rate as f64) as u64;
+        if let Some(remaining) = self.current.as_ref().and_then(|t| t.remaining_frames) {
+            if remaining <= fade_frames {
+                self.begin_crossfade(remaining);
+            }
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        if self.channel_index == 0 {
+            self.on_frame_start();
+        }
+
+        let mut sample = match self.current.as_mut() {
+            Some(track) => match track.source.next() {
+                Some(sample) => sample,
+                None if self.channel_index == 0 => {
+                    self.end_current();
+                    // A gapless join in the same format continues right away
+                    self.current.as_mut()
+                        .and_then(|track| track.source.next())
+                        .unwrap_or(0.0)
+                }
+                None => 0.0,
+            },
+            None => 0.0,
+        };
+
+        if let Some(outgoing) = self.outgoing.as_mut() {
+            let progress = outgoing.fade_position as f32 / outgoing.fade_length as f32;
+            let (gain_out, gain_in) = equal_power_gains(progress);
+            let faded = outgoing.track.source.next().unwrap_or(0.0);
+            sample = sample * gain_in + faded * gain_out;
+        }
+
+        self.channel_index = (self.channel_index + 1) % self.channels;
+        sample
+    }
+}
+
+/// Gains for the outgoing and incoming tracks at `progress` (0..1) through a crossfade;
+/// their squares always sum to one, so the perceived loudness stays constant
+pub fn equal_power_gains(progress: f32) -> (f32, f32) {
+    let angle = progress.clamp(0.0, 1.0) * FRAC_PI_2;
+    (angle.cos(), angle.sin())
+}
+
+impl Iterator for TrackMixer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.shared.has_commands.load(Ordering::Relaxed) && self.channel_index == 0 {
+            self.apply_commands();
+        }
+
+        let sample = self.next_sample();
+
+        self.block_remaining -= 1;
+        if self.block_remaining == 0 {
+            // Switch formats before rodio asks for the next frame's layout
+            self.start_block();
+        }
+
+        Some(sample)
+    }
+}
+
+impl Source for TrackMixer {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.block_remaining)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn track(channels: u16, sample_rate: u32, samples: Vec<f32>) -> TrackSource {
+        Box::new(SamplesBuffer::new(channels, sample_rate, samples))
+    }
+
+    #[test]
+    fn test_gapless_join_in_same_format() {
+        let (mut mixer, handle) = TrackMixer::new();
+        handle.play(track(2, 44100, vec![1.0; 4]), None);
+        handle.queue(track(2, 44100, vec![2.0; 4]), None, Duration::ZERO);
+
+        let samples: Vec<f32> = mixer.by_ref().take(10).collect();
+        assert_eq!(samples, vec![1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0, 0.0, 0.0]);
+        assert!(!handle.is_active());
+    }
+
+    #[test]
+    fn test_format_change_waits_for_block_boundary() {
+        let (mut mixer, handle) = TrackMixer::new();
+        handle.play(track(1, 48000, vec![1.0; 4]), None);
+
+        let silence: Vec<f32> = mixer.by_ref().take(BLOCK_FRAMES * 2).collect();
+        assert!(silence.iter().all(|&s| s == 0.0));
+        assert_eq!(mixer.channels(), 1);
+        assert_eq!(mixer.sample_rate(), 48000);
+        assert_eq!(mixer.next(), Some(1.0));
+    }
+
+    #[test]
+    fn test_crossfade_mixes_both_tracks() {
+        let (mut mixer, handle) = TrackMixer::new();
+        let length = Duration::from_secs_f64(8.0 / 44100.0);
+        handle.play(track(2, 44100, vec![1.0; 16]), Some(length));
+        handle.queue(track(2, 44100, vec![1.0; 64]), None, Duration::from_secs(1));
+
+        let samples: Vec<f32> = mixer.by_ref().take(32).collect();
+        // Equal-power gains sum above 1.0 for correlated signals mid-fade
+        assert!(samples.iter().any(|&s| s > 1.0));
+        assert!(handle.is_active());
+    }
+
+    #[test]
+    fn test_equal_power_gains() {
+        let (out, inc) = equal_power_gains(0.5);
+        assert!((out * out + inc * inc - 1.0).abs() < 1e-6);
+        assert_eq!(equal_power_gains(0.0), (1.0, 0.0));
+    }
+}
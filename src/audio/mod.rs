@@ -1,8 +1,36 @@
 mod utils;
+mod buffered;
+mod chapters;
 mod decoder;
 mod decoders;
+mod frames;
+mod gapless;
+mod info;
+mod mixer;
+mod probe;
+mod range;
+mod registry;
+mod ring;
+mod volume;
+#[cfg(test)]
+mod test_utils;
 pub mod player;
+pub mod position;
 
 pub use utils::{TimeFormat, TimeUtils};
 pub use player::AudioPlayer;
+pub use position::{CountingSource, FrameCounter};
+pub use volume::Volume;
+pub use buffered::BufferedSource;
+pub use ring::BufferStats;
+pub use frames::{FrameReader, FrameSource};
+pub use info::{DecoderInfo, Picture};
+pub use chapters::{chapter_at, Chapter};
+pub use probe::{probe_file, AudioFormat, ProbeData};
+pub use range::{RangeSource, TrackRange};
+pub use registry::{
+    global_registry, AudioSource, BoxedSource, DecoderFactory, DecoderRegistry,
+    SCORE_CERTAIN, SCORE_EXTENSION, SCORE_FALLBACK, SCORE_SUPPORTED,
+};
+pub use decoder::load_audio_file;
 pub use super::audio::decoders::*;
\ No newline at end of file
@@ -1,35 +1,60 @@
 //! Module for managing audio playback, including play, pause, seek, and stop functionality
 
 
-use rodio::{OutputStream, Sink};
+use rodio::{OutputStream, Sink, Source};
 use anyhow::Result;
 use std::{
     path::{Path, PathBuf},
-    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
 };
 
-use super::decoder::AudioDecoder;
+use super::buffered::BufferedSource;
+use super::chapters::{chapter_at, Chapter};
+use super::decoder::SkipDuration;
+use super::info::DecoderInfo;
+use super::range::{RangeSource, TrackRange};
+use super::registry::AudioSource;
+use super::ring::BufferStats;
 use crate::display::console::DisplayThread;
 use super::utils::{TimeFormat, TimeUtils};
 use super::decoder::load_audio_file;
+use super::position::{CountingSource, FrameCounter};
+use super::mixer::{MixerHandle, TrackMixer};
+use super::volume::Volume;
 use std::io::{stdout, Write};
 
+/// Going back within this far into a chapter restarts it; any later goes to the one before
+const CHAPTER_RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
 /// Manages audio playback, including state and display
 pub struct AudioPlayer {
     _stream: OutputStream,
-    stream_handle: rodio::OutputStreamHandle,
     sink: Arc<Sink>,
+    mixer: MixerHandle,
+    crossfade: Duration,
+    queued_crossfade: bool,
+    volume: Arc<Volume>,
     is_playing: Arc<AtomicBool>,
     is_paused: Arc<AtomicBool>,
-    current_position: Arc<Mutex<u64>>,
+    /// Whether the display shows the time within the current chapter
+    chapter_time: Arc<AtomicBool>,
+    frame_counter: Arc<FrameCounter>,
     file_path: Option<PathBuf>,
+    /// The part of `file_path` being played, for tracks of a CUE sheet
+    track_range: Option<TrackRange>,
     total_duration: Option<Duration>,
     display_thread: Option<DisplayThread>,
-    playback_start: Arc<Mutex<Option<Instant>>>,
-    pause_start: Arc<Mutex<Option<Instant>>>,
-    total_pause_duration: Arc<Mutex<Duration>>,
     metadata_duration: Option<Duration>,
+    queued_path: Option<PathBuf>,
+    queued_range: Option<TrackRange>,
+    queued_duration: Option<Duration>,
+    buffer_stats: Option<BufferStats>,
+    queued_buffer_stats: Option<BufferStats>,
+    track_info: Option<DecoderInfo>,
+    queued_track_info: Option<DecoderInfo>,
+    current_track: u64,
+    display_enabled: bool,
 }
 
 impl AudioPlayer {
@@ -37,26 +62,41 @@ impl AudioPlayer {
     pub fn new() -> Result<Self> {
         let (_stream, stream_handle) = OutputStream::try_default()?;
         let sink = Sink::try_new(&stream_handle)?;
+        // The mixer stays on the sink for the player's lifetime and carries every track
+        let (track_mixer, mixer) = TrackMixer::new();
+        sink.append(track_mixer);
         Ok(Self { 
             _stream, 
-            stream_handle,
             sink: Arc::new(sink),
+            mixer,
+            crossfade: Duration::ZERO,
+            queued_crossfade: false,
+            volume: Arc::new(Volume::default()),
             is_playing: Arc::new(AtomicBool::new(false)),
             is_paused: Arc::new(AtomicBool::new(false)),
-            current_position: Arc::new(Mutex::new(0)),
+            chapter_time: Arc::new(AtomicBool::new(false)),
+            frame_counter: Arc::new(FrameCounter::new()),
             file_path: None,
+            track_range: None,
             metadata_duration: None,
             total_duration: None,
             display_thread: None,
-            playback_start: Arc::new(Mutex::new(None)),
-            pause_start: Arc::new(Mutex::new(None)),
-            total_pause_duration: Arc::new(Mutex::new(Duration::from_secs(0))),
+            queued_path: None,
+            queued_range: None,
+            queued_duration: None,
+            buffer_stats: None,
+            queued_buffer_stats: None,
+            track_info: None,
+            queued_track_info: None,
+            current_track: 0,
+            display_enabled: true,
         })
     }
 
-    /// Sets the metadata duration of the audiofile
-    pub fn set_metadata_duration(&mut self, duration_seconds: u64) {
-        self.metadata_duration = Some(Duration::from_secs(duration_seconds));
+    /// Sets the metadata duration of the audiofile, used when the decoder cannot tell.
+    /// Called for every track, so one without tags does not inherit the previous length.
+    pub fn set_metadata_duration(&mut self, duration: Option<Duration>) {
+        self.metadata_duration = duration;
         // Also set total_duration if it's not available from the decoder
         if self.total_duration.is_none() {
             self.total_duration = self.metadata_duration;
@@ -64,48 +104,174 @@ impl AudioPlayer {
     }
 
     pub fn play<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.play_range(path, None)
+    }
+
+    /// Plays `range` of the file as a track of its own, or the whole file when None
+    pub fn play_range<P: AsRef<Path>>(&mut self, path: P, range: Option<TrackRange>) -> Result<()> {
         // Stop any existing display thread
         if let Some(mut display_thread) = self.display_thread.take() {
             display_thread.stop();
         }
 
-        let source = load_audio_file(path.as_ref())?;
+        let mut source = open_track(path.as_ref(), range)?;
+        source.report_underruns(Arc::clone(&self.frame_counter));
         self.file_path = Some(path.as_ref().to_path_buf());
+        self.track_range = range;
+        self.buffer_stats = Some(source.stats());
+        self.track_info = Some(source.info());
 
-        // Try to get duration from decoder
-        self.total_duration = self.metadata_duration;
+        // The decoder's own duration is exact; tags are only a fallback
+        self.total_duration = source.total_duration().or(self.metadata_duration);
 
-        let new_sink = Sink::try_new(&self.stream_handle)?;
-        new_sink.append(source);
-        self.sink = Arc::new(new_sink);
+        self.mixer.play(
+            Box::new(CountingSource::new(source, Arc::clone(&self.frame_counter), Duration::ZERO)),
+            self.total_duration,
+        );
+        self.sink.play();
         
         // Reset state
         self.is_playing.store(true, Ordering::SeqCst);
         self.is_paused.store(false, Ordering::SeqCst);
-        *self.current_position.lock().unwrap() = 0;
-        *self.pause_start.lock().unwrap() = None;
-        *self.total_pause_duration.lock().unwrap() = Duration::from_secs(0);
-        *self.playback_start.lock().unwrap() = Some(Instant::now());
+        self.queued_path = None;
+        self.current_track = self.frame_counter.track();
+
+        self.start_display();
+
+        Ok(())
+    }
+
+    /// Opens the next track ahead of time and queues it behind the current one, so its
+    /// samples follow with no gap. With `crossfade` set, the configured crossfade is used
+    /// instead; `length` helps the mixer find where to start it.
+    pub fn queue<P: AsRef<Path>>(&mut self, path: P, length: Option<Duration>, crossfade: bool) -> Result<()> {
+        self.queue_range(path, None, length, crossfade)
+    }
+
+    /// Queues `range` of the file as the next track, like `queue`
+    pub fn queue_range<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        range: Option<TrackRange>,
+        length: Option<Duration>,
+        crossfade: bool,
+    ) -> Result<()> {
+        let mut source = open_track(path.as_ref(), range)?;
+        source.report_underruns(Arc::clone(&self.frame_counter));
+        let decoder_duration = source.total_duration();
+        let length = decoder_duration.or(length);
+        let source_stats = source.stats();
+        let source_info = source.info();
+        let fade = if crossfade { self.crossfade } else { Duration::ZERO };
+        self.mixer.queue(
+            Box::new(CountingSource::queued(source, Arc::clone(&self.frame_counter))),
+            length,
+            fade,
+        );
+        self.queued_path = Some(path.as_ref().to_path_buf());
+        self.queued_range = range;
+        self.queued_duration = decoder_duration;
+        self.queued_buffer_stats = Some(source_stats);
+        self.queued_track_info = Some(source_info);
+        self.queued_crossfade = crossfade;
+        Ok(())
+    }
+
+    /// Drops the queued track, e.g. when the playlist no longer continues with it
+    pub fn clear_queued(&mut self) {
+        self.queued_duration = None;
+        self.queued_buffer_stats = None;
+        self.queued_track_info = None;
+        self.queued_range = None;
+        if self.queued_path.take().is_some() {
+            self.mixer.dequeue();
+        }
+    }
+
+    /// Sets the crossfade between consecutive tracks, zero for a gapless join
+    pub fn set_crossfade(&mut self, crossfade: Duration) {
+        self.crossfade = crossfade;
+        if self.queued_crossfade {
+            self.mixer.set_queued_crossfade(crossfade);
+        }
+    }
+
+    pub fn crossfade(&self) -> Duration {
+        self.crossfade
+    }
+
+    /// Sets the volume level in percent (0-100); the sink gain follows a dB curve
+    pub fn set_volume(&mut self, level: u8) {
+        self.volume.set_level(level);
+        self.sink.set_volume(self.volume.gain());
+    }
+
+    /// Returns the volume level in percent, regardless of mute
+    pub fn volume(&self) -> u8 {
+        self.volume.level()
+    }
+
+    /// Mutes or unmutes playback, keeping the level; returns whether it is now muted
+    pub fn toggle_mute(&mut self) -> bool {
+        let muted = self.volume.toggle_mute();
+        self.sink.set_volume(self.volume.gain());
+        muted
+    }
+
+    /// Returns the queued track's path once the sink has started playing it.
+    ///
+    /// The display is stopped so the caller can print the new track's information,
+    /// set its metadata duration, and then call `start_display`.
+    pub fn poll_track_change(&mut self) -> Option<PathBuf> {
+        let track = self.frame_counter.track();
+        if track == self.current_track {
+            return None;
+        }
+        self.current_track = track;
+
+        let path = self.queued_path.take()?;
+        if let Some(mut display_thread) = self.display_thread.take() {
+            display_thread.stop();
+        }
+        self.file_path = Some(path.clone());
+        self.track_range = self.queued_range.take();
+        self.metadata_duration = None;
+        self.total_duration = self.queued_duration.take();
+        self.buffer_stats = self.queued_buffer_stats.take();
+        self.track_info = self.queued_track_info.take();
+        Some(path)
+    }
+
+    /// Turns the progress display on or off, e.g. when running without a UI
+    pub fn set_display_enabled(&mut self, enabled: bool) {
+        self.display_enabled = enabled;
+    }
+
+    /// Starts (or restarts) the progress display for the current track
+    pub fn start_display(&mut self) {
+        if let Some(mut display_thread) = self.display_thread.take() {
+            display_thread.stop();
+        }
+        if !self.display_enabled {
+            return;
+        }
 
-        // Create and start new display thread
         self.display_thread = Some(DisplayThread::new(
             Arc::clone(&self.is_playing),
             Arc::clone(&self.is_paused),
-            Arc::clone(&self.current_position),
+            Arc::clone(&self.frame_counter),
+            Arc::clone(&self.volume),
             self.total_duration,
-            Arc::clone(&self.playback_start),
-            Arc::clone(&self.pause_start),
-            Arc::clone(&self.total_pause_duration),
+            self.chapters().to_vec(),
+            Arc::clone(&self.chapter_time),
         ));
-
-        Ok(())
     }
 
-    fn create_decoder(&self) -> Result<AudioDecoder, String> {
+    fn create_decoder(&self) -> Result<BufferedSource, String> {
         let path = self.file_path.as_ref()
             .ok_or_else(|| "No file path set".to_string())?;
 
-        load_audio_file(path)
+        open_track(path, self.track_range)
             .map_err(|e| format!("Failed to create decoder: {}", e))
     }
 
@@ -118,27 +284,33 @@ impl AudioPlayer {
             }
         }
 
-        // Create decoder and skip to position
-        let decoder = self.create_decoder()
+        // Create decoder and seek to position
+        let mut decoder = self.create_decoder()
             .map_err(|e| format!("Failed to create decoder: {}", e))?;
-        let skip_duration = Duration::from_millis(position_ms);
-        let skipped_source = decoder.skip_duration(skip_duration);
+        let seek_position = Duration::from_millis(position_ms);
 
-        // Create new sink and play
-        let new_sink = Sink::try_new(&self.stream_handle)
-            .map_err(|e| format!("Failed to create sink: {}", e))?;
-        
-        new_sink.append(skipped_source);
-        
-        // Stop old sink and replace with new one
-        self.sink.stop();
-        self.sink = Arc::new(new_sink);
-        
-        // Reset all timing-related state
-        *self.playback_start.lock().unwrap() = Some(Instant::now() - Duration::from_millis(position_ms));
-        *self.pause_start.lock().unwrap() = None;
-        *self.total_pause_duration.lock().unwrap() = Duration::from_secs(0);
-        *self.current_position.lock().unwrap() = position_ms;
+        // Swap the playing track for the repositioned one; the queued track stays in place
+        let remaining = self.total_duration.map(|total| total.saturating_sub(seek_position));
+        if decoder.seek(seek_position).is_ok() {
+            decoder.report_underruns(Arc::clone(&self.frame_counter));
+            self.buffer_stats = Some(decoder.stats());
+            self.mixer.replace(
+                Box::new(CountingSource::new(decoder, Arc::clone(&self.frame_counter), seek_position)),
+                remaining,
+            );
+        } else {
+            // The backend cannot seek: decode and discard from a fresh decoder instead. Its
+            // underruns go unreported, as those while skipping would hold back the count.
+            let fresh_decoder = self.create_decoder()
+                .map_err(|e| format!("Failed to create decoder: {}", e))?;
+            self.buffer_stats = Some(fresh_decoder.stats());
+            let skipped_source = SkipDuration::new(fresh_decoder, seek_position);
+            self.mixer.replace(
+                Box::new(CountingSource::new(skipped_source, Arc::clone(&self.frame_counter), seek_position)),
+                remaining,
+            );
+        }
+        self.sink.play();
 
         self.is_playing.store(true, Ordering::SeqCst);
         self.is_paused.store(false, Ordering::SeqCst);
@@ -146,10 +318,65 @@ impl AudioPlayer {
         Ok(())
     }
 
+    /// Fill level and underruns of the current track's decode buffer
+    pub fn buffer_stats(&self) -> Option<&BufferStats> {
+        self.buffer_stats.as_ref()
+    }
+
+    /// Format of the current track as reported by its decoder, such as its bit depth
+    pub fn track_info(&self) -> Option<&DecoderInfo> {
+        self.track_info.as_ref()
+    }
+
+    /// Chapter marks of the current track, empty when it has none
+    pub fn chapters(&self) -> &[Chapter] {
+        self.track_info.as_ref().map_or(&[], |info| info.chapters.as_slice())
+    }
+
+    /// Index of the chapter playing now
+    pub fn current_chapter(&self) -> Option<usize> {
+        chapter_at(self.chapters(), self.position())
+    }
+
+    /// Jumps to the start of the next chapter, returning its index; None at the last one
+    pub fn next_chapter(&mut self) -> Result<Option<usize>, String> {
+        let next = self.current_chapter().map_or(0, |index| index + 1);
+        let Some(start) = self.chapters().get(next).map(|chapter| chapter.start) else {
+            return Ok(None);
+        };
+        self.seek_to(start)?;
+        Ok(Some(next))
+    }
+
+    /// Jumps to the start of the current chapter, or to the previous one when the current
+    /// one has only just started. Returns the index jumped to.
+    pub fn previous_chapter(&mut self) -> Result<Option<usize>, String> {
+        let Some(current) = self.current_chapter() else { return Ok(None) };
+        let into_chapter = self.position().saturating_sub(self.chapters()[current].start);
+        let target = if into_chapter < CHAPTER_RESTART_THRESHOLD { current.saturating_sub(1) } else { current };
+        let start = self.chapters()[target].start;
+        self.seek_to(start)?;
+        Ok(Some(target))
+    }
+
+    /// Switches the display between track and chapter time; returns whether chapter time is on
+    pub fn toggle_chapter_time(&self) -> bool {
+        !self.chapter_time.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    /// Returns the playback position, based on the frames the sink has pulled from the decoder
+    pub fn position(&self) -> Duration {
+        self.frame_counter.position()
+    }
+
+    /// Seeks to an absolute position in the current track
+    pub fn seek_to(&mut self, position: Duration) -> Result<(), String> {
+        self.play_from_position(position.as_millis() as u64)
+    }
+
     pub fn seek(&mut self, offset_seconds: i64) -> Result<(), String> {
-        // Get current position with mutex lock
         let new_pos = {
-            let current_pos = *self.current_position.lock().unwrap();
+            let current_pos = self.position().as_millis() as u64;
             if offset_seconds.is_negative() {
                 current_pos.saturating_sub(offset_seconds.unsigned_abs() * 1000)
             } else {
@@ -184,7 +411,9 @@ impl AudioPlayer {
     }
 
     pub fn stop(&mut self) {
-        self.sink.stop();
+        self.mixer.clear();
+        self.queued_path = None;
+        self.queued_range = None;
         self.is_playing.store(false, Ordering::SeqCst);
         self.is_paused.store(false, Ordering::SeqCst);
         
@@ -197,18 +426,10 @@ impl AudioPlayer {
     pub fn toggle_pause(&self) {
         if self.is_paused.load(Ordering::SeqCst) {
             // Resuming playback
-            if let Some(pause_time) = *self.pause_start.lock().unwrap() {
-                let pause_duration = Instant::now() - pause_time;
-                let mut total_pause = self.total_pause_duration.lock().unwrap();
-                *total_pause += pause_duration;
-            }
-            *self.pause_start.lock().unwrap() = None;
-            
             self.sink.play();
             self.is_paused.store(false, Ordering::SeqCst);
         } else {
             // Pausing playback
-            *self.pause_start.lock().unwrap() = Some(Instant::now());
             self.sink.pause();
             self.is_paused.store(true, Ordering::SeqCst);
         }
@@ -219,10 +440,10 @@ impl AudioPlayer {
             return true;
         }
         
-        let sink_active = !self.sink.empty() && self.sink.len() > 0;
+        let mixer_active = self.mixer.is_active();
         let currently_playing = self.is_playing.load(Ordering::SeqCst);
         
-        let playing = sink_active && currently_playing;
+        let playing = mixer_active && currently_playing;
         
         if !playing && currently_playing {
             self.is_playing.store(false, Ordering::SeqCst);
@@ -242,4 +463,13 @@ impl Drop for AudioPlayer {
             display_thread.stop();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Opens a file for playback, limited to `range` when only part of it is the track
+fn open_track(path: &Path, range: Option<TrackRange>) -> Result<BufferedSource> {
+    let source = load_audio_file(path)?;
+    match range {
+        Some(range) => BufferedSource::new(Box::new(RangeSource::new(source, range))),
+        None => BufferedSource::new(source),
+    }
+}
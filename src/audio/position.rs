@@ -0,0 +1,237 @@
+//! Sample-accurate playback position, derived from the frames pulled through the decoder
+
+use std::{
+    sync::{atomic::{AtomicU32, AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+use rodio::{Sample, Source};
+
+/// Shared frame counter between the audio thread and the player
+pub struct FrameCounter {
+    frames: AtomicU64,
+    sample_rate: AtomicU32,
+    track: AtomicU64,
+    /// Frames of underrun silence yet to pass through the counting source
+    silent_frames: AtomicU64,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        Self {
+            frames: AtomicU64::new(0),
+            sample_rate: AtomicU32::new(0),
+            track: AtomicU64::new(0),
+            silent_frames: AtomicU64::new(0),
+        }
+    }
+
+    /// Resets the counter so that it reports `position` for a source at `sample_rate`
+    pub fn reset(&self, position: Duration, sample_rate: u32) {
+        let frames = (position.as_secs_f64() * sample_rate as f64).round() as u64;
+        self.sample_rate.store(sample_rate, Ordering::SeqCst);
+        self.frames.store(frames, Ordering::SeqCst);
+        self.silent_frames.store(0, Ordering::SeqCst);
+    }
+
+    /// Returns how many queued tracks have started playing so far
+    pub fn track(&self) -> u64 {
+        self.track.load(Ordering::SeqCst)
+    }
+
+    fn start_queued_track(&self, sample_rate: u32) {
+        // Not `reset`: the new track's first frame may be underrun silence, already reported
+        self.sample_rate.store(sample_rate, Ordering::SeqCst);
+        self.frames.store(0, Ordering::SeqCst);
+        self.track.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Leaves the next frame out of the count: the silence played while the decoder
+    /// catches up is not part of the track
+    pub fn skip_silent_frame(&self) {
+        self.silent_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of frames played since the start of the track
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current playback position
+    pub fn position(&self) -> Duration {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        if sample_rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let frames = self.frames();
+        let secs = frames / sample_rate as u64;
+        let nanos = (frames % sample_rate as u64) * 1_000_000_000 / sample_rate as u64;
+        Duration::new(secs, nanos as u32)
+    }
+
+    /// Returns the current playback position in milliseconds
+    pub fn position_ms(&self) -> u64 {
+        self.position().as_millis() as u64
+    }
+
+    fn advance(&self) {
+        let silent = self.silent_frames.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |frames| frames.checked_sub(1));
+        if silent.is_err() {
+            self.frames.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wraps a source and counts every complete frame it yields
+pub struct CountingSource<S> {
+    source: S,
+    counter: Arc<FrameCounter>,
+    channels: u16,
+    samples_in_frame: u16,
+    queued: bool,
+}
+
+impl<S> CountingSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    /// Wraps `source`, resetting `counter` to `start` (the position the source begins at)
+    pub fn new(source: S, counter: Arc<FrameCounter>, start: Duration) -> Self {
+        counter.reset(start, source.sample_rate());
+        let channels = source.channels().max(1);
+        Self {
+            source,
+            counter,
+            channels,
+            samples_in_frame: 0,
+            queued: false,
+        }
+    }
+
+    /// Wraps a source queued behind the current one: the counter is only reset, and the
+    /// track change announced, once the sink pulls its first sample
+    pub fn queued(source: S, counter: Arc<FrameCounter>) -> Self {
+        let channels = source.channels().max(1);
+        Self {
+            source,
+            counter,
+            channels,
+            samples_in_frame: 0,
+            queued: true,
+        }
+    }
+}
+
+impl<S> Iterator for CountingSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.source.next()?;
+        if self.queued {
+            self.queued = false;
+            self.counter.start_queued_track(self.source.sample_rate());
+        }
+        self.samples_in_frame += 1;
+        if self.samples_in_frame >= self.channels {
+            self.samples_in_frame = 0;
+            self.counter.advance();
+        }
+        Some(sample)
+    }
+}
+
+impl<S> Source for CountingSource<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn test_counts_frames_not_samples() {
+        let counter = Arc::new(FrameCounter::new());
+        let source = SamplesBuffer::new(2, 1000, vec![0.0f32; 2000]);
+        let mut counting = CountingSource::new(source, Arc::clone(&counter), Duration::ZERO);
+
+        for _ in 0..1001 {
+            counting.next();
+        }
+
+        assert_eq!(counter.frames(), 500);
+        assert_eq!(counter.position_ms(), 500);
+    }
+
+    #[test]
+    fn test_reset_starts_from_offset() {
+        let counter = Arc::new(FrameCounter::new());
+        let source = SamplesBuffer::new(1, 48000, vec![0.0f32; 48000]);
+        let mut counting = CountingSource::new(source, Arc::clone(&counter), Duration::from_secs(30));
+
+        for _ in 0..24000 {
+            counting.next();
+        }
+
+        assert_eq!(counter.position_ms(), 30_500);
+    }
+
+    #[test]
+    fn test_queued_source_starts_new_track() {
+        let counter = Arc::new(FrameCounter::new());
+        let first = SamplesBuffer::new(1, 1000, vec![0.0f32; 10]);
+        let second = SamplesBuffer::new(1, 1000, vec![0.0f32; 10]);
+        let mut first = CountingSource::new(first, Arc::clone(&counter), Duration::ZERO);
+        let mut second = CountingSource::queued(second, Arc::clone(&counter));
+
+        while first.next().is_some() {}
+        assert_eq!(counter.frames(), 10);
+        assert_eq!(counter.track(), 0);
+
+        second.next();
+        assert_eq!(counter.frames(), 1);
+        assert_eq!(counter.track(), 1);
+    }
+
+    #[test]
+    fn test_silent_frames_are_not_counted() {
+        let counter = Arc::new(FrameCounter::new());
+        let source = SamplesBuffer::new(2, 1000, vec![0.0f32; 20]);
+        let mut counting = CountingSource::new(source, Arc::clone(&counter), Duration::ZERO);
+
+        counter.skip_silent_frame();
+        for _ in 0..6 {
+            counting.next();
+        }
+        assert_eq!(counter.frames(), 2);
+    }
+}
@@ -0,0 +1,272 @@
+//! Identifies the audio format from the first bytes of a file, so that decoding does not
+//! depend on the file extension
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use super::registry::SCORE_EXTENSION;
+
+/// Bytes read from the start of the file (or after an ID3v2 tag) for probing
+const PROBE_SIZE: usize = 4096;
+
+const ASF_HEADER_GUID: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11,
+    0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+
+/// The EBML element ID every Matroska and WebM file starts with
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+/// Audio formats recognised from their content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// Ogg stream starting with an OpusHead packet
+    OggOpus,
+    OggVorbis,
+    OggFlac,
+    /// Ogg stream with a codec we do not recognise
+    Ogg,
+    Flac,
+    /// MP4/M4A; the codec is only known once the `moov` box is read
+    Mp4,
+    Wav,
+    Mp3,
+    /// AAC in ADTS frames
+    Aac,
+    /// ASF container (WMA)
+    Asf,
+    /// Matroska or WebM (MKA, MKV, WEBM)
+    Matroska,
+}
+
+/// What the decoders get to look at when asked whether they can decode a file
+#[derive(Debug, Clone, Default)]
+pub struct ProbeData {
+    /// The first bytes of the stream, after any ID3v2 tag
+    pub header: Vec<u8>,
+    /// The format recognised from `header`, if any
+    pub format: Option<AudioFormat>,
+    /// Lower-case file extension
+    pub extension: Option<String>,
+}
+
+impl ProbeData {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = read_header(&mut file)?;
+
+        // An ID3v2 tag can precede MP3, but also FLAC or AAC
+        let format = match id3v2_size(&header) {
+            Some(tag_size) => {
+                file.seek(SeekFrom::Start(tag_size))?;
+                header = read_header(&mut file)?;
+                Some(probe(&header).unwrap_or(AudioFormat::Mp3))
+            }
+            None => probe(&header),
+        };
+
+        Ok(Self {
+            header,
+            format,
+            extension: path.extension().map(|ext| ext.to_string_lossy().to_lowercase()),
+        })
+    }
+
+    /// Scores a decoder that handles `formats` and `extensions`: a content match is
+    /// certain, while the extension only counts when the content was not recognised
+    pub fn score(&self, formats: &[AudioFormat], extensions: &[&str], content_score: u8) -> u8 {
+        match self.format {
+            Some(format) if formats.contains(&format) => content_score,
+            Some(_) => 0,
+            None if self.has_extension(extensions) => SCORE_EXTENSION,
+            None => 0,
+        }
+    }
+
+    pub fn has_extension(&self, extensions: &[&str]) -> bool {
+        self.extension.as_deref().is_some_and(|ext| extensions.contains(&ext))
+    }
+}
+
+/// Probes the file at `path`. Returns None when the content is not recognised, in which
+/// case the extension is all there is to go by.
+pub fn probe_file(path: &Path) -> std::io::Result<Option<AudioFormat>> {
+    ProbeData::read(path).map(|probe| probe.format)
+}
+
+fn read_header(file: &mut File) -> std::io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(PROBE_SIZE);
+    file.take(PROBE_SIZE as u64).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Identifies the format from the first bytes of the stream
+pub fn probe(header: &[u8]) -> Option<AudioFormat> {
+    if header.starts_with(b"OggS") {
+        return Some(probe_ogg(header));
+    }
+    if header.starts_with(b"fLaC") {
+        return Some(AudioFormat::Flac);
+    }
+    if header.get(4..8) == Some(&b"ftyp"[..]) {
+        return Some(AudioFormat::Mp4);
+    }
+    if (header.starts_with(b"RIFF") || header.starts_with(b"RF64")) && header.get(8..12) == Some(&b"WAVE"[..]) {
+        return Some(AudioFormat::Wav);
+    }
+    if header.starts_with(&ASF_HEADER_GUID) {
+        return Some(AudioFormat::Asf);
+    }
+    if header.starts_with(&EBML_MAGIC) {
+        return Some(AudioFormat::Matroska);
+    }
+    if id3v2_size(header).is_some() {
+        return Some(AudioFormat::Mp3);
+    }
+    probe_frames(header)
+}
+
+/// Looks at the codec identification header in the first packet of an Ogg stream
+fn probe_ogg(header: &[u8]) -> AudioFormat {
+    // The page header is 27 bytes plus one lacing value per segment
+    let packet = header.get(26)
+        .and_then(|&segments| header.get(27 + segments as usize..))
+        .unwrap_or_default();
+
+    if packet.starts_with(b"OpusHead") {
+        AudioFormat::OggOpus
+    } else if packet.starts_with(b"\x01vorbis") {
+        AudioFormat::OggVorbis
+    } else if packet.starts_with(b"\x7FFLAC") {
+        AudioFormat::OggFlac
+    } else {
+        AudioFormat::Ogg
+    }
+}
+
+/// Size of a leading ID3v2 tag, including its header and footer
+pub fn id3v2_size(header: &[u8]) -> Option<u64> {
+    if header.len() < 10 || !header.starts_with(b"ID3") {
+        return None;
+    }
+    // The size is syncsafe: 7 bits per byte
+    let size = header[6..10].iter().try_fold(0u64, |size, &byte| {
+        (byte < 0x80).then_some((size << 7) | byte as u64)
+    })?;
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+/// Recognises raw MPEG audio or ADTS frames. A frame sync is easily found by chance, so
+/// the frame that follows must be valid too.
+fn probe_frames(header: &[u8]) -> Option<AudioFormat> {
+    let (format, length) = frame_header(header)?;
+    let (next, _) = frame_header(header.get(length..)?)?;
+    (next == format).then_some(format)
+}
+
+/// Parses an MPEG audio or ADTS frame header, returning the format and frame length
+fn frame_header(data: &[u8]) -> Option<(AudioFormat, usize)> {
+    let header = data.get(..4)?;
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    if version & 0x02 != 0 && layer == 0 {
+        // ADTS: 12-bit sync, layer always 0
+        let sample_rate_index = (header[2] >> 2) & 0x0F;
+        let length = ((header[3] as usize & 0x03) << 11)
+            | ((*data.get(4)? as usize) << 3)
+            | (*data.get(5)? as usize >> 5);
+        return (sample_rate_index < 13 && length >= 7).then_some((AudioFormat::Aac, length));
+    }
+
+    // MPEG audio: version 1 is reserved, layer 0 is reserved
+    let bitrate_index = (header[2] >> 4) as usize;
+    let sample_rate_index = ((header[2] >> 2) & 0x03) as usize;
+    if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+        return None;
+    }
+    let padding = ((header[2] >> 1) & 0x01) as usize;
+    let mpeg1 = version == 3;
+
+    const BITRATES_V1: [[usize; 15]; 3] = [
+        [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+        [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    ];
+    const BITRATES_V2: [[usize; 15]; 2] = [
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+    const SAMPLE_RATES: [usize; 3] = [44100, 48000, 32000];
+
+    // Layer bits: 3 is layer I, 2 is layer II, 1 is layer III
+    let layer_index = 3 - layer as usize;
+    let bitrate = 1000 * if mpeg1 {
+        BITRATES_V1[layer_index][bitrate_index]
+    } else {
+        BITRATES_V2[layer_index.min(1)][bitrate_index]
+    };
+    // MPEG 2 halves the sample rate, MPEG 2.5 quarters it
+    let sample_rate = SAMPLE_RATES[sample_rate_index] >> (3 - version as usize).min(2);
+
+    let length = match layer_index {
+        0 => (12 * bitrate / sample_rate + padding) * 4,
+        2 if !mpeg1 => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    };
+    Some((AudioFormat::Mp3, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two MPEG-1 layer III frames at 128 kbps, 44.1 kHz: 417 bytes each
+    fn mp3_frames() -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        frame.repeat(2)
+    }
+
+    #[test]
+    fn test_probe_containers() {
+        let mut ogg = b"OggS\0\x02".to_vec();
+        ogg.extend([0; 20]);
+        ogg.push(1);
+        ogg.push(19);
+        let opus = [ogg.clone(), b"OpusHead\x01\x02".to_vec()].concat();
+        let vorbis = [ogg.clone(), b"\x01vorbis".to_vec()].concat();
+
+        assert_eq!(probe(&opus), Some(AudioFormat::OggOpus));
+        assert_eq!(probe(&vorbis), Some(AudioFormat::OggVorbis));
+        assert_eq!(probe(&ogg), Some(AudioFormat::Ogg));
+        assert_eq!(probe(b"fLaC\0\0\0\x22"), Some(AudioFormat::Flac));
+        assert_eq!(probe(b"\0\0\0\x20ftypM4A \0\0\0\0"), Some(AudioFormat::Mp4));
+        assert_eq!(probe(b"RIFF\x24\0\0\0WAVEfmt "), Some(AudioFormat::Wav));
+        assert_eq!(probe(&ASF_HEADER_GUID), Some(AudioFormat::Asf));
+        assert_eq!(probe(b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81\x01"), Some(AudioFormat::Matroska));
+        assert_eq!(probe(b"ID3\x04\0\0\0\0\x01\x00"), Some(AudioFormat::Mp3));
+        assert_eq!(id3v2_size(b"ID3\x04\0\0\0\0\x01\x00"), Some(138));
+        assert_eq!(probe(b"just some text"), None);
+    }
+
+    #[test]
+    fn test_probe_frames() {
+        let frames = mp3_frames();
+        assert_eq!(probe(&frames), Some(AudioFormat::Mp3));
+        // A lone sync word is not enough
+        assert_eq!(probe(&frames[..420]), None);
+
+        // ADTS, 44.1 kHz, two frames of 100 bytes
+        let mut frame = [0u8; 100];
+        frame[..6].copy_from_slice(&[0xFF, 0xF1, 0x50, 0x80, 0x0C, 0x80]);
+        assert_eq!(probe(&frame.repeat(2)), Some(AudioFormat::Aac));
+    }
+}
@@ -0,0 +1,241 @@
+//! Playing part of a file as a track of its own, as CUE sheets split a whole-album rip
+
+use std::time::Duration;
+use anyhow::Result;
+use rodio::Source;
+
+use super::info::DecoderInfo;
+use super::registry::{AudioSource, BoxedSource};
+
+/// The part of a file a track covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackRange {
+    pub start: Duration,
+    /// Where the track ends; None to play to the end of the file
+    pub end: Option<Duration>,
+}
+
+impl TrackRange {
+    /// Length of the track, when its end is known
+    pub fn duration(&self) -> Option<Duration> {
+        self.end.map(|end| end.saturating_sub(self.start))
+    }
+}
+
+/// Rounded to the nearest frame, so CUE positions (1/75 s) land on exact samples
+fn frames_at(pos: Duration, sample_rate: u32) -> u64 {
+    ((pos.as_nanos() * sample_rate as u128 + 500_000_000) / 1_000_000_000) as u64
+}
+
+/// Plays `range` of a source, with positions and seeks relative to the start of the range
+pub struct RangeSource {
+    source: BoxedSource,
+    range: TrackRange,
+    channels: usize,
+    /// Samples left before the end of the range; None when it runs to the end of the file
+    remaining: Option<u64>,
+    total_duration: Option<Duration>,
+}
+
+impl RangeSource {
+    /// Positions `source` at the start of `range`; sources that cannot seek are decoded
+    /// and discarded up to it instead
+    pub fn new(source: BoxedSource, range: TrackRange) -> Self {
+        let total_duration = range.duration()
+            .or_else(|| source.total_duration().map(|total| total.saturating_sub(range.start)));
+        let mut ranged = Self {
+            channels: source.channels().max(1) as usize,
+            source,
+            range,
+            remaining: None,
+            total_duration,
+        };
+
+        if ranged.source.seek(range.start).is_err() {
+            ranged.skip_frames(frames_at(range.start, ranged.source.sample_rate()));
+        }
+        ranged.reset_remaining(range.start);
+        ranged
+    }
+
+    fn skip_frames(&mut self, frames: u64) {
+        let mut buffer = vec![0.0; 1024 * self.channels];
+        let mut left = frames;
+        while left > 0 {
+            let wanted = left.min(1024) as usize * self.channels;
+            let read = self.source.read_frames(&mut buffer[..wanted]);
+            if read == 0 {
+                return;
+            }
+            left -= read as u64;
+        }
+    }
+
+    /// Counts the samples left from `pos` in the file to the end of the range
+    fn reset_remaining(&mut self, pos: Duration) {
+        let sample_rate = self.source.sample_rate();
+        self.remaining = self.range.end.map(|end| {
+            frames_at(end, sample_rate).saturating_sub(frames_at(pos, sample_rate)) * self.channels as u64
+        });
+    }
+}
+
+impl Iterator for RangeSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining.checked_sub(1)?;
+        }
+        self.source.next()
+    }
+}
+
+impl Source for RangeSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+impl AudioSource for RangeSource {
+    fn seek(&mut self, pos: Duration) -> Result<()> {
+        let pos = self.range.start + pos;
+        self.source.seek(pos)?;
+        self.reset_remaining(pos);
+        Ok(())
+    }
+
+    /// The file's information, with its chapters moved to the range's timeline
+    fn info(&self) -> DecoderInfo {
+        let mut info = DecoderInfo { duration: self.total_duration, ..self.source.info() };
+        let TrackRange { start, end } = self.range;
+        info.chapters.retain(|chapter| chapter.start >= start && end.is_none_or(|end| chapter.start < end));
+        for chapter in &mut info.chapters {
+            chapter.start -= start;
+        }
+        info
+    }
+
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let wanted = match self.remaining {
+            Some(remaining) => buffer.len().min(remaining as usize),
+            None => buffer.len(),
+        };
+        let frames = self.source.read_frames(&mut buffer[..wanted / self.channels * self.channels]);
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= (frames * self.channels) as u64;
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::test_utils::read_source;
+
+    /// Stereo frames (n, -n) at 1 kHz, counting from the seek position
+    struct Counter {
+        frame: u32,
+        end: u32,
+        seekable: bool,
+    }
+
+    impl Iterator for Counter {
+        type Item = f32;
+
+        fn next(&mut self) -> Option<f32> {
+            unreachable!("read a block at a time")
+        }
+    }
+
+    impl Source for Counter {
+        fn current_frame_len(&self) -> Option<usize> {
+            None
+        }
+
+        fn channels(&self) -> u16 {
+            2
+        }
+
+        fn sample_rate(&self) -> u32 {
+            1000
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            Some(Duration::from_millis(self.end as u64))
+        }
+    }
+
+    impl AudioSource for Counter {
+        fn seek(&mut self, pos: Duration) -> Result<()> {
+            if !self.seekable {
+                anyhow::bail!("not seekable");
+            }
+            self.frame = pos.as_millis() as u32;
+            Ok(())
+        }
+
+        fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+            let mut written = 0;
+            for frame in buffer.chunks_exact_mut(2) {
+                if self.frame == self.end {
+                    break;
+                }
+                frame.copy_from_slice(&[self.frame as f32, -(self.frame as f32)]);
+                self.frame += 1;
+                written += 1;
+            }
+            written
+        }
+    }
+
+    fn range(start: u64, end: Option<u64>) -> TrackRange {
+        TrackRange { start: Duration::from_millis(start), end: end.map(Duration::from_millis) }
+    }
+
+    #[test]
+    fn test_plays_only_the_range() {
+        for seekable in [true, false] {
+            let counter = Counter { frame: 0, end: 1000, seekable };
+            let mut source = RangeSource::new(Box::new(counter), range(100, Some(250)));
+            assert_eq!(source.total_duration(), Some(Duration::from_millis(150)));
+
+            let samples = read_source(&mut source);
+            assert_eq!(samples.len(), 300);
+            assert_eq!(&samples[..2], &[100.0, -100.0]);
+            assert_eq!(&samples[298..], &[249.0, -249.0]);
+        }
+    }
+
+    #[test]
+    fn test_seek_is_relative_to_range() {
+        let counter = Counter { frame: 0, end: 1000, seekable: true };
+        let mut source = RangeSource::new(Box::new(counter), range(400, None));
+        assert_eq!(source.total_duration(), Some(Duration::from_millis(600)));
+
+        source.seek(Duration::from_millis(500)).unwrap();
+        let samples = read_source(&mut source);
+        assert_eq!(samples.len(), 200);
+        assert_eq!(samples[0], 900.0);
+    }
+
+    #[test]
+    fn test_cue_positions_are_exact_samples() {
+        // 01:02:37 in a CUE sheet: 62 seconds and 37 of 75 frames
+        let pos = Duration::new(62, (37 * 1_000_000_000u64 / 75) as u32);
+        assert_eq!(frames_at(pos, 44100), 62 * 44100 + 37 * 588);
+    }
+}
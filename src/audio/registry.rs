@@ -0,0 +1,269 @@
+//! Decoder backends and the registry that picks one for each file
+//!
+//! Every backend is a `DecoderFactory`. `load_audio_file` and the playlist scanner ask the
+//! global registry, so a crate using the player can add codecs by registering a factory:
+//!
+//! ```ignore
+//! rust_music_player::audio::global_registry().write().unwrap().register(MyFactory);
+//! ```
+
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
+use anyhow::{bail, Result};
+use rodio::Source;
+
+use super::decoders::{FFmpegFactory, FlacFactory, Mp4Factory, OpusFactory, SymphoniaFactory, VorbisFactory};
+use super::info::DecoderInfo;
+use super::probe::ProbeData;
+
+/// Probe score of a decoder that recognises the content as its own format
+pub const SCORE_CERTAIN: u8 = 100;
+/// Probe score of a general decoder that supports the recognised format
+pub const SCORE_SUPPORTED: u8 = 50;
+/// Probe score when only the file extension matches
+pub const SCORE_EXTENSION: u8 = 10;
+/// Probe score of a catch-all decoder that can try any file
+pub const SCORE_FALLBACK: u8 = 1;
+
+/// A decoded track, as the player consumes it
+pub trait AudioSource: Source<Item = f32> + Send {
+    /// Seeks using the backend's native seeking, leaving the decoder positioned at `pos`
+    fn seek(&mut self, pos: Duration) -> Result<()>;
+
+    /// Duration, format and tags as reported by the decoder
+    fn info(&self) -> DecoderInfo {
+        DecoderInfo::from_source(self)
+    }
+
+    /// Fills `buffer` with whole interleaved frames and returns how many were written, 0 at
+    /// the end of the stream. Block-based sources override this to skip the per-sample calls.
+    fn read_frames(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.channels().max(1) as usize;
+        let mut written = 0;
+        let wanted = buffer.len() / channels * channels;
+        for slot in &mut buffer[..wanted] {
+            let Some(sample) = self.next() else { break };
+            *slot = sample;
+            written += 1;
+        }
+        written / channels
+    }
+}
+
+pub type BoxedSource = Box<dyn AudioSource>;
+
+impl Source for BoxedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        (**self).current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        (**self).channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        (**self).total_duration()
+    }
+}
+
+/// A decoder backend
+pub trait DecoderFactory: Send + Sync {
+    /// Short name, used to configure the fallback order and in error messages
+    fn name(&self) -> &str;
+
+    /// Lower-case extensions of the files this backend handles
+    fn extensions(&self) -> &[&str];
+
+    fn mime_types(&self) -> &[&str] {
+        &[]
+    }
+
+    /// How likely this backend is to decode the file, from 0 (not at all) to
+    /// `SCORE_CERTAIN`. Backends are tried from the highest score down.
+    fn probe(&self, probe: &ProbeData) -> u8;
+
+    fn open(&self, path: &Path) -> Result<BoxedSource>;
+}
+
+/// The decoder backends, in fallback order
+#[derive(Clone, Default)]
+pub struct DecoderRegistry {
+    factories: Vec<Arc<dyn DecoderFactory>>,
+}
+
+impl DecoderRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in backends: native Opus, Vorbis, MP4 (ALAC/Opus) and FLAC decoders first,
+    /// then Symphonia, then FFmpeg for anything else
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(OpusFactory);
+        registry.register(VorbisFactory);
+        registry.register(Mp4Factory);
+        registry.register(FlacFactory);
+        registry.register(SymphoniaFactory);
+        registry.register(FFmpegFactory);
+        registry
+    }
+
+    /// Adds a backend after the ones already registered, replacing any with the same name
+    pub fn register<F: DecoderFactory + 'static>(&mut self, factory: F) {
+        self.factories.retain(|existing| existing.name() != factory.name());
+        self.factories.push(Arc::new(factory));
+    }
+
+    /// Moves the named backends to the front, in the given order. Among backends with the
+    /// same probe score, the earlier one is tried first.
+    pub fn set_order(&mut self, names: &[&str]) {
+        let rank = |factory: &Arc<dyn DecoderFactory>| {
+            names.iter().position(|&name| name == factory.name()).unwrap_or(names.len())
+        };
+        // Stable: unnamed backends keep their relative order
+        self.factories.sort_by_key(rank);
+    }
+
+    /// Names of the registered backends, in fallback order
+    pub fn names(&self) -> Vec<&str> {
+        self.factories.iter().map(|factory| factory.name()).collect()
+    }
+
+    pub fn supports_extension(&self, extension: &str) -> bool {
+        let extension = extension.to_lowercase();
+        self.factories.iter().any(|factory| factory.extensions().contains(&extension.as_str()))
+    }
+
+    pub fn supports_mime_type(&self, mime_type: &str) -> bool {
+        self.factories.iter().any(|factory| {
+            factory.mime_types().iter().any(|supported| supported.eq_ignore_ascii_case(mime_type))
+        })
+    }
+
+    /// Whether a backend claims the file, by its content or its extension. A catch-all
+    /// backend trying anything does not count.
+    pub fn is_supported(&self, path: &Path) -> bool {
+        let has_extension = path.extension()
+            .is_some_and(|ext| self.supports_extension(&ext.to_string_lossy()));
+        has_extension || ProbeData::read(path).is_ok_and(|probe| {
+            self.factories.iter().any(|factory| factory.probe(&probe) > SCORE_FALLBACK)
+        })
+    }
+
+    /// The backends that may decode the probed file, most likely first
+    pub fn candidates(&self, probe: &ProbeData) -> Vec<&dyn DecoderFactory> {
+        let mut scored: Vec<(u8, &dyn DecoderFactory)> = self.factories.iter()
+            .map(|factory| (factory.probe(probe), factory.as_ref()))
+            .filter(|&(score, _)| score > 0)
+            .collect();
+        // Stable: ties keep the fallback order
+        scored.sort_by(|(a, _), (b, _)| b.cmp(a));
+        scored.into_iter().map(|(_, factory)| factory).collect()
+    }
+
+    /// Opens the file with the first backend that can decode it
+    pub fn open(&self, path: &Path) -> Result<BoxedSource> {
+        let probe = ProbeData::read(path)?;
+        let mut errors = Vec::new();
+        for factory in self.candidates(&probe) {
+            match factory.open(path) {
+                Ok(source) => return Ok(source),
+                Err(e) => errors.push(format!("{}: {}", factory.name(), e)),
+            }
+        }
+
+        if errors.is_empty() {
+            bail!("No decoder for {}", path.display());
+        }
+        bail!("Failed to decode {} ({})", path.display(), errors.join("; "))
+    }
+}
+
+/// The registry used by `load_audio_file` and the playlist scanner, holding the built-in
+/// backends until changed
+pub fn global_registry() -> &'static RwLock<DecoderRegistry> {
+    static REGISTRY: OnceLock<RwLock<DecoderRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(DecoderRegistry::with_builtin()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::AudioFormat;
+
+    struct TestFactory {
+        name: &'static str,
+        score: u8,
+    }
+
+    impl DecoderFactory for TestFactory {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &["xyz"]
+        }
+
+        fn mime_types(&self) -> &[&str] {
+            &["audio/x-xyz"]
+        }
+
+        fn probe(&self, _probe: &ProbeData) -> u8 {
+            self.score
+        }
+
+        fn open(&self, _path: &Path) -> Result<BoxedSource> {
+            bail!("{} cannot decode", self.name)
+        }
+    }
+
+    fn names<'a>(candidates: &[&'a dyn DecoderFactory]) -> Vec<&'a str> {
+        candidates.iter().map(|factory| factory.name()).collect()
+    }
+
+    #[test]
+    fn test_candidates_by_score_then_order() {
+        let mut registry = DecoderRegistry::new();
+        registry.register(TestFactory { name: "fallback", score: SCORE_FALLBACK });
+        registry.register(TestFactory { name: "general", score: SCORE_SUPPORTED });
+        registry.register(TestFactory { name: "other", score: SCORE_SUPPORTED });
+        registry.register(TestFactory { name: "never", score: 0 });
+        registry.register(TestFactory { name: "native", score: SCORE_CERTAIN });
+
+        let probe = ProbeData::default();
+        assert_eq!(names(&registry.candidates(&probe)), vec!["native", "general", "other", "fallback"]);
+
+        registry.set_order(&["other"]);
+        assert_eq!(names(&registry.candidates(&probe)), vec!["native", "other", "general", "fallback"]);
+        assert_eq!(registry.names()[0], "other");
+
+        assert!(registry.supports_extension("XYZ"));
+        assert!(registry.supports_mime_type("audio/X-XYZ"));
+        assert!(!registry.supports_extension("mp3"));
+    }
+
+    #[test]
+    fn test_probe_scores() {
+        let probe = ProbeData {
+            format: Some(AudioFormat::OggOpus),
+            extension: Some("ogg".to_string()),
+            ..ProbeData::default()
+        };
+        // The content decides; the extension is only a hint for unrecognised content
+        assert_eq!(probe.score(&[AudioFormat::OggOpus], &["opus"], SCORE_CERTAIN), SCORE_CERTAIN);
+        assert_eq!(probe.score(&[AudioFormat::OggVorbis], &["ogg"], SCORE_CERTAIN), 0);
+
+        let unknown = ProbeData { format: None, ..probe };
+        assert_eq!(unknown.score(&[AudioFormat::OggVorbis], &["ogg"], SCORE_CERTAIN), SCORE_EXTENSION);
+    }
+}
@@ -0,0 +1,229 @@
+//! Lock-free single-producer, single-consumer ring buffer of samples
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+struct Shared {
+    /// Sample bit patterns; atomics keep the buffer free of unsafe code
+    slots: Box<[AtomicU32]>,
+    /// Total samples written, only advanced by the producer
+    head: AtomicUsize,
+    /// Total samples read, only advanced by the consumer
+    tail: AtomicUsize,
+    /// Set by the producer once nothing more will be written
+    finished: AtomicBool,
+    underruns: AtomicU64,
+}
+
+/// Creates a ring holding up to `capacity` samples
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        finished: AtomicBool::new(false),
+        underruns: AtomicU64::new(0),
+    });
+    let producer = Producer { shared: Arc::clone(&shared), head: 0 };
+    let consumer = Consumer { shared, tail: 0, head: 0 };
+    (producer, consumer)
+}
+
+/// The writing end, owned by the decode thread
+pub struct Producer {
+    shared: Arc<Shared>,
+    head: usize,
+}
+
+impl Producer {
+    /// Room left, in samples
+    pub fn free(&self) -> usize {
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        self.shared.slots.len() - self.head.wrapping_sub(tail)
+    }
+
+    /// Writes as many of `samples` as fit, returning how many were written
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let count = samples.len().min(self.free());
+        let capacity = self.shared.slots.len();
+        for (i, sample) in samples[..count].iter().enumerate() {
+            self.shared.slots[self.head.wrapping_add(i) % capacity].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.head = self.head.wrapping_add(count);
+        self.shared.head.store(self.head, Ordering::Release);
+        count
+    }
+
+    /// Total samples written so far; the consumer can skip up to this point
+    pub fn written(&self) -> usize {
+        self.head
+    }
+
+    /// Marks whether the stream has ended, after the last `push`
+    pub fn set_finished(&self, finished: bool) {
+        self.shared.finished.store(finished, Ordering::Release);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Producer {
+    /// A producer that goes away, even by panicking, writes nothing more
+    fn drop(&mut self) {
+        self.set_finished(true);
+    }
+}
+
+/// The reading end, drained by the audio thread
+pub struct Consumer {
+    shared: Arc<Shared>,
+    tail: usize,
+    /// Last head seen, so most reads need no atomic load of it
+    head: usize,
+}
+
+impl Consumer {
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.tail == self.head {
+            self.head = self.shared.head.load(Ordering::Acquire);
+            if self.tail == self.head {
+                return None;
+            }
+        }
+
+        let bits = self.shared.slots[self.tail % self.shared.slots.len()].load(Ordering::Relaxed);
+        self.tail = self.tail.wrapping_add(1);
+        self.shared.tail.store(self.tail, Ordering::Release);
+        Some(f32::from_bits(bits))
+    }
+
+    /// Drops everything written before `position`, a value of `Producer::written`
+    pub fn skip_to(&mut self, position: usize) {
+        self.tail = position;
+        self.head = self.shared.head.load(Ordering::Acquire);
+        self.shared.tail.store(self.tail, Ordering::Release);
+    }
+
+    /// Samples ready to be read
+    pub fn available(&self) -> usize {
+        self.shared.head.load(Ordering::Acquire).wrapping_sub(self.tail)
+    }
+
+    /// Whether the producer has marked the end of the stream. Anything written before
+    /// it did is visible to `pop` once this returns true.
+    pub fn producer_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Acquire)
+    }
+
+    pub fn record_underrun(&self) {
+        self.shared.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        BufferStats { shared: Arc::clone(&self.shared) }
+    }
+}
+
+/// A read-only view of a ring, for diagnostics
+#[derive(Clone)]
+pub struct BufferStats {
+    shared: Arc<Shared>,
+}
+
+impl BufferStats {
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    /// Samples decoded but not played yet
+    pub fn len(&self) -> usize {
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        self.shared.head.load(Ordering::Acquire).wrapping_sub(tail).min(self.capacity())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How full the buffer is, from 0.0 to 1.0
+    pub fn fill_level(&self) -> f32 {
+        self.len() as f32 / self.capacity() as f32
+    }
+
+    /// How often the audio thread found the buffer empty before the end of the stream
+    pub fn underruns(&self) -> u64 {
+        self.shared.underruns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_push_pop_wraps_around() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        let stats = consumer.stats();
+
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0, 4.0, 5.0]), 4);
+        assert_eq!(producer.free(), 0);
+        assert_eq!(stats.fill_level(), 1.0);
+        assert_eq!(consumer.pop(), Some(1.0));
+        assert_eq!(consumer.pop(), Some(2.0));
+
+        assert_eq!(producer.push(&[5.0, 6.0]), 2);
+        let drained: Vec<f32> = std::iter::from_fn(|| consumer.pop()).collect();
+        assert_eq!(drained, vec![3.0, 4.0, 5.0, 6.0]);
+        assert!(stats.is_empty());
+    }
+
+    #[test]
+    fn test_skip_to_flushes_old_samples() {
+        let (mut producer, mut consumer) = ring_buffer(8);
+        producer.push(&[1.0, 2.0, 3.0]);
+        let flush_point = producer.written();
+        producer.push(&[10.0]);
+
+        consumer.skip_to(flush_point);
+        assert_eq!(consumer.pop(), Some(10.0));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn test_dropped_producer_finishes() {
+        let (producer, consumer) = ring_buffer(8);
+        assert!(!consumer.producer_finished());
+        drop(producer);
+        assert!(consumer.producer_finished());
+    }
+
+    #[test]
+    fn test_across_threads_keeps_order() {
+        let (mut producer, mut consumer) = ring_buffer(64);
+        let writer = thread::spawn(move || {
+            let samples: Vec<f32> = (0..10_000).map(|i| i as f32).collect();
+            let mut written = 0;
+            while written < samples.len() {
+                written += producer.push(&samples[written..]);
+                thread::yield_now();
+            }
+        });
+
+        let mut expected = 0.0;
+        while expected < 10_000.0 {
+            match consumer.pop() {
+                Some(sample) => {
+                    assert_eq!(sample, expected);
+                    expected += 1.0;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+//! Helpers shared by the decoder tests
+
+use std::path::{Path, PathBuf};
+
+use super::frames::FrameReader;
+use super::registry::AudioSource;
+
+/// Samples per read: small, so that streams end and ranges start partway through a block
+const BLOCK_SAMPLES: usize = 64;
+
+/// A file of `tests/resources`
+pub fn resource(name: &str) -> PathBuf {
+    Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/resources")).join(name)
+}
+
+/// The PCM integers of a WAV file's data chunk, `bytes` wide each
+pub fn wav_integers(name: &str, bytes: usize) -> Vec<i32> {
+    let wav = std::fs::read(resource(name)).unwrap();
+    let data = wav.windows(4).position(|id| id == b"data").unwrap() + 8;
+    wav[data..].chunks_exact(bytes)
+        .map(|sample| {
+            let mut word = [0; 4];
+            word[4 - bytes..].copy_from_slice(sample);
+            i32::from_le_bytes(word) >> (32 - 8 * bytes)
+        })
+        .collect()
+}
+
+/// Decodes the rest of the stream, interleaved
+pub fn read_all<R: FrameReader>(reader: &mut R) -> Vec<f32> {
+    let channels = reader.channels() as usize;
+    read_blocks(channels, |block| reader.read_frames(block))
+}
+
+/// Reads the rest of a source, interleaved
+pub fn read_source<S: AudioSource>(source: &mut S) -> Vec<f32> {
+    let channels = source.channels() as usize;
+    read_blocks(channels, |block| source.read_frames(block))
+}
+
+fn read_blocks(channels: usize, mut read_frames: impl FnMut(&mut [f32]) -> usize) -> Vec<f32> {
+    let mut samples = Vec::new();
+    let mut block = [0.0; BLOCK_SAMPLES];
+    loop {
+        let frames = read_frames(&mut block);
+        if frames == 0 {
+            return samples;
+        }
+        samples.extend_from_slice(&block[..frames * channels]);
+    }
+}
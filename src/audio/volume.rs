@@ -0,0 +1,104 @@
+//! Volume level and mute state, mapped to sink gain with a perceptual (dB-based) curve
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+// Attenuation at the lowest non-zero level; 0% is silence
+const VOLUME_RANGE_DB: f32 = 60.0;
+
+/// Volume level in percent, shared between the player and the display
+pub struct Volume {
+    level: AtomicU8,
+    muted: AtomicBool,
+}
+
+impl Volume {
+    /// Amount the level changes per key press
+    pub const STEP: u8 = 5;
+    pub const MAX: u8 = 100;
+
+    pub fn new(level: u8) -> Self {
+        Self {
+            level: AtomicU8::new(level.min(Self::MAX)),
+            muted: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the level in percent, regardless of mute
+    pub fn level(&self) -> u8 {
+        self.level.load(Ordering::SeqCst)
+    }
+
+    /// Sets the level in percent, clamped to 100
+    pub fn set_level(&self, level: u8) {
+        self.level.store(level.min(Self::MAX), Ordering::SeqCst);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::SeqCst)
+    }
+
+    /// Toggles mute and returns the new state
+    pub fn toggle_mute(&self) -> bool {
+        !self.muted.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    /// Returns the linear gain to apply to the sink
+    pub fn gain(&self) -> f32 {
+        if self.is_muted() {
+            return 0.0;
+        }
+        Self::level_to_gain(self.level())
+    }
+
+    /// Maps a level in percent to a linear gain, spreading the levels evenly in dB
+    pub fn level_to_gain(level: u8) -> f32 {
+        match level.min(Self::MAX) {
+            0 => 0.0,
+            level => {
+                let db = -VOLUME_RANGE_DB * (1.0 - level as f32 / Self::MAX as f32);
+                10f32.powf(db / 20.0)
+            }
+        }
+    }
+
+    /// Short readout for the status line, e.g. `Vol 80%` or `Muted`
+    pub fn label(&self) -> String {
+        if self.is_muted() {
+            "Muted".to_string()
+        } else {
+            format!("Vol {}%", self.level())
+        }
+    }
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self::new(Self::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gain_curve() {
+        assert_eq!(Volume::level_to_gain(0), 0.0);
+        assert_eq!(Volume::level_to_gain(100), 1.0);
+        // Half the level is 30 dB down, not half the amplitude
+        assert!((Volume::level_to_gain(50) - 10f32.powf(-1.5)).abs() < 1e-6);
+        assert_eq!(Volume::level_to_gain(150), 1.0);
+    }
+
+    #[test]
+    fn test_mute_keeps_level() {
+        let volume = Volume::new(70);
+        assert!(volume.toggle_mute());
+        assert_eq!(volume.gain(), 0.0);
+        assert_eq!(volume.level(), 70);
+        assert_eq!(volume.label(), "Muted");
+
+        assert!(!volume.toggle_mute());
+        assert_eq!(volume.label(), "Vol 70%");
+    }
+}
@@ -0,0 +1,256 @@
+//! Command line options and key bindings
+//!
+//! `--help` is generated from `OPTIONS` and `KEY_BINDINGS`, and the in-player help
+//! prints the same `KEY_BINDINGS`, so the two cannot drift apart.
+
+use std::{path::PathBuf, time::Duration};
+use anyhow::{anyhow, bail, Result};
+
+use crate::audio::{TimeFormat, TimeUtils, Volume};
+use crate::playlist::{RepeatMode, PLAYLIST_EXTENSIONS};
+
+/// A command line option, as shown by `--help`
+pub struct OptionSpec {
+    pub long: &'static str,
+    pub short: Option<char>,
+    /// Placeholder for the option's value, None for flags
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub const OPTIONS: &[OptionSpec] = &[
+    OptionSpec { long: "shuffle", short: Some('s'), value: None, help: "Shuffle the playlist" },
+    OptionSpec { long: "repeat", short: Some('r'), value: Some("MODE"), help: "Repeat mode: off, one or all (default: off)" },
+    OptionSpec { long: "start-at", short: None, value: Some("MM:SS"), help: "Start the first track at this position" },
+    OptionSpec { long: "volume", short: None, value: Some("PERCENT"), help: "Initial volume, 0-100 (default: 100)" },
+    OptionSpec { long: "crossfade", short: None, value: Some("SECONDS"), help: "Crossfade between tracks of different albums" },
+    OptionSpec { long: "recursive", short: Some('R'), value: None, help: "Scan directories recursively" },
+    OptionSpec { long: "no-ui", short: None, value: None, help: "Play without key bindings or progress display" },
+    OptionSpec { long: "save-playlist", short: None, value: Some("PATH"), help: "Where 'w' saves the playlist (default: playlist.m3u8)" },
+    OptionSpec { long: "help", short: Some('h'), value: None, help: "Print this help" },
+    OptionSpec { long: "version", short: Some('V'), value: None, help: "Print the version" },
+];
+
+/// Key bindings of the player, as (keys, action)
+pub const KEY_BINDINGS: &[(&str, &str)] = &[
+    ("SPACE",   "Play/Pause"),
+    ("q/ENTER", "Quit program"),
+    ("→/k",     "Seek forward 10s"),
+    ("←/j",     "Seek backward 10s"),
+    ("n/l",     "Next track (playlist)"),
+    ("p/h",     "Previous track (playlist)"),
+    ("]/[",     "Next/previous chapter"),
+    ("t",       "Toggle track/chapter time"),
+    ("+/-",     "Volume up/down"),
+    ("m",       "Mute/Unmute"),
+    ("c",       "Cycle crossfade (off/3s/5s/10s)"),
+    ("r",       "Cycle repeat (off/all/one)"),
+    ("s",       "Toggle shuffle"),
+    ("w",       "Save playlist (see --save-playlist)"),
+    ("?",       "Show this help"),
+];
+
+/// Options for a playback run
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Args {
+    /// Audio files, directories, playlists and glob patterns
+    pub inputs: Vec<String>,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    pub start_at: Option<Duration>,
+    pub volume: Option<u8>,
+    pub crossfade: Option<Duration>,
+    pub recursive: bool,
+    pub no_ui: bool,
+    pub save_playlist: Option<PathBuf>,
+}
+
+/// What the command line asks for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Play(Args),
+    Help,
+    Version,
+}
+
+/// Parses the arguments that follow the program name
+pub fn parse_args<I, S>(args: I) -> Result<Command>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut parsed = Args::default();
+    let mut args = args.into_iter().map(Into::into);
+    let mut only_inputs = false;
+
+    while let Some(arg) = args.next() {
+        if only_inputs || arg == "-" || !arg.starts_with('-') {
+            parsed.inputs.push(arg);
+            continue;
+        }
+        if arg == "--" {
+            only_inputs = true;
+            continue;
+        }
+
+        let (spec, inline_value) = find_option(&arg)?;
+        let value = match (spec.value, inline_value) {
+            (Some(_), Some(value)) => Some(value),
+            (Some(_), None) => Some(args.next()
+                .ok_or_else(|| anyhow!("Option --{} needs a value", spec.long))?),
+            (None, Some(_)) => bail!("Option --{} does not take a value", spec.long),
+            (None, None) => None,
+        };
+        let value = value.unwrap_or_default();
+
+        match spec.long {
+            "help" => return Ok(Command::Help),
+            "version" => return Ok(Command::Version),
+            "shuffle" => parsed.shuffle = true,
+            "recursive" => parsed.recursive = true,
+            "no-ui" => parsed.no_ui = true,
+            "repeat" => parsed.repeat = value.parse()?,
+            "start-at" => parsed.start_at = Some(parse_position(&value)?),
+            "save-playlist" => parsed.save_playlist = Some(PathBuf::from(value)),
+            "volume" => {
+                let volume: u8 = value.parse()
+                    .ok()
+                    .filter(|&volume| volume <= Volume::MAX)
+                    .ok_or_else(|| anyhow!("Invalid volume '{}' (expected 0-100)", value))?;
+                parsed.volume = Some(volume);
+            }
+            "crossfade" => {
+                let secs: f64 = value.parse()
+                    .ok()
+                    .filter(|secs: &f64| secs.is_finite() && *secs >= 0.0)
+                    .ok_or_else(|| anyhow!("Invalid crossfade '{}' (expected seconds)", value))?;
+                parsed.crossfade = Some(Duration::from_secs_f64(secs));
+            }
+            _ => unreachable!("option without a handler: --{}", spec.long),
+        }
+    }
+
+    if parsed.inputs.is_empty() {
+        bail!("No input given (see --help)");
+    }
+    Ok(Command::Play(parsed))
+}
+
+fn find_option(arg: &str) -> Result<(&'static OptionSpec, Option<String>)> {
+    let spec = if let Some(long) = arg.strip_prefix("--") {
+        let (name, value) = match long.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (long, None),
+        };
+        OPTIONS.iter()
+            .find(|spec| spec.long == name)
+            .map(|spec| (spec, value))
+    } else {
+        let mut chars = arg[1..].chars();
+        match (chars.next(), chars.as_str()) {
+            (Some(short), rest) => OPTIONS.iter()
+                .find(|spec| spec.short == Some(short))
+                .map(|spec| (spec, Some(rest.to_string()).filter(|rest| !rest.is_empty()))),
+            (None, _) => None,
+        }
+    };
+
+    spec.ok_or_else(|| anyhow!("Unknown option '{}' (see --help)", arg))
+}
+
+/// Accepts `MM:SS` or plain seconds
+fn parse_position(value: &str) -> Result<Duration> {
+    TimeUtils::parse_time_str(value)
+        .or_else(|| value.parse::<u64>().ok().map(|secs| secs * 1000))
+        .map(Duration::from_millis)
+        .ok_or_else(|| anyhow!("Invalid position '{}' (expected MM:SS)", value))
+}
+
+/// Formats the key bindings table shown by `--help` and the `?` key
+pub fn key_bindings_text() -> String {
+    KEY_BINDINGS.iter()
+        .map(|(key, action)| format!("{:<8} : {}\n", key, action))
+        .collect()
+}
+
+pub fn version_text() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+pub fn help_text(program: &str) -> String {
+    let playlists: Vec<String> = PLAYLIST_EXTENSIONS.iter().map(|ext| format!(".{}", ext)).collect();
+    let mut help = format!(
+        "{}\n\nUsage: {} [OPTIONS] <INPUT>...\n\n\
+         Inputs can be audio files, directories, playlists ({})\n\
+         and glob patterns; they are played in the order given.\n\nOptions:\n",
+        version_text(),
+        program,
+        playlists.join(", "),
+    );
+
+    for spec in OPTIONS {
+        let short = spec.short.map_or("    ".to_string(), |short| format!("-{}, ", short));
+        let long = match spec.value {
+            Some(value) => format!("--{} <{}>", spec.long, value),
+            None => format!("--{}", spec.long),
+        };
+        help.push_str(&format!("  {}{:<22} {}\n", short, long, spec.help));
+    }
+
+    help.push_str("\nControls:\n");
+    help.push_str(&key_bindings_text());
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(args: &[&str]) -> Args {
+        match parse_args(args.iter().copied()).unwrap() {
+            Command::Play(args) => args,
+            other => panic!("expected play, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_options_and_inputs() {
+        let args = play(&["a.mp3", "--shuffle", "--repeat", "all", "--start-at=1:23", "-R", "music/", "--volume", "40"]);
+        assert_eq!(args.inputs, vec!["a.mp3", "music/"]);
+        assert!(args.shuffle);
+        assert!(args.recursive);
+        assert_eq!(args.repeat, RepeatMode::All);
+        assert_eq!(args.start_at, Some(Duration::from_secs(83)));
+        assert_eq!(args.volume, Some(40));
+        assert!(!args.no_ui);
+        assert_eq!(args.save_playlist, None);
+
+        let args = play(&["--no-ui", "--save-playlist", "mix.m3u8", "--", "--odd-name.mp3"]);
+        assert!(args.no_ui);
+        assert_eq!(args.save_playlist, Some(PathBuf::from("mix.m3u8")));
+        assert_eq!(args.inputs, vec!["--odd-name.mp3"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_args(["--bogus", "a.mp3"]).is_err());
+        assert!(parse_args(["--repeat"]).is_err());
+        assert!(parse_args(["--volume", "150", "a.mp3"]).is_err());
+        assert!(parse_args(["--shuffle=yes", "a.mp3"]).is_err());
+        assert!(parse_args(Vec::<String>::new()).is_err());
+        assert_eq!(parse_args(["-h"]).unwrap(), Command::Help);
+        assert_eq!(parse_args(["a.mp3", "--version"]).unwrap(), Command::Version);
+    }
+
+    #[test]
+    fn test_help_lists_every_option_and_binding() {
+        let help = help_text("player");
+        for spec in OPTIONS {
+            assert!(help.contains(&format!("--{}", spec.long)));
+        }
+        for (key, _) in KEY_BINDINGS {
+            assert!(help.contains(key));
+        }
+        assert!(help.contains("playlists (.m3u, .m3u8, .pls, .xspf, .cue)"));
+    }
+}
@@ -3,13 +3,13 @@
 
 use std::{
     io::{stdout, Write},
-    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 use terminal_size::{terminal_size, Width, Height};
 
-use crate::audio::{TimeFormat, TimeUtils};
+use crate::audio::{chapter_at, Chapter, FrameCounter, TimeFormat, TimeUtils, Volume};
 
 // Display rate of 60fps
 const POLL_INTERVAL: Duration = Duration::from_millis(16);
@@ -25,11 +25,11 @@ impl DisplayThread {
     pub fn new(
         is_playing: Arc<AtomicBool>,
         is_paused: Arc<AtomicBool>,
-        current_position: Arc<Mutex<u64>>,
+        frame_counter: Arc<FrameCounter>,
+        volume: Arc<Volume>,
         total_duration: Option<Duration>,
-        playback_start: Arc<Mutex<Option<Instant>>>,
-        pause_start: Arc<Mutex<Option<Instant>>>,
-        total_pause_duration: Arc<Mutex<Duration>>,
+        chapters: Vec<Chapter>,
+        chapter_time: Arc<AtomicBool>,
     ) -> Self {
         let should_stop = Arc::new(AtomicBool::new(false));
         let should_stop_clone = Arc::clone(&should_stop);
@@ -45,53 +45,45 @@ impl DisplayThread {
                 let now = Instant::now();
                 if now.duration_since(last_update) >= POLL_INTERVAL {
                     if is_playing.load(Ordering::SeqCst) {
-                        if let Some(start_time) = *playback_start.lock().unwrap() {
-                            let pause_duration = *total_pause_duration.lock().unwrap();
-                            let elapsed = if is_paused.load(Ordering::SeqCst) {
-                                if let Some(pause_time) = *pause_start.lock().unwrap() {
-                                    start_time.elapsed() - (Instant::now() - pause_time) - pause_duration
-                                } else {
-                                    start_time.elapsed() - pause_duration
-                                }
-                            } else {
-                                start_time.elapsed() - pause_duration
-                            };
-
-                            let position_ms = elapsed.as_millis() as u64;
-                            *current_position.lock().unwrap() = position_ms;
-
-                            let total_ms = total_duration.map_or(0, |d| d.as_millis() as u64);
-                            let progress_bar = Self::format_progress_bar(
-                                position_ms,
-                                total_ms,
-                                Self::calculate_progress_bar_width()
-                            );
-
-                            let status = if is_paused.load(Ordering::SeqCst) {
-                                "(Paused)"
-                            } else {
-                                "(Playing)"
-                            };
-
-                            // Move to start of line, clear line, and print update
-                            print!("\r\x1B[2K{} / {} {} {}",
-                                TimeUtils::format_time(position_ms),
-                                TimeUtils::format_time(total_ms),
-                                progress_bar,
-                                status
-                            );
-                            stdout().flush().unwrap();
-
-                            if let Some(duration) = total_duration {
-                                if position_ms >= duration.as_millis() as u64 {
-                                    is_playing.store(false, Ordering::SeqCst);
-                                    println!(); // New line at end of playback
-                                    print!("\x1B[?25h"); // Show cursor
-                                    stdout().flush().unwrap();
-                                    break;
-                                }
-                            }
-                        }
+                        let total_ms = total_duration.map_or(0, |d| d.as_millis() as u64);
+                        // The end of the track is detected by the sink running dry; metadata
+                        // durations can be slightly short, so never display past them
+                        let position_ms = match frame_counter.position_ms() {
+                            position if total_ms > 0 => position.min(total_ms),
+                            position => position,
+                        };
+                        // With chapter time on, the times and bar cover the current chapter
+                        let chapter = Self::chapter_progress(&chapters, position_ms, total_ms);
+                        let (shown_ms, shown_total_ms) = match chapter {
+                            Some((_, elapsed, length)) if chapter_time.load(Ordering::SeqCst) => (elapsed, length),
+                            _ => (position_ms, total_ms),
+                        };
+                        let chapter_label = match chapter {
+                            Some((index, _, _)) => format!("Ch {}/{} ", index + 1, chapters.len()),
+                            None => String::new(),
+                        };
+                        let progress_bar = Self::format_progress_bar(
+                            shown_ms,
+                            shown_total_ms,
+                            Self::calculate_progress_bar_width()
+                        );
+
+                        let status = if is_paused.load(Ordering::SeqCst) {
+                            "(Paused)"
+                        } else {
+                            "(Playing)"
+                        };
+
+                        // Move to start of line, clear line, and print update
+                        print!("\r\x1B[2K{}{} / {} {} {} {}",
+                            chapter_label,
+                            TimeUtils::format_time(shown_ms),
+                            TimeUtils::format_time(shown_total_ms),
+                            progress_bar,
+                            status,
+                            volume.label()
+                        );
+                        stdout().flush().unwrap();
                     }
                     last_update = now;
                 }
@@ -134,6 +126,16 @@ impl DisplayThread {
         bar
     }
 
+    /// The chapter playing at `position_ms`, with the time into it and its length. The
+    /// last chapter runs to `total_ms`, or has no length when that is unknown.
+    pub fn chapter_progress(chapters: &[Chapter], position_ms: u64, total_ms: u64) -> Option<(usize, u64, u64)> {
+        let index = chapter_at(chapters, Duration::from_millis(position_ms))?;
+        let start_ms = chapters[index].start.as_millis() as u64;
+        let end_ms = chapters.get(index + 1)
+            .map_or(total_ms, |next| next.start.as_millis() as u64);
+        Some((index, position_ms - start_ms, end_ms.saturating_sub(start_ms)))
+    }
+
     /// Calculates the width of the progress bar based on the terminal size
     fn get_terminal_width() -> usize {
         if let Some((Width(w), Height(_))) = terminal_size() {
@@ -146,9 +148,9 @@ impl DisplayThread {
     /// Calcualtes the width of the progress bar, reserving space for other UI elements
     pub fn calculate_progress_bar_width() -> usize {
         let term_width = Self::get_terminal_width();
-        // Reserve space for "00:00 / 00:00 [] (Playing)    "
-        // Which is approximately 35 characters
-        let reserved_space = 35;
+        // Reserve space for "Ch 10/20 00:00 / 00:00 [] (Playing) Vol 100%    "
+        // Which is approximately 54 characters
+        let reserved_space = 54;
         if term_width > reserved_space {
             term_width - reserved_space
         } else {
@@ -168,8 +170,7 @@ impl Drop for DisplayThread {
 mod tests {
     use super::*;
     use std::sync::atomic::AtomicBool;
-    use std::sync::Mutex;
-    use std::time::{Duration, Instant};
+    use std::time::Duration;
 
     #[test]
     fn test_progress_bar_formatting() {
@@ -194,24 +195,34 @@ mod tests {
         assert!(width >= 20); // Minimum width
     }
 
+    #[test]
+    fn test_chapter_progress() {
+        let chapters: Vec<Chapter> = [0, 60, 90].iter()
+            .map(|&secs| Chapter { start: Duration::from_secs(secs), title: None })
+            .collect();
+        assert_eq!(DisplayThread::chapter_progress(&chapters, 75_000, 120_000), Some((1, 15_000, 30_000)));
+        assert_eq!(DisplayThread::chapter_progress(&chapters, 100_000, 120_000), Some((2, 10_000, 30_000)));
+        // The last chapter of a track of unknown length has no length either
+        assert_eq!(DisplayThread::chapter_progress(&chapters, 100_000, 0), Some((2, 10_000, 0)));
+        assert_eq!(DisplayThread::chapter_progress(&[], 100_000, 120_000), None);
+    }
+
     #[test]
     fn test_display_thread_lifecycle() {
         let is_playing = Arc::new(AtomicBool::new(true));
         let is_paused = Arc::new(AtomicBool::new(false));
-        let current_position = Arc::new(Mutex::new(0));
+        let frame_counter = Arc::new(FrameCounter::new());
+        let volume = Arc::new(Volume::default());
         let total_duration = Some(Duration::from_secs(10));
-        let playback_start = Arc::new(Mutex::new(Some(Instant::now())));
-        let pause_start = Arc::new(Mutex::new(None));
-        let total_pause_duration = Arc::new(Mutex::new(Duration::from_secs(0)));
 
         let mut display = DisplayThread::new(
             Arc::clone(&is_playing),
             Arc::clone(&is_paused),
-            Arc::clone(&current_position),
+            Arc::clone(&frame_counter),
+            Arc::clone(&volume),
             total_duration,
-            Arc::clone(&playback_start),
-            Arc::clone(&pause_start),
-            Arc::clone(&total_pause_duration),
+            Vec::new(),
+            Arc::new(AtomicBool::new(false)),
         );
 
         // Let it run for a brief moment
@@ -2,4 +2,5 @@ pub mod models;
 pub mod audio;
 pub mod display;
 pub mod utils;
-pub mod playlist;
\ No newline at end of file
+pub mod playlist;
+pub mod cli;
\ No newline at end of file
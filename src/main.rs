@@ -1,108 +1,270 @@
-use std::{env, io::{stdout, Write}, path::Path, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
-use std::path::PathBuf;
+use std::{env, fs, io::{stdout, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant}};
 use anyhow::Result;
 use crossterm::{
     event::{self, Event, KeyCode, KeyEventKind},
     terminal::{enable_raw_mode, disable_raw_mode},
 };
 
-use rust_music_player::audio::player::AudioPlayer;
-use rust_music_player::utils::metadata::print_song_info;
-
-mod playlist;
-use playlist::{Playlist, get_supported_files};
+use rust_music_player::audio::{player::AudioPlayer, TimeFormat, TimeUtils, Volume};
+use rust_music_player::cli::{self, Args, Command};
+use rust_music_player::playlist::{Playlist, collect_inputs, save_m3u8};
+use rust_music_player::utils::metadata::{print_metadata, read_metadata};
 
 // Poll keyboard at 60x / s
 const POLL_INTERVAL: Duration = Duration::from_millis(60);
+// Where 'w' saves the current playlist without --save-playlist
+const SAVED_PLAYLIST: &str = "playlist.m3u8";
+// Shortest time between two seeks from held-down keys
+const SEEK_COOLDOWN: Duration = Duration::from_millis(100);
+// Crossfade lengths cycled through with 'c', in seconds
+const CROSSFADE_STEPS: [u64; 4] = [0, 3, 5, 10];
+
+/// How the playback loop of a track ended
+enum PlaybackEnd {
+    Quit,
+    Finished,
+    Skipped,
+}
 
 fn main() -> Result<()> {
-    let args = parse_args()?;
+    let program = env::args().next().unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+    let args = match cli::parse_args(env::args().skip(1))? {
+        Command::Play(args) => args,
+        Command::Help => {
+            print!("{}", cli::help_text(&program));
+            return Ok(());
+        }
+        Command::Version => {
+            println!("{}", cli::version_text());
+            return Ok(());
+        }
+    };
+
+    let mut playlist = setup_playlist(&args)?;
+    // Track navigation only makes sense with more than a single file
+    let can_navigate = playlist.len() > 1;
+
     let mut player = AudioPlayer::new()?;
-    let (mut playlist, is_directory) = setup_playlist(&args)?;
+    if let Some(volume) = args.volume {
+        player.set_volume(volume);
+    }
+    if let Some(crossfade) = args.crossfade {
+        player.set_crossfade(crossfade);
+    }
+    player.set_display_enabled(!args.no_ui);
 
-    print_controls()?;
-    enable_raw_mode()?;
+    if !args.no_ui {
+        print_controls()?;
+        enable_raw_mode()?;
+    }
 
     let should_stop = Arc::new(AtomicBool::new(false));
     let mut last_seek = Instant::now();
-    let seek_cooldown = Duration::from_millis(100);
+    let save_target = args.save_playlist.clone().unwrap_or_else(|| PathBuf::from(SAVED_PLAYLIST));
+    // 'w' never overwrites a playlist being played from
+    let save_path = (!is_input(&save_target, &args.inputs)).then_some(save_target.as_path());
 
-    while let Some(current_path) = playlist.current() {
-        handle_track_start(current_path, &mut player)?;
+    let mut start_at = args.start_at;
 
-        let original_index = playlist.current_index();
+    while let Some(current_path) = playlist.current() {
+        handle_track_start(current_path, &playlist, &mut player)?;
+        if let Some(position) = start_at.take() {
+            if let Err(e) = player.seek_to(position) {
+                println!("\r{}", e);
+            }
+        }
+        queue_next_track(&playlist, &mut player);
 
-        let exit_program = handle_playback_loop(
+        let playback_end = handle_playback_loop(
             &mut player,
             &mut playlist,
             &should_stop,
             &mut last_seek,
-            seek_cooldown,
-            is_directory,
+            can_navigate,
+            args.no_ui,
+            save_path,
         )?;
 
-        if exit_program {
-            break;
-        }
-
-        if playlist.current_index() == original_index {
-            playlist.next();
-        }
-
-        if playlist.current().is_none() {
-            break;
+        match playback_end {
+            PlaybackEnd::Quit => break,
+            PlaybackEnd::Finished => playlist.advance(),
+            PlaybackEnd::Skipped => {}
         }
     }
 
     cleanup(player)
 }
 
-fn parse_args() -> anyhow::Result<PathBuf> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        anyhow::bail!("Usage: {} <audio_file_or_directory>", args[0]);
+fn setup_playlist(args: &Args) -> anyhow::Result<Playlist> {
+    let max_depth = if args.recursive { None } else { Some(0) };
+    let loaded = collect_inputs(&args.inputs, max_depth)?;
+    for entry in &loaded.unresolved {
+        eprintln!("Skipping {}: {}", entry.location, entry.reason);
     }
-    Ok(PathBuf::from(&args[1]))
-}
 
-fn setup_playlist(path: &Path) -> anyhow::Result<(Playlist, bool)> {
-    let (files, is_directory) = if path.is_dir() {
-        (get_supported_files(path)?, true)
-    } else {
-        (vec![path.to_path_buf()], false)
-    };
-
-    if files.is_empty() {
+    let mut playlist = Playlist::from_entries(loaded.entries);
+    if playlist.is_empty() {
         anyhow::bail!("No supported audio files found");
     }
 
-    Ok((Playlist::new(files), is_directory))
+    playlist.set_repeat(args.repeat);
+    if args.shuffle {
+        playlist.reshuffle();
+    }
+    Ok(playlist)
 }
 
-fn print_controls() -> anyhow::Result<()> {
-    let controls = [
-        ("SPACE",   "Play/Pause"),
-        ("q/ENTER", "Quit program"),
-        ("→/k",     "Seek forward 10s"),
-        ("←/j",     "Seek backward 10s"),
-        ("n/l",     "Next track (playlist)"),
-        ("p/h",     "Previous track (playlist)"),
-        ("?",       "Show this help"),
-    ];
+/// Whether `path` names the same file as one of the inputs
+fn is_input(path: &Path, inputs: &[String]) -> bool {
+    let Ok(path) = fs::canonicalize(path) else { return false };
+    inputs.iter().any(|input| fs::canonicalize(input).is_ok_and(|input| input == path))
+}
 
+/// Saves the playlist for 'w', or says why it cannot: `save_path` is None when saving
+/// would overwrite an input playlist
+fn save_playlist(playlist: &Playlist, save_path: Option<&Path>) {
+    let Some(path) = save_path else {
+        println!("\r\nNot saving over an input playlist; choose another file with --save-playlist");
+        return;
+    };
+    match save_m3u8(playlist, path) {
+        Ok(()) => println!("\r\nPlaylist saved to {}", path.display()),
+        Err(e) => println!("\r\n{}", e),
+    }
+}
+
+fn print_controls() -> anyhow::Result<()> {
     println!("\r\n\n\n=== Controls ===\n");
-    for (key, action) in controls {
-        println!("\r{:<8} : {}", key, action);
+    for line in cli::key_bindings_text().lines() {
+        println!("\r{}", line);
     }
     println!("\r==================");
     stdout().flush()?;
     Ok(())
 }
 
-fn handle_track_start(path: &Path, player: &mut AudioPlayer) -> anyhow::Result<()> {
-    let duration = print_song_info(path)?;
+fn print_playlist_modes(playlist: &Playlist) {
+    let shuffle = if playlist.is_shuffled() { "On" } else { "Off" };
+    println!("\rRepeat: {} | Shuffle: {}", playlist.repeat(), shuffle);
+}
+
+/// Falls back to the playlist file's duration hint when the track has no duration tag
+fn with_duration_hint(duration: Option<Duration>, playlist: &Playlist) -> Option<Duration> {
+    duration.or_else(|| playlist.current_entry().and_then(|entry| entry.duration))
+}
+
+/// Prints the bit depth and sample rate the decoder reports, e.g. "24-bit/96kHz"
+fn print_resolution(player: &AudioPlayer) {
+    if let Some(info) = player.track_info() {
+        println!("\rResolution: {}", info.resolution());
+    }
+}
+
+/// Lists the track's chapters with their start times, if it has any
+fn print_chapters(player: &AudioPlayer) {
+    let chapters = player.chapters();
+    if chapters.is_empty() {
+        return;
+    }
+    println!("\rChapters: {}", chapters.len());
+    for (index, chapter) in chapters.iter().enumerate() {
+        println!("\r  {:>3}. {} {}",
+            index + 1,
+            TimeUtils::format_time(chapter.start.as_millis() as u64),
+            chapter.label(index)
+        );
+    }
+}
+
+/// Announces the chapter a chapter key jumped to
+fn print_chapter_jump(player: &AudioPlayer, jumped: Result<Option<usize>, String>) {
+    match jumped {
+        Ok(Some(index)) => {
+            let chapters = player.chapters();
+            println!("\r\nChapter {}/{}: {}", index + 1, chapters.len(), chapters[index].label(index));
+        }
+        Ok(None) => {}
+        Err(e) => println!("\r\n{}", e),
+    }
+}
+
+/// Prints the file's tags; for a track of a CUE sheet, the sheet's title, performer and
+/// track length take the place of the whole file's. Tags that cannot be read show as
+/// unknown: the decoders may still play a file the tag reader does not know
+fn print_track_info(path: &Path, playlist: &Playlist) -> Option<Duration> {
+    let mut metadata = read_metadata(path).unwrap_or_default();
+    if let Some(entry) = playlist.current_entry().filter(|entry| entry.range.is_some()) {
+        metadata.title = entry.title.clone().or(metadata.title);
+        metadata.artist = entry.artist.clone().or(metadata.artist);
+        metadata.duration = entry.duration;
+        metadata.track_number = None;
+    }
+    print_metadata(&metadata);
+    metadata.duration
+}
+
+fn handle_track_start(path: &Path, playlist: &Playlist, player: &mut AudioPlayer) -> anyhow::Result<()> {
+    let duration = with_duration_hint(print_track_info(path, playlist), playlist);
+    print_playlist_modes(playlist);
     player.set_metadata_duration(duration);
-    player.play(path)?;
+    let range = playlist.current_entry().and_then(|entry| entry.range);
+    player.play_range(path, range)?;
+    print_resolution(player);
+    print_chapters(player);
+    Ok(())
+}
+
+/// Opens the next playlist entry ahead of time so it joins the current one without a gap,
+/// or crossfades into it when it comes from a different album
+fn queue_next_track(playlist: &Playlist, player: &mut AudioPlayer) {
+    let Some(next) = playlist.peek_next_entry() else {
+        player.clear_queued();
+        return;
+    };
+
+    let metadata = read_metadata(&next.path).ok();
+    let current_album = playlist.current()
+        .and_then(|path| read_metadata(path).ok())
+        .and_then(|metadata| metadata.album);
+    let next_album = metadata.as_ref().and_then(|metadata| metadata.album.as_ref());
+    // Tracks of one CUE sheet share a file, tagged or not
+    let same_album = (current_album.is_some() && current_album.as_ref() == next_album)
+        || playlist.current() == Some(next.path.as_path());
+
+    // A CUE track's length is not its file's
+    let length = match next.range {
+        Some(range) => range.duration(),
+        None => metadata.and_then(|metadata| metadata.duration),
+    };
+    // On failure the track is opened again, and reported, once the current one ends
+    let _ = player.queue_range(&next.path, next.range, length, !same_album);
+}
+
+fn cycle_crossfade(player: &mut AudioPlayer) {
+    let current = player.crossfade().as_secs();
+    let index = CROSSFADE_STEPS.iter().position(|&step| step == current).unwrap_or(0);
+    let next = CROSSFADE_STEPS[(index + 1) % CROSSFADE_STEPS.len()];
+    player.set_crossfade(Duration::from_secs(next));
+
+    if next == 0 {
+        println!("\r\nCrossfade: off");
+    } else {
+        println!("\r\nCrossfade: {}s", next);
+    }
+}
+
+/// Follows the player onto the queued track once the sink starts it
+fn handle_track_change(player: &mut AudioPlayer, playlist: &mut Playlist) -> anyhow::Result<()> {
+    if let Some(path) = player.poll_track_change() {
+        playlist.advance();
+        let duration = with_duration_hint(print_track_info(&path, playlist), playlist);
+        print_playlist_modes(playlist);
+        player.set_metadata_duration(duration);
+        print_resolution(player);
+        print_chapters(player);
+        player.start_display();
+        queue_next_track(playlist, player);
+    }
     Ok(())
 }
 
@@ -111,32 +273,43 @@ fn handle_playback_loop(
     playlist: &mut Playlist,
     should_stop: &AtomicBool,
     last_seek: &mut Instant,
-    seek_cooldown: Duration,
-    is_directory: bool,
-) -> anyhow::Result<bool> {
+    can_navigate: bool,
+    no_ui: bool,
+    save_path: Option<&Path>,
+) -> anyhow::Result<PlaybackEnd> {
     let mut not_playing_count = 0;
     const MAX_NOT_PLAYING: u32 = 3;
 
     while player.is_playing() {
         if should_stop.load(Ordering::SeqCst) {
-            return Ok(true);
+            return Ok(PlaybackEnd::Quit);
         }
 
-        handle_user_input(
-            player,
-            playlist,
-            should_stop,
-            last_seek,
-            seek_cooldown,
-            is_directory,
-        )?;
+        let track_changed = if no_ui {
+            thread::sleep(POLL_INTERVAL);
+            false
+        } else {
+            handle_user_input(
+                player,
+                playlist,
+                should_stop,
+                last_seek,
+                can_navigate,
+                save_path,
+            )?
+        };
+        if track_changed {
+            return Ok(PlaybackEnd::Skipped);
+        }
+
+        handle_track_change(player, playlist)?;
 
         if !check_playback_status(player, &mut not_playing_count, MAX_NOT_PLAYING) {
             break;
         }
     }
 
-    Ok(false)
+    Ok(PlaybackEnd::Finished)
 }
 
 fn handle_user_input(
@@ -144,38 +317,74 @@ fn handle_user_input(
     playlist: &mut Playlist,
     should_stop: &AtomicBool,
     last_seek: &mut Instant,
-    seek_cooldown: Duration,
-    is_directory: bool,
-) -> anyhow::Result<()> {
+    can_navigate: bool,
+    save_path: Option<&Path>,
+) -> anyhow::Result<bool> {
     if event::poll(POLL_INTERVAL)? {
         if let Event::Key(key) = event::read()? {
             if key.kind != KeyEventKind::Press {
-                return Ok(());
+                return Ok(false);
             }
 
             match key.code {
                 KeyCode::Char(' ') => player.toggle_pause(),
                 KeyCode::Enter | KeyCode::Char('q') => should_stop.store(true, Ordering::SeqCst),
-                KeyCode::Right | KeyCode::Char('k') => handle_seek(player, 10, last_seek, seek_cooldown),
-                KeyCode::Left  | KeyCode::Char('j') => handle_seek(player, -10, last_seek, seek_cooldown),
-                KeyCode::Char('n') | KeyCode::Char('l') if is_directory => handle_next_track(player, playlist),
-                KeyCode::Char('p') | KeyCode::Char('h') if is_directory => handle_prev_track(player, playlist),
+                KeyCode::Right | KeyCode::Char('k') => handle_seek(player, 10, last_seek),
+                KeyCode::Left  | KeyCode::Char('j') => handle_seek(player, -10, last_seek),
+                KeyCode::Char('n') | KeyCode::Char('l') if can_navigate => {
+                    handle_next_track(player, playlist);
+                    return Ok(true);
+                }
+                KeyCode::Char('p') | KeyCode::Char('h') if can_navigate => {
+                    handle_prev_track(player, playlist);
+                    return Ok(true);
+                }
+                KeyCode::Char('+') | KeyCode::Char('=') => {
+                    player.set_volume(player.volume().saturating_add(Volume::STEP));
+                }
+                KeyCode::Char('-') => player.set_volume(player.volume().saturating_sub(Volume::STEP)),
+                KeyCode::Char('m') => {
+                    player.toggle_mute();
+                }
+                KeyCode::Char('c') => cycle_crossfade(player),
+                KeyCode::Char(']') => {
+                    let jumped = player.next_chapter();
+                    print_chapter_jump(player, jumped);
+                }
+                KeyCode::Char('[') => {
+                    let jumped = player.previous_chapter();
+                    print_chapter_jump(player, jumped);
+                }
+                KeyCode::Char('t') => {
+                    let chapter_time = player.toggle_chapter_time();
+                    println!("\r\nTime display: {}", if chapter_time { "chapter" } else { "track" });
+                }
+                KeyCode::Char('r') => {
+                    playlist.set_repeat(playlist.repeat().cycle());
+                    println!("\r\nRepeat: {}", playlist.repeat());
+                    queue_next_track(playlist, player);
+                }
+                KeyCode::Char('s') => {
+                    playlist.set_shuffle(!playlist.is_shuffled());
+                    println!("\r\nShuffle: {}", if playlist.is_shuffled() { "On" } else { "Off" });
+                    queue_next_track(playlist, player);
+                }
+                KeyCode::Char('w') => save_playlist(playlist, save_path),
                 KeyCode::Char('?') => print_controls()?,
                 _ => {}
             }
         }
     }
-    Ok(())
+    Ok(false)
 }
 
 fn handle_seek(
     player: &mut AudioPlayer,
     offset: i64,
     last_seek: &mut Instant,
-    cooldown: Duration,
 ) {
     let now = Instant::now();
-    if now.duration_since(*last_seek) >= cooldown {
+    if now.duration_since(*last_seek) >= SEEK_COOLDOWN {
         let _ = player.seek(offset);
         *last_seek = now;
     }
@@ -0,0 +1,168 @@
+//! CUE sheets, which split whole-album rips into tracks at their `INDEX 01` marks
+
+use std::{fs, path::Path, time::Duration};
+use anyhow::Context;
+
+use super::LoadedPlaylist;
+use super::location::{decode_text, resolve_items, resolve_location, PlaylistItem};
+use crate::audio::TrackRange;
+
+/// Loads a CUE sheet as one entry per track. Each track plays from its `INDEX 01` to the
+/// next track's in the same file, and the last track of a file plays to its end.
+pub fn load_cue(path: &Path) -> anyhow::Result<LoadedPlaylist> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed to read CUE sheet: {}", path.display()))?;
+    let contents = decode_text(bytes);
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut loaded = resolve_items(parse_cue(&contents), base, resolve_location);
+    for entry in &mut loaded.entries {
+        entry.cue_sheet = Some(path.to_path_buf());
+    }
+    Ok(loaded)
+}
+
+/// A `TRACK` of the sheet, with the file and position of its `INDEX 01`
+struct CueTrack {
+    file: String,
+    title: Option<String>,
+    performer: Option<String>,
+    start: Option<Duration>,
+}
+
+fn parse_cue(contents: &str) -> Vec<PlaylistItem> {
+    let mut tracks = Vec::new();
+    let mut current: Option<CueTrack> = None;
+    let mut file = None;
+    let mut sheet_performer = None;
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let fields = split_fields(line);
+        let Some(command) = fields.first() else { continue };
+        let value = fields.get(1).cloned();
+
+        match command.to_uppercase().as_str() {
+            // An unquoted name may contain spaces; the last field is the file type
+            "FILE" if fields.len() > 1 => file = Some(fields[1..(fields.len() - 1).max(2)].join(" ")),
+            "TRACK" => {
+                tracks.extend(current.take());
+                current = Some(CueTrack { file: String::new(), title: None, performer: None, start: None });
+            }
+            "TITLE" => {
+                // Before the first track, the title is the album's
+                if let Some(track) = current.as_mut() {
+                    track.title = value;
+                }
+            }
+            "PERFORMER" => match current.as_mut() {
+                Some(track) => track.performer = value,
+                None => sheet_performer = value,
+            },
+            // A track's audio starts at INDEX 01; the pregap before it belongs to the track before
+            "INDEX" if value.as_deref().and_then(|number| number.parse::<u32>().ok()) == Some(1) => {
+                if let (Some(track), Some(file)) = (current.as_mut(), &file) {
+                    track.file = file.clone();
+                    track.start = fields.get(2).and_then(|time| parse_timestamp(time));
+                }
+            }
+            _ => {}
+        }
+    }
+    tracks.extend(current);
+
+    tracks.retain(|track| track.start.is_some());
+    let ends: Vec<Option<Duration>> = tracks.iter().enumerate()
+        .map(|(i, track)| {
+            tracks.get(i + 1)
+                .filter(|next| next.file == track.file)
+                .and_then(|next| next.start)
+        })
+        .collect();
+
+    tracks.into_iter().zip(ends)
+        .map(|(track, end)| {
+            let range = TrackRange { start: track.start.unwrap_or_default(), end };
+            PlaylistItem {
+                location: track.file,
+                title: track.title.filter(|title| !title.is_empty()),
+                artist: track.performer.or_else(|| sheet_performer.clone()).filter(|artist| !artist.is_empty()),
+                duration: range.duration(),
+                range: Some(range),
+            }
+        })
+        .collect()
+}
+
+/// Splits a line into its words, keeping "quoted strings" together
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let (field, after) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+        };
+        fields.push(field.to_string());
+        rest = after.trim_start();
+    }
+    fields
+}
+
+/// Parses `mm:ss:ff`, where a frame is 1/75 of a second
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let parts: Vec<u64> = timestamp.split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let &[minutes, seconds, frames] = parts.as_slice() else { return None };
+    (seconds < 60 && frames < 75)
+        .then(|| Duration::new(minutes * 60 + seconds, (frames * 1_000_000_000 / 75) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("00:00:00"), Some(Duration::ZERO));
+        assert_eq!(parse_timestamp("01:02:15"), Some(Duration::from_millis(62_200)));
+        assert_eq!(parse_timestamp("120:00:00"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_timestamp("00:60:00"), None);
+        assert_eq!(parse_timestamp("00:00:75"), None);
+        assert_eq!(parse_timestamp("00:00"), None);
+    }
+
+    #[test]
+    fn test_parse_cue() {
+        let contents = "\u{feff}REM GENRE Jazz\n\
+            PERFORMER \"The Band\"\n\
+            TITLE \"The Album\"\n\
+            FILE \"The Album.flac\" WAVE\n\
+            \x20 TRACK 01 AUDIO\n\
+            \x20   TITLE \"Opening\"\n\
+            \x20   INDEX 01 00:00:00\n\
+            \x20 TRACK 02 AUDIO\n\
+            \x20   TITLE \"Guest Spot\"\n\
+            \x20   PERFORMER \"A Guest\"\n\
+            \x20   INDEX 00 03:58:00\n\
+            \x20   INDEX 01 04:00:30\n\
+            FILE bonus track.wav WAVE\n\
+            \x20 TRACK 03 AUDIO\n\
+            \x20   INDEX 01 00:00:00\n";
+        let items = parse_cue(contents);
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].location, "The Album.flac");
+        assert_eq!(items[0].title.as_deref(), Some("Opening"));
+        assert_eq!(items[0].artist.as_deref(), Some("The Band"));
+        assert_eq!(items[0].range, Some(TrackRange { start: Duration::ZERO, end: Some(Duration::from_millis(240_400)) }));
+        assert_eq!(items[0].duration, Some(Duration::from_millis(240_400)));
+
+        assert_eq!(items[1].artist.as_deref(), Some("A Guest"));
+        assert_eq!(items[1].range, Some(TrackRange { start: Duration::from_millis(240_400), end: None }));
+        assert_eq!(items[1].duration, None);
+
+        assert_eq!(items[2].location, "bonus track.wav");
+        assert_eq!(items[2].title, None);
+        assert_eq!(items[2].range, Some(TrackRange { start: Duration::ZERO, end: None }));
+    }
+}
@@ -0,0 +1,118 @@
+//! Expands `*`, `?` and `[...]` patterns given on the command line, for shells that don't
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Returns true if `pattern` contains glob wildcards
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Lists the existing paths matching `pattern`, sorted. Wildcards never match a
+/// leading dot, so hidden files are left out like in a shell.
+pub fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut matches = vec![PathBuf::new()];
+
+    for component in Path::new(pattern).components() {
+        let part = match component {
+            Component::Normal(part) => part.to_string_lossy(),
+            other => {
+                // Root, prefix, `.` and `..` are taken as they are
+                for path in &mut matches {
+                    path.push(other.as_os_str());
+                }
+                continue;
+            }
+        };
+
+        if !is_glob(&part) {
+            for path in &mut matches {
+                path.push(part.as_ref());
+            }
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for dir in &matches {
+            let read_from = if dir.as_os_str().is_empty() { Path::new(".") } else { dir.as_path() };
+            let Ok(entries) = fs::read_dir(read_from) else { continue };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !name.starts_with('.') && matches_pattern(&part, &name) {
+                    next.push(dir.join(name));
+                }
+            }
+        }
+        matches = next;
+    }
+
+    matches.retain(|path| !path.as_os_str().is_empty() && path.exists());
+    matches.sort();
+    matches
+}
+
+/// Matches a single path component against a pattern
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_from(&pattern, &name)
+}
+
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches_from(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches_from(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(close) = pattern.iter().skip(2).position(|&c| c == ']').map(|i| i + 2) else {
+                // No closing bracket: a literal '['
+                return name.first() == Some(&'[') && matches_from(&pattern[1..], &name[1..]);
+            };
+            let Some(&c) = name.first() else { return false };
+            let (negated, set) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..close]),
+                _ => (false, &pattern[1..close]),
+            };
+            let in_set = set.iter().enumerate().any(|(i, &start)| {
+                if set.get(i + 1) == Some(&'-') && i + 2 < set.len() {
+                    (start..=set[i + 2]).contains(&c)
+                } else {
+                    start == c
+                }
+            });
+            in_set != negated && matches_from(&pattern[close + 1..], &name[1..])
+        }
+        Some(&literal) => name.first() == Some(&literal) && matches_from(&pattern[1..], &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_pattern_matching() {
+        assert!(matches_pattern("*.mp3", "song.mp3"));
+        assert!(!matches_pattern("*.mp3", "song.flac"));
+        assert!(matches_pattern("track?.ogg", "track1.ogg"));
+        assert!(matches_pattern("[0-9]*", "01 Intro.flac"));
+        assert!(!matches_pattern("[!0-9]*", "01 Intro.flac"));
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let root = tempdir().unwrap();
+        for name in ["A/1.mp3", "A/2.flac", "B/3.mp3", "B/.4.mp3"] {
+            let path = root.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let pattern = root.path().join("*/*.mp3");
+        let found = expand_glob(&pattern.to_string_lossy());
+        assert_eq!(found, vec![root.path().join("A/1.mp3"), root.path().join("B/3.mp3")]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Turns the locations written in playlist files into local paths
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{LoadedPlaylist, PlaylistEntry, UnresolvedEntry};
+use crate::audio::TrackRange;
+
+/// An entry as written in a playlist file, before its location is resolved
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PlaylistItem {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub duration: Option<Duration>,
+    pub range: Option<TrackRange>,
+}
+
+/// Resolves every item against `base` (the playlist's directory) with `resolve`. Items
+/// that do not point to an existing local file are reported instead of failing the
+/// whole playlist.
+pub(crate) fn resolve_items(
+    items: Vec<PlaylistItem>,
+    base: &Path,
+    resolve: fn(&str, &Path) -> Result<PathBuf, String>,
+) -> LoadedPlaylist {
+    let mut loaded = LoadedPlaylist::default();
+    for item in items {
+        match resolve(&item.location, base) {
+            Ok(path) if path.is_file() => loaded.entries.push(PlaylistEntry {
+                path,
+                title: item.title,
+                artist: item.artist,
+                duration: item.duration,
+                range: item.range,
+                cue_sheet: None,
+            }),
+            Ok(_) => loaded.unresolved.push(UnresolvedEntry {
+                location: item.location,
+                reason: "file not found".to_string(),
+            }),
+            Err(reason) => loaded.unresolved.push(UnresolvedEntry {
+                location: item.location,
+                reason,
+            }),
+        }
+    }
+    loaded
+}
+
+/// Maps a plain path, a relative reference or a `file://` URI to a local path
+pub(crate) fn resolve_location(location: &str, base: &Path) -> Result<PathBuf, String> {
+    let path = if let Some(rest) = strip_scheme(location, "file") {
+        // file:///path and file://localhost/path are local; other hosts are not
+        let rest = rest.strip_prefix("//").unwrap_or(rest);
+        let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+        if !rest.starts_with('/') {
+            return Err("remote file URI".to_string());
+        }
+        let decoded = percent_decode(rest).ok_or("invalid percent-encoding")?;
+        // file:///C:/Music → C:/Music
+        let is_drive = decoded.as_bytes().get(2) == Some(&b':');
+        PathBuf::from(if is_drive { &decoded[1..] } else { &decoded[..] })
+    } else if has_scheme(location) {
+        return Err("not a local file".to_string());
+    } else {
+        PathBuf::from(location)
+    };
+
+    Ok(if path.is_absolute() { path } else { base.join(path) })
+}
+
+/// Resolves a URI reference as used by XSPF: relative references are percent-encoded too
+pub(crate) fn resolve_uri(location: &str, base: &Path) -> Result<PathBuf, String> {
+    if has_scheme(location) {
+        return resolve_location(location, base);
+    }
+    let decoded = percent_decode(location).ok_or("invalid percent-encoding")?;
+    resolve_location(&decoded, base)
+}
+
+fn strip_scheme<'a>(location: &'a str, scheme: &str) -> Option<&'a str> {
+    let (head, rest) = location.split_once(':')?;
+    head.eq_ignore_ascii_case(scheme).then_some(rest)
+}
+
+/// A URI scheme needs at least two characters, so `C:\Music` is still a path
+fn has_scheme(location: &str) -> bool {
+    location.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.len() > 1
+            && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+/// The text of a playlist file. Older programs write the system code page rather than
+/// UTF-8; read as Latin-1, most of it stays legible.
+pub(crate) fn decode_text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes)
+        .unwrap_or_else(|e| e.into_bytes().iter().map(|&byte| byte as char).collect())
+}
+
+/// Decodes `%XX` escapes; returns None for malformed escapes or invalid UTF-8
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("My%20Song%C3%A9.mp3").as_deref(), Some("My Songé.mp3"));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text("Café".as_bytes().to_vec()), "Café");
+        assert_eq!(decode_text(b"Caf\xE9".to_vec()), "Café");
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let base = Path::new("/lists");
+        assert_eq!(resolve_location("file:///music/a%20b.mp3", base), Ok(PathBuf::from("/music/a b.mp3")));
+        assert_eq!(resolve_location("file://localhost/music/a.mp3", base), Ok(PathBuf::from("/music/a.mp3")));
+        assert_eq!(resolve_location("sub/a.mp3", base), Ok(PathBuf::from("/lists/sub/a.mp3")));
+        assert!(resolve_location("http://example.com/a.mp3", base).is_err());
+        assert!(resolve_location("file://server/share/a.mp3", base).is_err());
+        assert_eq!(resolve_uri("sub/a%20b.mp3", base), Ok(PathBuf::from("/lists/sub/a b.mp3")));
+    }
+}
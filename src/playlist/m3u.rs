@@ -0,0 +1,171 @@
+//! M3U and M3U8 playlist files
+
+use std::{collections::HashSet, fs, path::Path, time::Duration};
+use anyhow::Context;
+
+use super::{LoadedPlaylist, Playlist};
+use super::location::{decode_text, resolve_items, resolve_location, PlaylistItem};
+
+/// Loads an M3U/M3U8 playlist. Relative paths are resolved against the playlist's
+/// directory, `#EXTINF` lines provide the following entry's duration and title, and
+/// any other `#` line is a comment.
+pub fn load_m3u(path: &Path) -> anyhow::Result<LoadedPlaylist> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed to read playlist: {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(resolve_items(parse_m3u(&decode_text(bytes)), base, resolve_location))
+}
+
+fn parse_m3u(contents: &str) -> Vec<PlaylistItem> {
+    let mut items = Vec::new();
+    let mut info: Option<(Option<Duration>, Option<String>)> = None;
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = Some(parse_extinf(extinf));
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (duration, title) = info.take().unwrap_or_default();
+        items.push(PlaylistItem {
+            location: line.to_string(),
+            title,
+            duration,
+            ..Default::default()
+        });
+    }
+
+    items
+}
+
+/// Parses `<seconds>[ attributes],<title>`; a negative length means unknown
+fn parse_extinf(extinf: &str) -> (Option<Duration>, Option<String>) {
+    let (head, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+    let duration = head.split_whitespace()
+        .next()
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .filter(|secs| *secs >= 0.0)
+        .map(Duration::from_secs_f64);
+    let title = Some(title.trim().to_string()).filter(|title| !title.is_empty());
+    (duration, title)
+}
+
+/// Writes the playlist, in its current play order (shuffled or not), as an M3U8 file.
+/// Entries below the playlist's directory are stored with relative paths, and the tracks
+/// of a CUE sheet as the sheet, once, where its first track is.
+pub fn save_m3u8(playlist: &Playlist, path: &Path) -> anyhow::Result<()> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    fs::write(path, format_m3u8(playlist, base))
+        .with_context(|| format!("Failed to write playlist: {}", path.display()))
+}
+
+fn format_m3u8(playlist: &Playlist, base: &Path) -> String {
+    let mut contents = String::from("#EXTM3U\n");
+    let mut written_sheets = HashSet::new();
+    for entry in playlist.entries() {
+        let file = match &entry.cue_sheet {
+            Some(sheet) if !written_sheets.insert(sheet) => continue,
+            Some(sheet) => sheet,
+            None => {
+                if entry.duration.is_some() || entry.title.is_some() {
+                    let secs = entry.duration.map_or(-1, |d| d.as_secs() as i64);
+                    contents.push_str(&format!("#EXTINF:{},{}\n", secs, entry.title.as_deref().unwrap_or("")));
+                }
+                &entry.path
+            }
+        };
+
+        let path = match file.strip_prefix(base) {
+            Ok(relative) if !base.as_os_str().is_empty() => relative,
+            _ => file.as_path(),
+        };
+        contents.push_str(&path.to_string_lossy());
+        contents.push('\n');
+    }
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::playlist::{load_playlist_file, PlaylistEntry};
+
+    #[test]
+    fn test_parse_m3u() {
+        let contents = "\u{feff}#EXTM3U\n\
+            # a comment\n\
+            #EXTINF:123,Artist - Title\n\
+            album/01.flac\n\
+            \n\
+            /music/02.mp3\n\
+            #EXTINF:-1,\n\
+            03.ogg\n";
+        let items = parse_m3u(contents);
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].location, "album/01.flac");
+        assert_eq!(items[0].duration, Some(Duration::from_secs(123)));
+        assert_eq!(items[0].title.as_deref(), Some("Artist - Title"));
+        assert_eq!(items[1], PlaylistItem { location: "/music/02.mp3".to_string(), ..Default::default() });
+        assert_eq!(items[2], PlaylistItem { location: "03.ogg".to_string(), ..Default::default() });
+    }
+
+    #[test]
+    fn test_load_latin1() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("Café.mp3"), b"").unwrap();
+        fs::write(root.path().join("list.m3u"), b"#EXTINF:10,Caf\xE9\nCaf\xE9.mp3\n").unwrap();
+
+        let loaded = load_m3u(&root.path().join("list.m3u")).unwrap();
+        assert!(loaded.unresolved.is_empty());
+        assert_eq!(loaded.entries[0].path, root.path().join("Café.mp3"));
+        assert_eq!(loaded.entries[0].title.as_deref(), Some("Café"));
+    }
+
+    #[test]
+    fn test_save_keeps_play_order() {
+        let mut entries = vec![PlaylistEntry::new(PathBuf::from("/lists/a.mp3"))];
+        entries.push(PlaylistEntry {
+            path: PathBuf::from("/elsewhere/b.mp3"),
+            title: Some("B".to_string()),
+            duration: Some(Duration::from_secs(61)),
+            ..PlaylistEntry::new(PathBuf::new())
+        });
+        let mut playlist = Playlist::with_seed(entries, 2);
+        playlist.reshuffle();
+
+        let contents = format_m3u8(&playlist, Path::new("/lists"));
+        assert_eq!(contents, "#EXTM3U\n#EXTINF:61,B\n/elsewhere/b.mp3\na.mp3\n");
+
+        let reloaded = parse_m3u(&contents);
+        assert_eq!(reloaded[1].location, "a.mp3");
+    }
+
+    #[test]
+    fn test_save_writes_cue_sheet_once() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("album.flac"), b"").unwrap();
+        fs::write(root.path().join("other.mp3"), b"").unwrap();
+        fs::write(root.path().join("album.cue"), "FILE \"album.flac\" WAVE\n\
+            TRACK 01 AUDIO\n  INDEX 01 00:00:00\n\
+            TRACK 02 AUDIO\n  INDEX 01 03:00:00\n").unwrap();
+
+        let mut entries = load_playlist_file(&root.path().join("album.cue")).unwrap().entries;
+        entries.push(PlaylistEntry::new(root.path().join("other.mp3")));
+        let saved = root.path().join("saved.m3u8");
+        save_m3u8(&Playlist::from_entries(entries.clone()), &saved).unwrap();
+        assert_eq!(fs::read_to_string(&saved).unwrap(), "#EXTM3U\nalbum.cue\nother.mp3\n");
+
+        // Reloading brings back the sheet's tracks, not the whole rip once per track
+        assert_eq!(load_playlist_file(&saved).unwrap().entries, entries);
+    }
+}
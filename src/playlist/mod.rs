@@ -0,0 +1,494 @@
+mod glob;
+mod location;
+mod m3u;
+mod cue;
+mod pls;
+mod scan;
+mod xspf;
+
+pub use glob::{expand_glob, is_glob};
+pub use cue::load_cue;
+pub use location::percent_decode;
+pub use m3u::{load_m3u, save_m3u8};
+pub use pls::load_pls;
+pub use xspf::load_xspf;
+pub use scan::{get_supported_files, scan_directory, is_supported_extension};
+
+use crate::audio::TrackRange;
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A playlist entry, with the hints a playlist file may carry about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlaylistEntry {
+    pub path: PathBuf,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub duration: Option<Duration>,
+    /// The part of the file this entry plays, for the tracks of a CUE sheet
+    pub range: Option<TrackRange>,
+    /// The CUE sheet the entry is a track of; saved playlists list the sheet instead
+    pub cue_sheet: Option<PathBuf>,
+}
+
+impl PlaylistEntry {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, title: None, artist: None, duration: None, range: None, cue_sheet: None }
+    }
+}
+
+/// An entry of a playlist file that does not lead to a playable local file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedEntry {
+    /// The location as written in the playlist file
+    pub location: String,
+    pub reason: String,
+}
+
+/// Result of loading a playlist file: the usable entries and those that were skipped
+#[derive(Debug, Clone, Default)]
+pub struct LoadedPlaylist {
+    pub entries: Vec<PlaylistEntry>,
+    pub unresolved: Vec<UnresolvedEntry>,
+}
+
+impl LoadedPlaylist {
+    /// Appends the entries and skipped locations of another playlist
+    pub fn extend(&mut self, other: LoadedPlaylist) {
+        self.entries.extend(other.entries);
+        self.unresolved.extend(other.unresolved);
+    }
+
+    /// Appends a playlist file's entries, or the file itself as skipped when it cannot be read
+    fn extend_from_file(&mut self, path: &Path) {
+        match load_playlist_file(path) {
+            Ok(loaded) => self.extend(loaded),
+            Err(e) => self.unresolved.push(UnresolvedEntry {
+                location: path.display().to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+}
+
+/// Extensions of the playlist files the loaders understand
+pub const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8", "pls", "xspf", "cue"];
+
+fn playlist_extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|ext| ext.to_lowercase())
+        .filter(|ext| PLAYLIST_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Returns true for files the playlist loaders understand
+pub fn is_playlist_file(path: &Path) -> bool {
+    playlist_extension(path).is_some()
+}
+
+/// Loads the entries of a playlist file, picking the parser from its extension
+pub fn load_playlist_file(path: &Path) -> anyhow::Result<LoadedPlaylist> {
+    let loaded = match playlist_extension(path).as_deref() {
+        Some("m3u" | "m3u8") => load_m3u(path)?,
+        Some("pls") => load_pls(path)?,
+        Some("xspf") => load_xspf(path)?,
+        Some("cue") => return load_cue(path),
+        _ => anyhow::bail!("Unsupported playlist format: {}", path.display()),
+    };
+    Ok(expand_cue_sheets(loaded))
+}
+
+/// Replaces the CUE sheets a playlist lists, as saved playlists do, with their tracks
+fn expand_cue_sheets(loaded: LoadedPlaylist) -> LoadedPlaylist {
+    let mut expanded = LoadedPlaylist { entries: Vec::new(), unresolved: loaded.unresolved };
+    for entry in loaded.entries {
+        if playlist_extension(&entry.path).as_deref() != Some("cue") {
+            expanded.entries.push(entry);
+            continue;
+        }
+        match load_cue(&entry.path) {
+            Ok(sheet) => expanded.extend(sheet),
+            Err(e) => expanded.unresolved.push(UnresolvedEntry {
+                location: entry.path.display().to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+    expanded
+}
+
+/// Builds one list from command line inputs: audio files, directories (scanned down to
+/// `max_depth` levels), playlist files and glob patterns, in the order given
+pub fn collect_inputs<S: AsRef<str>>(inputs: &[S], max_depth: Option<usize>) -> anyhow::Result<LoadedPlaylist> {
+    let mut loaded = LoadedPlaylist::default();
+
+    for input in inputs {
+        let input = input.as_ref();
+        let paths = if is_glob(input) && !Path::new(input).exists() {
+            expand_glob(input)
+        } else {
+            vec![PathBuf::from(input)]
+        };
+        if paths.is_empty() {
+            loaded.unresolved.push(UnresolvedEntry {
+                location: input.to_string(),
+                reason: "no match".to_string(),
+            });
+        }
+
+        for path in paths {
+            if path.is_dir() {
+                let files = match scan_directory(&path, max_depth) {
+                    Ok(files) => files,
+                    Err(e) => {
+                        loaded.unresolved.push(UnresolvedEntry {
+                            location: path.display().to_string(),
+                            reason: e.to_string(),
+                        });
+                        continue;
+                    }
+                };
+                // Scans list CUE sheets in place of the files they split into tracks
+                for file in files {
+                    if is_playlist_file(&file) {
+                        loaded.extend_from_file(&file);
+                    } else {
+                        loaded.entries.push(PlaylistEntry::new(file));
+                    }
+                }
+            } else if is_playlist_file(&path) {
+                loaded.extend_from_file(&path);
+            } else if path.is_file() {
+                loaded.entries.push(PlaylistEntry::new(path));
+            } else {
+                loaded.unresolved.push(UnresolvedEntry {
+                    location: path.display().to_string(),
+                    reason: "file not found".to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// What happens when a track ends or the end of the list is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepeatMode {
+    /// Stop after the last track
+    #[default]
+    Off,
+    /// Play the current track again
+    One,
+    /// Start over from the first track
+    All,
+}
+
+impl RepeatMode {
+    /// Returns the mode after this one, for a single cycling keybinding
+    pub fn cycle(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+impl FromStr for RepeatMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(RepeatMode::Off),
+            "one" => Ok(RepeatMode::One),
+            "all" => Ok(RepeatMode::All),
+            _ => anyhow::bail!("Invalid repeat mode '{}' (expected off, one or all)", s),
+        }
+    }
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            RepeatMode::Off => "Off",
+            RepeatMode::One => "One",
+            RepeatMode::All => "All",
+        };
+        f.write_str(label)
+    }
+}
+
+pub struct Playlist {
+    entries: Vec<PlaylistEntry>,
+    /// Play order as indices into `entries`: in order, or a shuffled permutation
+    order: Vec<usize>,
+    /// Position in `order`; equal to its length once the list is finished
+    position: usize,
+    repeat: RepeatMode,
+    shuffle: bool,
+    seed: u64,
+}
+
+impl Playlist {
+    pub fn new(files: Vec<PathBuf>) -> Self {
+        Self::from_entries(files.into_iter().map(PlaylistEntry::new).collect())
+    }
+
+    pub fn from_entries(entries: Vec<PlaylistEntry>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::with_seed(entries, seed)
+    }
+
+    /// Creates a playlist whose shuffle order is derived from `seed`
+    pub fn with_seed(entries: Vec<PlaylistEntry>, seed: u64) -> Self {
+        Self {
+            order: (0..entries.len()).collect(),
+            entries,
+            position: 0,
+            repeat: RepeatMode::default(),
+            shuffle: false,
+            seed,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the current entry, or None once the list is finished
+    pub fn current(&self) -> Option<&Path> {
+        self.current_entry().map(|entry| entry.path.as_path())
+    }
+
+    /// Returns the current entry with its hints, or None once the list is finished
+    pub fn current_entry(&self) -> Option<&PlaylistEntry> {
+        self.order.get(self.position).map(|&index| &self.entries[index])
+    }
+
+    /// Returns the entry that `advance` would move to, without moving
+    pub fn peek_next(&self) -> Option<&Path> {
+        self.peek_next_entry().map(|entry| entry.path.as_path())
+    }
+
+    /// Returns the entry that `advance` would move to with its hints, without moving
+    pub fn peek_next_entry(&self) -> Option<&PlaylistEntry> {
+        if self.repeat == RepeatMode::One {
+            return self.current_entry();
+        }
+        self.next_position()
+            .map(|position| &self.entries[self.order[position]])
+    }
+
+    /// Moves on after the current track finished playing, honouring `RepeatMode::One`
+    pub fn advance(&mut self) {
+        if self.repeat != RepeatMode::One {
+            self.next();
+        }
+    }
+
+    /// Skips to the next entry; past the last one the list is finished unless repeating
+    pub fn next(&mut self) {
+        self.position = self.next_position().unwrap_or(self.order.len());
+    }
+
+    /// Steps back through the play order; wraps to the end only when repeating all
+    pub fn previous(&mut self) {
+        if self.order.is_empty() {
+            return;
+        }
+
+        if self.position > 0 {
+            self.position = (self.position - 1).min(self.order.len() - 1);
+        } else if self.repeat == RepeatMode::All {
+            self.position = self.order.len() - 1;
+        }
+    }
+
+    fn next_position(&self) -> Option<usize> {
+        let next = self.position + 1;
+        if next < self.order.len() {
+            Some(next)
+        } else if self.repeat == RepeatMode::All && !self.order.is_empty() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index into the entry list of the current entry
+    pub fn current_index(&self) -> usize {
+        self.order.get(self.position).copied().unwrap_or(self.entries.len())
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffle
+    }
+
+    /// Turns shuffle on or off. The current entry stays current: when shuffling, the
+    /// entries played so far keep their place, so `previous` still goes back through
+    /// them, and only the ones after the current entry are shuffled.
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        if shuffle == self.shuffle {
+            return;
+        }
+        self.shuffle = shuffle;
+
+        let current = self.order.get(self.position).copied();
+        if shuffle {
+            let played = (self.position + 1).min(self.order.len());
+            shuffle_indices(&mut self.order[played..], &mut self.seed);
+        } else {
+            self.order = (0..self.entries.len()).collect();
+            self.position = current.unwrap_or(self.entries.len());
+        }
+    }
+
+    /// Shuffles the whole list, current entry included, and starts from the top
+    pub fn reshuffle(&mut self) {
+        self.shuffle = true;
+        self.order = (0..self.entries.len()).collect();
+        shuffle_indices(&mut self.order, &mut self.seed);
+        self.position = 0;
+    }
+
+    /// Returns the entries in play order
+    pub fn entries(&self) -> impl Iterator<Item = &PlaylistEntry> {
+        self.order.iter().map(|&index| &self.entries[index])
+    }
+}
+
+/// Fisher-Yates shuffle driven by a xorshift generator, so a seed always gives the same order
+fn shuffle_indices(indices: &mut [usize], seed: &mut u64) {
+    for i in (1..indices.len()).rev() {
+        // xorshift64 never leaves zero, so nudge it off
+        let mut x = if *seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { *seed };
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *seed = x;
+
+        let j = (x % (i as u64 + 1)) as usize;
+        indices.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn playlist(count: usize) -> Playlist {
+        let entries = (0..count)
+            .map(|i| PlaylistEntry::new(PathBuf::from(format!("{}.mp3", i))))
+            .collect();
+        Playlist::with_seed(entries, 42)
+    }
+
+    #[test]
+    fn test_repeat_off_finishes() {
+        let mut list = playlist(2);
+        list.next();
+        assert_eq!(list.peek_next(), None);
+        list.advance();
+        assert_eq!(list.current(), None);
+    }
+
+    #[test]
+    fn test_repeat_modes() {
+        let mut list = playlist(2);
+        list.set_repeat(RepeatMode::One);
+        list.advance();
+        assert_eq!(list.current(), Some(Path::new("0.mp3")));
+
+        list.set_repeat(RepeatMode::All);
+        list.next();
+        list.next();
+        assert_eq!(list.current(), Some(Path::new("0.mp3")));
+        list.previous();
+        assert_eq!(list.current(), Some(Path::new("1.mp3")));
+    }
+
+    #[test]
+    fn test_shuffle_is_a_stable_permutation() {
+        let mut list = playlist(10);
+        list.next();
+        list.set_shuffle(true);
+        assert_eq!(list.current(), Some(Path::new("1.mp3")));
+
+        let mut played = vec![PathBuf::from("0.mp3"), PathBuf::from("1.mp3")];
+        for _ in 2..10 {
+            list.next();
+            played.push(list.current().unwrap().to_path_buf());
+        }
+        let mut sorted = played.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 10);
+
+        // previous walks back through what was actually played
+        for expected in played.iter().rev().skip(1) {
+            list.previous();
+            assert_eq!(list.current(), Some(expected.as_path()));
+        }
+
+        list.next();
+        list.set_shuffle(false);
+        assert_eq!(list.current(), Some(Path::new("1.mp3")));
+    }
+
+    #[test]
+    fn test_previous_after_shuffle() {
+        let mut list = playlist(6);
+        list.next();
+        list.next();
+        list.set_shuffle(true);
+        assert_eq!(list.current(), Some(Path::new("2.mp3")));
+
+        // Only the tracks not played yet are shuffled
+        let mut upcoming: Vec<PathBuf> = list.entries().skip(3).map(|entry| entry.path.clone()).collect();
+        upcoming.sort();
+        assert_eq!(upcoming, ["3.mp3", "4.mp3", "5.mp3"].map(PathBuf::from));
+
+        list.previous();
+        assert_eq!(list.current(), Some(Path::new("1.mp3")));
+        list.previous();
+        assert_eq!(list.current(), Some(Path::new("0.mp3")));
+    }
+
+    #[test]
+    fn test_unreadable_playlists_are_skipped() {
+        let root = tempdir().unwrap();
+        std::fs::write(root.path().join("a.mp3"), b"").unwrap();
+        let broken = root.path().join("broken.xspf");
+        std::fs::write(&broken, [0xFF, 0xFE, 0x00]).unwrap();
+        let missing = root.path().join("missing.m3u");
+
+        // One bad playlist among the inputs leaves the others to play
+        let inputs = [&broken, root.path(), &missing].map(|path| path.display().to_string());
+        let loaded = collect_inputs(&inputs, None).unwrap();
+        assert_eq!(loaded.entries.len(), 1);
+        assert_eq!(loaded.entries[0].path, root.path().join("a.mp3"));
+        let skipped: Vec<_> = loaded.unresolved.iter().map(|entry| entry.location.clone()).collect();
+        assert_eq!(skipped, [broken.display().to_string(), missing.display().to_string()]);
+    }
+}
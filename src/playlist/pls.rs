@@ -0,0 +1,87 @@
+//! PLS playlist files (INI-style `FileN`, `TitleN` and `LengthN` keys)
+
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
+use anyhow::Context;
+
+use super::LoadedPlaylist;
+use super::location::{decode_text, resolve_items, resolve_location, PlaylistItem};
+
+/// Loads a PLS playlist. Entries are ordered by their number, and `LengthN` of -1
+/// means the length is unknown.
+pub fn load_pls(path: &Path) -> anyhow::Result<LoadedPlaylist> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed to read playlist: {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(resolve_items(parse_pls(&decode_text(bytes)), base, resolve_location))
+}
+
+fn parse_pls(contents: &str) -> Vec<PlaylistItem> {
+    let mut items: BTreeMap<u32, PlaylistItem> = BTreeMap::new();
+
+    for line in contents.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        let Some((key, value)) = line.split_once('=') else { continue };
+        let key = key.trim().to_lowercase();
+        let value = value.trim();
+
+        // FileN / TitleN / LengthN; NumberOfEntries and Version are not needed
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (name, number) = key.split_at(split);
+        let Ok(number) = number.parse::<u32>() else { continue };
+        let item = items.entry(number).or_default();
+
+        match name {
+            "file" => item.location = value.to_string(),
+            "title" if !value.is_empty() => item.title = Some(value.to_string()),
+            "length" => {
+                item.duration = value.parse::<i64>().ok()
+                    .filter(|&secs| secs >= 0)
+                    .map(|secs| Duration::from_secs(secs as u64));
+            }
+            _ => {}
+        }
+    }
+
+    items.into_values()
+        .filter(|item| !item.location.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pls() {
+        let contents = "[playlist]\n\
+            File2=second.mp3\n\
+            Title2=Second\n\
+            Length2=-1\n\
+            File1=/music/first.flac\n\
+            Title1=First\n\
+            Length1=245\n\
+            Title3=No file\n\
+            NumberOfEntries=3\n\
+            Version=2\n";
+        let items = parse_pls(contents);
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].location, "/music/first.flac");
+        assert_eq!(items[0].title.as_deref(), Some("First"));
+        assert_eq!(items[0].duration, Some(Duration::from_secs(245)));
+        assert_eq!(items[1].location, "second.mp3");
+        assert_eq!(items[1].duration, None);
+    }
+
+    #[test]
+    fn test_load_latin1() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("Café.mp3"), b"").unwrap();
+        fs::write(root.path().join("list.pls"), b"[playlist]\nFile1=Caf\xE9.mp3\nTitle1=Caf\xE9\n").unwrap();
+
+        let loaded = load_pls(&root.path().join("list.pls")).unwrap();
+        assert!(loaded.unresolved.is_empty());
+        assert_eq!(loaded.entries[0].path, root.path().join("Café.mp3"));
+        assert_eq!(loaded.entries[0].title.as_deref(), Some("Café"));
+    }
+}
@@ -0,0 +1,191 @@
+//! Finds supported audio files in a directory tree
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::cue::load_cue;
+use crate::audio::global_registry;
+use crate::utils::metadata::read_metadata;
+
+/// Lists the supported audio files directly inside `dir`
+pub fn get_supported_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    scan_directory(dir, Some(0))
+}
+
+/// Lists the supported audio files under `dir`, descending at most `max_depth` levels
+/// of subdirectories (unlimited when None). A CUE sheet is listed in place of the audio
+/// files it splits into tracks.
+///
+/// Symlinks are followed, but a directory already visited through another path is not
+/// entered again. Hidden files and directories are skipped. Files are ordered by
+/// directory, then by track number, then by file name.
+pub fn scan_directory(dir: &Path, max_depth: Option<usize>) -> anyhow::Result<Vec<PathBuf>> {
+    let mut visited = HashSet::new();
+    let mut groups = Vec::new();
+    scan(dir, 0, max_depth, &mut visited, &mut groups)?;
+
+    groups.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(groups.into_iter().flat_map(|(_, files)| files).collect())
+}
+
+fn scan(
+    dir: &Path,
+    depth: usize,
+    max_depth: Option<usize>,
+    visited: &mut HashSet<PathBuf>,
+    groups: &mut Vec<(PathBuf, Vec<PathBuf>)>,
+) -> anyhow::Result<()> {
+    // Canonical paths identify directories reached again through a symlink
+    if !visited.insert(fs::canonicalize(dir)?) {
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    let mut cue_sheets = Vec::new();
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        // fs::metadata follows symlinks; broken links are skipped
+        let Ok(metadata) = fs::metadata(&path) else { continue };
+        if metadata.is_dir() {
+            subdirs.push(path);
+        } else if metadata.is_file() && is_cue_sheet(&path) {
+            cue_sheets.push(path);
+        } else if metadata.is_file() && is_audio_file(&path) {
+            files.push(path);
+        }
+    }
+    replace_with_cue_sheets(&mut files, cue_sheets);
+
+    if !files.is_empty() {
+        groups.push((dir.to_path_buf(), sort_by_track(files)));
+    }
+
+    if max_depth.is_none_or(|max| depth < max) {
+        subdirs.sort();
+        for subdir in subdirs {
+            // An unreadable subdirectory should not hide the rest of the library
+            let _ = scan(&subdir, depth + 1, max_depth, visited, groups);
+        }
+    }
+
+    Ok(())
+}
+
+/// Orders the files of one directory by track number, falling back to the file name
+fn sort_by_track(files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut keyed: Vec<(Option<u32>, PathBuf)> = files.into_iter()
+        .map(|path| {
+            let track = read_metadata(&path).ok().and_then(|m| m.track_number);
+            (track, path)
+        })
+        .collect();
+
+    // Numbered tracks first, then the rest by name
+    keyed.sort_by(|(track_a, path_a), (track_b, path_b)| {
+        track_a.is_none().cmp(&track_b.is_none())
+            .then(track_a.cmp(track_b))
+            .then_with(|| path_a.file_name().cmp(&path_b.file_name()))
+    });
+    keyed.into_iter().map(|(_, path)| path).collect()
+}
+
+fn is_cue_sheet(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Swaps the audio files that CUE sheets split into tracks for the sheets themselves.
+/// Sheets whose audio cannot be found are left out.
+fn replace_with_cue_sheets(files: &mut Vec<PathBuf>, mut cue_sheets: Vec<PathBuf>) {
+    cue_sheets.sort();
+    for sheet in cue_sheets {
+        let Ok(loaded) = load_cue(&sheet) else { continue };
+        if loaded.entries.is_empty() {
+            continue;
+        }
+        files.retain(|file| loaded.entries.iter().all(|entry| entry.path != *file));
+        files.push(sheet);
+    }
+}
+
+/// Asks the decoder registry; the extension is a quick hint, and files without a known
+/// one are recognised by content
+fn is_audio_file(path: &Path) -> bool {
+    global_registry().read().unwrap().is_supported(path)
+}
+
+/// Whether a registered decoder handles files with this extension
+pub fn is_supported_extension(ext: &str) -> bool {
+    global_registry().read().unwrap().supports_extension(ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+
+    fn names(root: &Path, files: &[PathBuf]) -> Vec<String> {
+        files.iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect()
+    }
+
+    #[test]
+    fn test_recursive_scan_order_and_depth() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        touch(&root.join("b.mp3"));
+        touch(&root.join("a.flac"));
+        touch(&root.join("notes.txt"));
+        fs::write(root.join("c.bin"), b"fLaC\0\0\0\x22").unwrap();
+        touch(&root.join(".hidden.mp3"));
+        touch(&root.join("Artist/Album/02.ogg"));
+        touch(&root.join("Artist/Album/01.ogg"));
+        touch(&root.join(".cache/x.mp3"));
+
+        let all = scan_directory(root, None).unwrap();
+        assert_eq!(names(root, &all), vec!["a.flac", "b.mp3", "c.bin", "Artist/Album/01.ogg", "Artist/Album/02.ogg"]);
+
+        let shallow = scan_directory(root, Some(1)).unwrap();
+        assert_eq!(names(root, &shallow), vec!["a.flac", "b.mp3", "c.bin"]);
+        assert_eq!(get_supported_files(root).unwrap().len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loop_is_visited_once() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        touch(&root.join("Album/track.mp3"));
+        std::os::unix::fs::symlink(root, root.join("Album/loop")).unwrap();
+
+        let files = scan_directory(root, None).unwrap();
+        assert_eq!(names(root, &files), vec!["Album/track.mp3"]);
+    }
+
+    #[test]
+    fn test_cue_sheet_replaces_its_audio() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        touch(&root.join("Album/album.flac"));
+        touch(&root.join("Album/bonus.mp3"));
+        fs::write(root.join("Album/album.cue"), "FILE \"album.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n").unwrap();
+        fs::write(root.join("Album/missing.cue"), "FILE \"gone.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n").unwrap();
+
+        let files = scan_directory(root, None).unwrap();
+        assert_eq!(names(root, &files), vec!["Album/album.cue", "Album/bonus.mp3"]);
+    }
+}
@@ -0,0 +1,139 @@
+//! XSPF playlist files (XML `<track>` elements with `<location>` URIs)
+
+use std::{fs, path::Path, time::Duration};
+use anyhow::Context;
+
+use super::LoadedPlaylist;
+use super::location::{resolve_items, resolve_uri, PlaylistItem};
+
+/// Loads an XSPF playlist. Each track's first `<location>` is used; `<title>` and
+/// `<duration>` (in milliseconds) become hints.
+pub fn load_xspf(path: &Path) -> anyhow::Result<LoadedPlaylist> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read playlist: {}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(resolve_items(parse_xspf(&contents), base, resolve_uri))
+}
+
+fn parse_xspf(contents: &str) -> Vec<PlaylistItem> {
+    elements(contents, "track")
+        .into_iter()
+        .filter_map(|track| {
+            let location = elements(track, "location").into_iter().next()?;
+            Some(PlaylistItem {
+                location: unescape(location.trim()),
+                title: elements(track, "title").into_iter().next()
+                    .map(|title| unescape(title.trim()))
+                    .filter(|title| !title.is_empty()),
+                duration: elements(track, "duration").into_iter().next()
+                    .and_then(|ms| ms.trim().parse::<u64>().ok())
+                    .map(Duration::from_millis),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Returns the contents of every `<name>...</name>` element, outermost first.
+/// XSPF tracks do not nest, so this is enough without a full XML parser.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut found = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find(&open) {
+        let after_name = &rest[start + open.len()..];
+        // Skip longer names such as <trackList> when looking for <track>
+        let Some(next) = after_name.chars().next() else { break };
+        if !(next == '>' || next == '/' || next.is_whitespace()) {
+            rest = after_name;
+            continue;
+        }
+
+        let Some(tag_end) = after_name.find('>') else { break };
+        if after_name[..tag_end].ends_with('/') {
+            // Self-closing element: no contents
+            rest = &after_name[tag_end + 1..];
+            continue;
+        }
+
+        let body = &after_name[tag_end + 1..];
+        let Some(end) = body.find(&close) else { break };
+        found.push(&body[..end]);
+        rest = &body[end + close.len()..];
+    }
+
+    found
+}
+
+/// Replaces the predefined XML entities and numeric character references
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        result.push_str(&rest[..amp]);
+        let entity = &rest[amp + 1..];
+        let Some(semi) = entity.find(';') else {
+            result.push_str(&rest[amp..]);
+            return result;
+        };
+
+        let name = &entity[..semi];
+        let decoded = match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => name.strip_prefix("#x").or_else(|| name.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| name.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+
+        match decoded {
+            Some(c) => result.push(c),
+            None => result.push_str(&rest[amp..amp + semi + 2]),
+        }
+        rest = &entity[semi + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_xspf() {
+        let contents = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/">
+              <trackList>
+                <track>
+                  <location>file:///music/Rock%20%26%20Roll.mp3</location>
+                  <title>Rock &amp; Roll</title>
+                  <duration>215000</duration>
+                </track>
+                <track><location>relative/b.ogg</location></track>
+                <track><title>No location</title></track>
+              </trackList>
+            </playlist>"#;
+        let items = parse_xspf(contents);
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].location, "file:///music/Rock%20%26%20Roll.mp3");
+        assert_eq!(items[0].title.as_deref(), Some("Rock & Roll"));
+        assert_eq!(items[0].duration, Some(Duration::from_secs(215)));
+        assert_eq!(items[1].location, "relative/b.ogg");
+        assert_eq!(items[1].title, None);
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("a &lt;b&gt; &#233;&#x41; &unknown; &"), "a <b> éA &unknown; &");
+    }
+}
@@ -19,6 +19,10 @@ fn get_default_tag(file_type: FileType) -> Tag {
 
 pub fn read_metadata(path: &Path) -> anyhow::Result<SongMetadata> {
     let tagged_file = Probe::open(path)
+        .with_context(|| format!("\rFailed to open file: {}", path.display()))?
+        // The contents, not the extension, decide the format: a misnamed or extensionless
+        // file still has its tags read
+        .guess_file_type()
         .with_context(|| format!("\rFailed to open file: {}", path.display()))?
         .read()
         .with_context(|| format!("\rFailed to read metadata: {}", path.display()))?;
@@ -39,7 +43,8 @@ pub fn read_metadata(path: &Path) -> anyhow::Result<SongMetadata> {
         title: tag.title().map(|s| s.to_string()),
         artist: tag.artist().map(|s| s.to_string()),
         album: tag.album().map(|s| s.to_string()),
-        duration: Some(Duration::from_secs(properties.duration().as_secs())),
+        // Zero when the tag reader could not work it out
+        duration: Some(properties.duration()).filter(|duration| !duration.is_zero()),
         year: tag.year(),
         track_number: tag.track(),
         format: format_to_string(file_type),
@@ -49,10 +54,13 @@ pub fn read_metadata(path: &Path) -> anyhow::Result<SongMetadata> {
     Ok(metadata)
 }
 
-pub fn print_song_info(path: &Path) -> anyhow::Result<u64> {
+pub fn print_song_info(path: &Path) -> anyhow::Result<Option<Duration>> {
     let metadata = read_metadata(path)?;
-    let mut return_duration: u64 = 0;
+    print_metadata(&metadata);
+    Ok(metadata.duration)
+}
 
+pub fn print_metadata(metadata: &SongMetadata) {
     println!("\n=== Song Information ===");
     println!("\rTitle: {}", metadata.title.as_deref().unwrap_or("Unknown"));
     println!("\rArtist: {}", metadata.artist.as_deref().unwrap_or("Unknown"));
@@ -65,7 +73,6 @@ pub fn print_song_info(path: &Path) -> anyhow::Result<u64> {
 
     if let Some(duration) = metadata.duration {
         println!("\rDuration: {}", format_duration(duration));
-        return_duration = duration.as_secs();
     }
 
     if let Some(year) = metadata.year {
@@ -75,6 +82,4 @@ pub fn print_song_info(path: &Path) -> anyhow::Result<u64> {
     if let Some(track) = metadata.track_number {
         println!("\rTrack Number: {}", track);
     }
-
-    Ok(return_duration)
 }
\ No newline at end of file
@@ -31,4 +31,24 @@ fn test_audio_player_lifecycle() {
     // Test stop
     player.stop();
     assert!(!player.is_playing());
-}
\ No newline at end of file
+}
+
+#[test]
+#[cfg_attr(not(feature = "local-audio-tests"), ignore)]
+fn test_volume_persists_across_tracks() {
+    let mut player = AudioPlayer::new().unwrap();
+    player.set_volume(40);
+
+    let test_file = PathBuf::from("tests/resources/test.wav");
+    assert!(player.play(&test_file).is_ok());
+    assert_eq!(player.volume(), 40);
+
+    assert!(player.toggle_mute());
+    assert!(player.play(&test_file).is_ok());
+    assert_eq!(player.volume(), 40);
+    assert!(!player.toggle_mute());
+
+    player.set_volume(250);
+    assert_eq!(player.volume(), 100);
+    player.stop();
+}